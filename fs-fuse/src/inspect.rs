@@ -0,0 +1,350 @@
+//! 查看镜像在磁盘上的样子：超级块、区域划分、位图占用、inode 和任意块的十六进制内容
+//!
+//! 不修改镜像，也不需要密钥，加密的数据按原样显示。
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use fs::{
+    BlockDevice, BlockRole, DiskInodeType, FileBlockDevice, FileSystem, SuperBlock, BLOCK_SZ,
+    INODE_FLAG_COMPRESSED, INODE_FLAG_ENCRYPTED, INODE_FLAG_NAMES_ENCRYPTED,
+    INODE_FLAG_PREALLOCATED,
+};
+use std::collections::BTreeMap;
+use std::io::{self, Error};
+use std::sync::Arc;
+
+/// The `inspect` subcommand
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("inspect")
+        .about(
+            "Print the superblock, areas and bitmaps of the image, and inodes or blocks in detail",
+        )
+        .arg(
+            Arg::with_name("image")
+                .required(true)
+                .help("Path of fs.img"),
+        )
+        .arg(
+            Arg::with_name("inodes")
+                .long("inodes")
+                .help("Print every allocated inode"),
+        )
+        .arg(
+            Arg::with_name("inode")
+                .long("inode")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Print an inode, allocated or not"),
+        )
+        .arg(
+            Arg::with_name("block")
+                .long("block")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Hex-dump a block, annotated with its role"),
+        )
+}
+
+/// Run the `inspect` subcommand
+pub fn run(matches: &ArgMatches) -> io::Result<()> {
+    let block_device: Arc<dyn BlockDevice> =
+        Arc::new(FileBlockDevice::open(matches.value_of("image").unwrap())?);
+    let super_block = FileSystem::read_superblock(&block_device);
+    print_superblock(&super_block);
+    if !super_block.is_valid() {
+        return Err(Error::other("not an easy-fs image"));
+    }
+    // 不回收孤儿 inode，显示镜像原本的样子
+    let efs = FileSystem::load(block_device);
+    println!("areas");
+    for area in efs.areas() {
+        println!(
+            "  {:<12} {:>8}..{:<8} {:>8} blocks",
+            format!("{:?}", area.kind),
+            area.start_block,
+            area.start_block + area.blocks,
+            area.blocks
+        );
+    }
+    for (name, usage) in [
+        ("inodes", efs.inode_usage()),
+        ("data blocks", efs.data_usage()),
+    ] {
+        println!(
+            "{}: {} of {} used, {} free in {} runs, the longest {}",
+            name,
+            usage.used,
+            usage.bits,
+            usage.bits - usage.used,
+            usage.free_runs,
+            usage.largest_free_run
+        );
+    }
+    let inodes = efs.allocated_inodes();
+    let fragments: Vec<u32> = inodes
+        .iter()
+        .map(|inode_id| efs.inode_fragments(*inode_id))
+        .collect();
+    println!(
+        "files: {} inodes, {} in more than one fragment, {} fragments in total",
+        inodes.len(),
+        fragments.iter().filter(|count| **count > 1).count(),
+        fragments.iter().sum::<u32>()
+    );
+    let mut inode_ids: Vec<u32> = parse_values(matches, "inode")?;
+    if matches.is_present("inodes") {
+        inode_ids.extend(inodes);
+    }
+    let inode_num = efs.inode_usage().bits;
+    if let Some(inode_id) = inode_ids.iter().find(|inode_id| **inode_id >= inode_num) {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("inode {} is out of range", inode_id),
+        ));
+    }
+    for inode_id in inode_ids {
+        print_inode(&efs, inode_id);
+    }
+    let block_ids: Vec<u32> = parse_values(matches, "block")?;
+    if !block_ids.is_empty() {
+        let owners = efs.block_owners();
+        for block_id in block_ids {
+            dump_block(&efs, block_id, &owners);
+        }
+    }
+    Ok(())
+}
+
+fn parse_values(matches: &ArgMatches, name: &str) -> io::Result<Vec<u32>> {
+    matches
+        .values_of(name)
+        .unwrap_or_default()
+        .map(|value| {
+            value.parse().map_err(|_| {
+                Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("bad {}: {}", name, value),
+                )
+            })
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn print_superblock(super_block: &SuperBlock) {
+    let label_len = super_block
+        .label
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(super_block.label.len());
+    println!("superblock");
+    println!(
+        "  magic              {:#010x}{}",
+        super_block.magic(),
+        if super_block.is_valid() {
+            ""
+        } else {
+            " (invalid)"
+        }
+    );
+    println!("  version            {}", super_block.version);
+    println!("  compat features    {:#x}", super_block.compat_features);
+    println!("  incompat features  {:#x}", super_block.incompat_features);
+    println!("  total blocks       {}", super_block.total_blocks);
+    println!(
+        "  inode bitmap       {} blocks",
+        super_block.inode_bitmap_blocks
+    );
+    println!(
+        "  inode area         {} blocks",
+        super_block.inode_area_blocks
+    );
+    println!(
+        "  data bitmap        {} blocks",
+        super_block.data_bitmap_blocks
+    );
+    println!(
+        "  data area          {} blocks",
+        super_block.data_area_blocks
+    );
+    println!("  uuid               {}", hex(&super_block.uuid));
+    println!(
+        "  label              {:?}",
+        String::from_utf8_lossy(&super_block.label[..label_len])
+    );
+    println!(
+        "  quota table        {} blocks at {}",
+        super_block.quota_blocks, super_block.quota_start_block
+    );
+    println!("  data extents       {}", super_block.data_extent_count);
+    println!("  orphan inodes      {}", super_block.orphans().len());
+    for orphan in super_block.orphans() {
+        println!(
+            "    inode {} of directory {}",
+            orphan.inode_id, orphan.parent_id
+        );
+    }
+    if super_block.key_check != [0; 32] {
+        println!("  key check          {}", hex(&super_block.key_check));
+    }
+    println!("  verity levels      {}", super_block.verity_levels);
+    if super_block.verity_levels != 0 {
+        println!("  verity root        {}", hex(&super_block.verity_root));
+    }
+}
+
+/// Split a block map into runs of contiguous blocks, holes are runs starting at 0
+fn runs(blocks: &[u32]) -> Vec<(u32, u32)> {
+    let mut v: Vec<(u32, u32)> = Vec::new();
+    for block_id in blocks.iter() {
+        match v.last_mut() {
+            Some((start, count)) if *start != 0 && *block_id == *start + *count => *count += 1,
+            Some((0, count)) if *block_id == 0 => *count += 1,
+            _ => v.push((*block_id, 1)),
+        }
+    }
+    v
+}
+
+fn format_runs(blocks: &[u32]) -> String {
+    let runs: Vec<String> = runs(blocks)
+        .into_iter()
+        .map(|(start, count)| match (start, count) {
+            (0, count) => format!("hole*{}", count),
+            (start, 1) => format!("{}", start),
+            (start, count) => format!("{}-{}", start, start + count - 1),
+        })
+        .collect();
+    runs.join(" ")
+}
+
+/// The type of an inode, a byte that is no known type in hex
+fn format_type(type_: DiskInodeType) -> String {
+    match type_ {
+        DiskInodeType::Unknown(byte) => format!("unknown type {:#04x}", byte),
+        type_ => format!("{:?}", type_),
+    }
+}
+
+/// The flags of an inode in hex, followed by the names of those that are known
+fn format_flags(flags: u8) -> String {
+    let known = [
+        (INODE_FLAG_COMPRESSED, "compressed"),
+        (INODE_FLAG_ENCRYPTED, "encrypted"),
+        (INODE_FLAG_NAMES_ENCRYPTED, "names encrypted"),
+        (INODE_FLAG_PREALLOCATED, "preallocated"),
+    ];
+    let mut names: Vec<String> = known
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    let unknown = known.iter().fold(flags, |flags, (flag, _)| flags & !flag);
+    if unknown != 0 {
+        names.push(format!("unknown {:#04x}", unknown));
+    }
+    match names.is_empty() {
+        true => format!("{:#04x}", flags),
+        false => format!("{:#04x} ({})", flags, names.join(", ")),
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    bytes.join(" ")
+}
+
+fn print_inode(efs: &Arc<FileSystem>, inode_id: u32) {
+    let disk_inode = efs.disk_inode(inode_id);
+    if let DiskInodeType::Unknown(_) = disk_inode.type_() {
+        // 类型损坏的 inode 的块映射不可信，只显示原始字节
+        println!(
+            "inode {}: {}, size {}, flags {}, uid {}",
+            inode_id,
+            format_type(disk_inode.type_()),
+            disk_inode.size,
+            format_flags(disk_inode.flags()),
+            disk_inode.uid()
+        );
+        for (i, line) in efs.raw_disk_inode(inode_id).chunks(16).enumerate() {
+            println!("  {:04x}  {}", i * 16, hex_bytes(line));
+        }
+        return;
+    }
+    let block_map = disk_inode.block_map(&efs.block_device);
+    println!(
+        "inode {}: {}, size {}, flags {}, uid {}, {} blocks in {} fragments",
+        inode_id,
+        format_type(disk_inode.type_()),
+        disk_inode.size,
+        format_flags(disk_inode.flags()),
+        disk_inode.uid(),
+        block_map.len(),
+        efs.inode_fragments(inode_id)
+    );
+    println!("  direct     {:?}", disk_inode.direct);
+    println!("  indirect1  {}", disk_inode.indirect1);
+    println!("  indirect2  {}", disk_inode.indirect2);
+    println!("  data       {}", format_runs(&block_map));
+    println!(
+        "  index      {}",
+        format_runs(&disk_inode.index_blocks(&efs.block_device))
+    );
+}
+
+/// Annotation of a line of 16 bytes at `offset` in a block with `role`
+fn annotate(efs: &Arc<FileSystem>, role: BlockRole, offset: usize) -> String {
+    match role {
+        BlockRole::InodeBitmap { first_bit } | BlockRole::DataBitmap { first_bit } => {
+            let bit = first_bit as usize + offset * 8;
+            format!("bits {}..{}", bit, bit + 128)
+        }
+        BlockRole::InodeArea { first_inode } if offset.is_multiple_of(128) => {
+            let inode_id = first_inode + (offset / 128) as u32;
+            let disk_inode = efs.disk_inode(inode_id);
+            format!(
+                "inode {}: {}, size {}, flags {}",
+                inode_id,
+                format_type(disk_inode.type_()),
+                disk_inode.size,
+                format_flags(disk_inode.flags())
+            )
+        }
+        BlockRole::Index { .. } => format!("entries {}..{}", offset / 4, offset / 4 + 4),
+        BlockRole::Data { inode, .. } if efs.disk_inode(inode).is_dir() => {
+            format!("dirents {}..{}", offset / 32, offset / 32 + 2)
+        }
+        _ => String::new(),
+    }
+}
+
+fn dump_block(efs: &Arc<FileSystem>, block_id: u32, owners: &BTreeMap<u32, BlockRole>) {
+    let role = efs.block_role(block_id, owners);
+    println!("block {}: {:?}", block_id, role);
+    if role == BlockRole::Outside {
+        return;
+    }
+    let mut data = [0u8; BLOCK_SZ];
+    efs.block_device.read_block(block_id as usize, &mut data);
+    for (i, line) in data.chunks(16).enumerate() {
+        let text: String = line
+            .iter()
+            .map(|byte| match *byte {
+                0x20..=0x7e => *byte as char,
+                _ => '.',
+            })
+            .collect();
+        let line = format!(
+            "  {:04x}  {}  |{}|  {}",
+            i * 16,
+            hex_bytes(line),
+            text,
+            annotate(efs, role, i * 16)
+        );
+        println!("{}", line.trim_end());
+    }
+}
@@ -1,36 +1,33 @@
-use clap::{App, Arg};
-use fs::{BlockDevice, EasyFileSystem};
-use std::fs::{read_dir, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::sync::Arc;
-use std::sync::Mutex;
-
-const BLOCK_SZ: usize = 512;
+mod inspect;
+mod tar;
+mod toolbox;
 
-struct BlockFile(Mutex<File>);
+use clap::{App, Arg, ArgMatches};
+use fs::{
+    block_cache_set_capacity, block_cache_stats, hash_tree_blocks, DiskInode, FileBlockDevice,
+    FileHandle, FileSystem, Inode, DIRENT_SZ, KEY_SZ, NAME_LENGTH_LIMIT,
+};
+use std::collections::HashMap;
+use std::fs::{read_dir, File};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-impl BlockDevice for BlockFile {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-        let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
-    }
+/// 写入镜像时每次复制的字节数，是压缩簇大小的整数倍
+const COPY_CHUNK_SZ: usize = 64 * 1024;
 
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
-        let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+fn parse_key(hex: &str) -> [u8; KEY_SZ] {
+    assert_eq!(hex.len(), KEY_SZ * 2, "The key must be {} hex digits!", KEY_SZ * 2);
+    let mut key = [0u8; KEY_SZ];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).expect("Bad hex digit in key!");
     }
+    key
 }
 
-fn main() {
-    fs_pack().expect("Error when packing easy-fs!");
-}
-
-fn fs_pack() -> std::io::Result<()> {
-    let matches = App::new("EasyFileSystem packer")
+fn app() -> App<'static, 'static> {
+    App::new("EasyFileSystem packer")
         .arg(
             Arg::with_name("source")
                 .short("s")
@@ -45,46 +42,507 @@ fn fs_pack() -> std::io::Result<()> {
                 .takes_value(true)
                 .help("Executable target dir(with backslash)"),
         )
-        .get_matches();
-    let src_path = matches.value_of("source").unwrap();
-    let target_path = matches.value_of("target").unwrap();
-    println!("src_path = {}\ntarget_path = {}", src_path, target_path);
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len(16 * 2048 * 512).unwrap();
-        f
-    })));
-    // 16MiB, at most 4095 files
-    let efs = EasyFileSystem::create(block_file, 16 * 2048, 1);
-    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
-    let apps: Vec<_> = read_dir(src_path)
-        .unwrap()
-        .into_iter()
+        .arg(
+            Arg::with_name("compress")
+                .short("c")
+                .long("compress")
+                .help("Store files with transparent compression"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .takes_value(true)
+                .help("Key for encrypted files, as 64 hex digits"),
+        )
+        .arg(
+            Arg::with_name("encrypt")
+                .long("encrypt")
+                .takes_value(true)
+                .multiple(true)
+                .requires("key")
+                .help("Apps to store encrypted"),
+        )
+        .arg(
+            Arg::with_name("label")
+                .long("label")
+                .takes_value(true)
+                .help("Volume label, at most 16 bytes"),
+        )
+        .arg(
+            Arg::with_name("grow")
+                .long("grow")
+                .takes_value(true)
+                .help("Grow the existing image to this many MiB instead of packing"),
+        )
+        .arg(
+            Arg::with_name("dir")
+                .short("d")
+                .long("dir")
+                .takes_value(true)
+                .conflicts_with("source")
+                .help("Host dir packed recursively with its hierarchy and file names"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .help("Path of the image, fs.img in the target dir by default"),
+        )
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .default_value("16")
+                .help("Image size in MiB"),
+        )
+        .arg(
+            Arg::with_name("inodes")
+                .long("inodes")
+                .takes_value(true)
+                .default_value("4096")
+                .help("Number of inodes, rounded up to a multiple of 4096"),
+        )
+        .arg(
+            Arg::with_name("uuid")
+                .long("uuid")
+                .takes_value(true)
+                .help("UUID of the volume as 32 hex digits, random by default"),
+        )
+        .arg(
+            Arg::with_name("reproducible")
+                .long("reproducible")
+                .conflicts_with("uuid")
+                .help("Derive the UUID from the file hashes so the same inputs give the same image"),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .long("manifest")
+                .takes_value(true)
+                .help("Write the SHA-256 of every packed file to this path, as sha256sum does"),
+        )
+        .arg(
+            Arg::with_name("verity")
+                .long("verity")
+                .help("Append a hash tree and record its root hash for verified mounting"),
+        )
+        .arg(
+            Arg::with_name("cache-blocks")
+                .long("cache-blocks")
+                .takes_value(true)
+                .global(true)
+                .help("Number of blocks the block cache keeps [default: 16]"),
+        )
+        .arg(
+            Arg::with_name("cache-stats")
+                .long("cache-stats")
+                .global(true)
+                .help("Print the block cache counters when done"),
+        )
+        .subcommand(inspect::subcommand())
+        .subcommands(toolbox::subcommands())
+        .subcommands(tar::subcommands())
+}
+
+fn main() {
+    let matches = app().get_matches();
+    if let Some(blocks) = matches.value_of("cache-blocks") {
+        match blocks.parse() {
+            Ok(blocks) if blocks > 0 => block_cache_set_capacity(blocks),
+            _ => {
+                eprintln!("fs-fuse: --cache-blocks must be a positive number");
+                std::process::exit(1);
+            }
+        }
+    }
+    let result = match matches.subcommand() {
+        ("", None) => fs_pack(&matches),
+        ("inspect", Some(sub_matches)) => inspect::run(sub_matches),
+        (name @ ("export" | "import"), Some(sub_matches)) => tar::run(name, sub_matches),
+        (name, Some(sub_matches)) => toolbox::run(name, sub_matches),
+        _ => unreachable!(),
+    };
+    if matches.is_present("cache-stats") {
+        let stats = block_cache_stats();
+        eprintln!(
+            "block cache: {} hits, {} misses, {} evictions, {} write-backs, \
+             {} overflows, at most {} of {} entries pinned",
+            stats.hits,
+            stats.misses,
+            stats.evictions,
+            stats.write_backs,
+            stats.overflows,
+            stats.pinned_high_water,
+            stats.capacity
+        );
+    }
+    if let Err(e) = result {
+        eprintln!("fs-fuse: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// A file or directory to put into the image
+struct PackEntry {
+    /// Path in the image, without the leading `/`
+    path: String,
+    /// Path on the host
+    host: PathBuf,
+    is_dir: bool,
+    /// Size of a file, or number of entries of a directory
+    size: u64,
+}
+
+/// Walk a host directory recursively in name order, a directory comes before its entries
+fn collect_tree(host_dir: &Path, prefix: &str, entries: &mut Vec<PackEntry>) -> io::Result<u64> {
+    let mut count = 0;
+    let mut dir_entries = read_dir(host_dir)?.collect::<io::Result<Vec<_>>>()?;
+    dir_entries.sort_by_key(|dir_entry| dir_entry.file_name());
+    for dir_entry in dir_entries {
+        let name = dir_entry.file_name().into_string().map_err(|name| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?}: file name is not UTF-8", name),
+            )
+        })?;
+        let path = format!("{}{}", prefix, name);
+        if name.len() > NAME_LENGTH_LIMIT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: names are limited to {} bytes", path, NAME_LENGTH_LIMIT),
+            ));
+        }
+        let metadata = std::fs::metadata(dir_entry.path())?;
+        count += 1;
+        if metadata.is_dir() {
+            let index = entries.len();
+            entries.push(PackEntry {
+                path: path.clone(),
+                host: dir_entry.path(),
+                is_dir: true,
+                size: 0,
+            });
+            entries[index].size = collect_tree(&dir_entry.path(), &format!("{}/", path), entries)?;
+        } else {
+            entries.push(PackEntry {
+                path,
+                host: dir_entry.path(),
+                is_dir: false,
+                size: metadata.len(),
+            });
+        }
+    }
+    Ok(count)
+}
+
+/// Apps built from `src_path` in name order, named after their sources without extension
+fn collect_apps(src_path: &str, target_path: &str) -> io::Result<Vec<PackEntry>> {
+    let mut apps = read_dir(src_path)?
         .map(|dir_entry| {
-            let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
+            let mut name_with_ext = dir_entry?.file_name().into_string().unwrap();
             name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
-            name_with_ext
+            let host = PathBuf::from(format!("{}{}", target_path, name_with_ext));
+            Ok(PackEntry {
+                size: std::fs::metadata(&host)?.len(),
+                path: name_with_ext,
+                host,
+                is_dir: false,
+            })
         })
-        .collect();
-    for app in apps {
+        .collect::<io::Result<Vec<_>>>()?;
+    apps.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(apps)
+}
+
+/// Hash every file, in the format of `sha256sum` with paths relative to the image root
+fn build_manifest(entries: &[PackEntry]) -> io::Result<String> {
+    let mut manifest = String::new();
+    for entry in entries.iter().filter(|entry| !entry.is_dir) {
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(&entry.host)?, &mut hasher)?;
+        manifest += &format!("{}  {}\n", hex(&hasher.finalize()), entry.path);
+    }
+    Ok(manifest)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_uuid(hex: &str) -> [u8; 16] {
+    assert_eq!(hex.len(), 32, "The UUID must be 32 hex digits!");
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).expect("Bad hex digit in UUID!");
+    }
+    uuid
+}
+
+/// Copy a host file into the image in chunks of a fixed size,
+/// so that compressed clusters do not depend on how `io::copy` buffers
+fn copy_file(host_file: &mut File, file: &mut FileHandle) -> io::Result<()> {
+    let mut buf = vec![0u8; COPY_CHUNK_SZ];
+    loop {
+        let len = host_file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        file.write_all(&buf[..len])?;
+    }
+}
+
+fn fs_pack(matches: &ArgMatches) -> io::Result<()> {
+    let target_path = matches.value_of("target").unwrap_or("");
+    let output = matches
+        .value_of("output")
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}{}", target_path, "fs.img"));
+    if let Some(size) = matches.value_of("grow") {
+        let size: u32 = size.parse().expect("Bad image size!");
+        return fs_grow(&output, size * 2048);
+    }
+    let compress = matches.is_present("compress");
+    let verity = matches.is_present("verity");
+    let key = matches.value_of("key").map(parse_key);
+    let encrypted: Vec<&str> = matches
+        .values_of("encrypt")
+        .map(|values| values.collect())
+        .unwrap_or_default();
+    let size_mib: u32 = matches.value_of("size").unwrap().parse().expect("Bad image size!");
+    let inodes: u32 = matches.value_of("inodes").unwrap().parse().expect("Bad inode count!");
+    let total_blocks = size_mib * 2048;
+    // 每个 inode 位图块管理 4096 个 inode，它们占用 1024 个块
+    let inode_bitmap_blocks = inodes.div_ceil(4096);
+    if FileSystem::usable_blocks(total_blocks, inode_bitmap_blocks).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("a {} MiB image cannot hold {} inodes", size_mib, inodes),
+        ));
+    }
+    let image_blocks = if verity {
+        total_blocks + hash_tree_blocks(total_blocks)
+    } else {
+        total_blocks
+    };
+    let (entries, root_entries) = match matches.value_of("dir") {
+        Some(dir) => {
+            println!("dir = {}", dir);
+            let mut entries = Vec::new();
+            let root_entries = collect_tree(Path::new(dir), "", &mut entries)?;
+            (entries, root_entries)
+        }
+        None => {
+            let src_path = matches.value_of("source").expect("Either --dir or --source is needed!");
+            println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+            let entries = collect_apps(src_path, target_path)?;
+            let root_entries = entries.len() as u64;
+            (entries, root_entries)
+        }
+    };
+    let block_file = Arc::new(FileBlockDevice::create(&output, image_blocks)?);
+    let efs = FileSystem::create(block_file, total_blocks, inode_bitmap_blocks);
+    // 压缩后的大小无法事先知道，压缩时只在写入每个文件前按未压缩的大小检查
+    let needed_blocks: u64 = DiskInode::total_blocks(root_entries as u32 * DIRENT_SZ as u32) as u64
+        + entries
+            .iter()
+            .map(|entry| match entry.is_dir {
+                true => DiskInode::total_blocks(entry.size as u32 * DIRENT_SZ as u32) as u64,
+                false if compress => 0,
+                false => DiskInode::total_blocks(entry.size as u32) as u64,
+            })
+            .sum::<u64>();
+    let stat = efs.stat();
+    if entries.len() as u64 > stat.free_inodes as u64 || needed_blocks > stat.free_data_blocks as u64
+    {
+        drop(efs);
+        std::fs::remove_file(&output)?;
+        return Err(io::Error::other(format!(
+            "the content needs {} inodes and {} blocks, but the image only has {} and {}",
+            entries.len(),
+            needed_blocks,
+            stat.free_inodes,
+            stat.free_data_blocks
+        )));
+    }
+    // easy-fs 不记录时间戳，UUID 是镜像中唯一与输入无关的内容
+    let manifest = build_manifest(&entries)?;
+    let uuid = match matches.value_of("uuid") {
+        Some(uuid) => parse_uuid(uuid),
+        None if matches.is_present("reproducible") => {
+            let mut uuid = [0u8; 16];
+            uuid.copy_from_slice(&Sha256::digest(manifest.as_bytes())[..16]);
+            uuid
+        }
+        None => rand::random(),
+    };
+    efs.set_uuid(uuid);
+    if let Some(label) = matches.value_of("label") {
+        efs.set_label(label);
+    }
+    if let Some(key) = key {
+        efs.set_key(key);
+    }
+    let root_inode = FileSystem::root_inode(&efs);
+    let mut dirs: HashMap<&str, Arc<Inode>> = HashMap::new();
+    for entry in entries.iter() {
+        let (parent, name) = match entry.path.rsplit_once('/') {
+            Some((parent, name)) => (&dirs[parent], name),
+            None => (&root_inode, entry.path.as_str()),
+        };
+        if entry.is_dir {
+            let inode = parent.create_dir(name).unwrap();
+            if encrypted.contains(&entry.path.as_str()) {
+                inode.set_encrypted(false);
+            }
+            dirs.insert(&entry.path, inode);
+            continue;
+        }
+        if compress && efs.stat().free_data_blocks < DiskInode::total_blocks(entry.size as u32) {
+            std::fs::remove_file(&output)?;
+            return Err(io::Error::other(format!(
+                "{}: not enough space left in the image",
+                entry.path
+            )));
+        }
         // load app data from host file system
-        let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
-        let mut all_data: Vec<u8> = Vec::new();
-        host_file.read_to_end(&mut all_data).unwrap();
+        let mut host_file = File::open(&entry.host)?;
         // create a file in easy-fs
-        let inode = root_inode.create(app.as_str()).unwrap();
+        let inode = parent.create(name).unwrap();
+        if compress {
+            inode.set_compressed(true);
+        }
+        if encrypted.contains(&entry.path.as_str()) {
+            inode.set_encrypted(false);
+        }
         // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+        copy_file(&mut host_file, &mut FileHandle::new(inode))?;
     }
-    // list apps
-    // for app in root_inode.ls() {
-    //     println!("{}", app);
-    // }
+    let stat = efs.stat();
+    println!(
+        "packed {} entries, {} of {} inodes and {} of {} data blocks free",
+        entries.len(),
+        stat.free_inodes,
+        stat.inodes,
+        stat.free_data_blocks,
+        stat.data_blocks
+    );
+    if verity {
+        let root_hash = efs.build_hash_tree();
+        println!("verity root hash = {}", hex(&root_hash));
+    }
+    efs.sync();
+    if let Some(path) = matches.value_of("manifest") {
+        std::fs::write(path, manifest)?;
+    }
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(&output)?, &mut hasher)?;
+    println!("image sha256 = {}", hex(&hasher.finalize()));
+    Ok(())
+}
+
+fn fs_grow(image_path: &str, total_blocks: u32) -> std::io::Result<()> {
+    let block_file = Arc::new(FileBlockDevice::open(image_path)?);
+    assert!(
+        block_file.total_blocks()? <= total_blocks,
+        "The image is already larger than that!"
+    );
+    block_file.set_total_blocks(total_blocks)?;
+    let efs = FileSystem::open(block_file);
+    let grown = efs.grow(total_blocks);
+    println!("easy-fs now takes {} of {} blocks", grown, total_blocks);
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    /// A directory in the temporary directory, removed when dropped
+    struct TempDir(PathBuf);
 
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("fs-fuse-{}-{}", std::process::id(), name));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+        fn join(&self, path: &str) -> String {
+            self.0.join(path).to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const TREE: [(&str, &[u8]); 5] = [
+        ("bin", b""),
+        ("bin/hello", b"hello world"),
+        ("bin/zeros", &[0u8; 20000]),
+        ("etc", b""),
+        ("readme", b"easy-fs"),
+    ];
+
+    /// Create the same tree, with entries made in the given order and stamped with `mtime`
+    fn make_tree(dir: &TempDir, order: &[usize], mtime: SystemTime) {
+        for &i in order {
+            let (path, data) = TREE[i];
+            let host = dir.0.join("in").join(path);
+            if data.is_empty() {
+                std::fs::create_dir_all(&host).unwrap();
+                continue;
+            }
+            std::fs::create_dir_all(host.parent().unwrap()).unwrap();
+            std::fs::write(&host, data).unwrap();
+            File::options()
+                .write(true)
+                .open(&host)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
+    }
+
+    fn pack(dir: &TempDir) -> (Vec<u8>, Vec<u8>) {
+        let (image, manifest) = (dir.join("fs.img"), dir.join("manifest"));
+        let matches = app().get_matches_from([
+            "fs-fuse",
+            "--dir",
+            &dir.join("in"),
+            "--output",
+            &image,
+            "--manifest",
+            &manifest,
+            "--size",
+            "4",
+            "--compress",
+            "--reproducible",
+        ]);
+        fs_pack(&matches).unwrap();
+        (
+            std::fs::read(image).unwrap(),
+            std::fs::read(manifest).unwrap(),
+        )
+    }
+
+    #[test]
+    fn reproducible_pack() {
+        let first = TempDir::new("reproducible-1");
+        let second = TempDir::new("reproducible-2");
+        make_tree(&first, &[0, 1, 2, 3, 4], SystemTime::UNIX_EPOCH);
+        make_tree(
+            &second,
+            &[4, 3, 0, 2, 1],
+            SystemTime::now() - Duration::from_secs(3600),
+        );
+        let (image, manifest) = pack(&first);
+        assert_eq!(pack(&second), (image, manifest.clone()));
+        let manifest = String::from_utf8(manifest).unwrap();
+        let paths: Vec<&str> = manifest.lines().map(|line| &line[66..]).collect();
+        assert_eq!(paths, ["bin/hello", "bin/zeros", "readme"]);
+    }
+}
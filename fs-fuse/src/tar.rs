@@ -0,0 +1,571 @@
+//! 镜像与 tar 归档之间的转换
+//!
+//! 写出 POSIX ustar 格式，放不进 ustar 的长路径用 pax 扩展头记录；读入时也接受 GNU 的长文件名。
+//! easy-fs 没有权限和时间戳，目录固定为 0755、文件固定为 0644，修改时间为 0，
+//! 属主 uid 存在 ustar 头中，压缩和加密标志存在 `EASYFS.` 开头的 pax 记录中。
+
+use crate::toolbox::{create, image_command, lookup_parent, open_image};
+use clap::{App, Arg, ArgMatches};
+use fs::{FileHandle, VfsInode};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::sync::Arc;
+
+/// Size of a tar header and of the unit the data is padded to
+const RECORD_SZ: usize = 512;
+const PAX_COMPRESSED: &str = "EASYFS.compressed";
+const PAX_ENCRYPTED: &str = "EASYFS.encrypted";
+
+/// Subcommands converting between an image and a tar archive
+pub fn subcommands() -> Vec<App<'static, 'static>> {
+    vec![
+        image_command("export", "Write the whole image as a tar archive").arg(
+            Arg::with_name("archive")
+                .required(true)
+                .help("Tar archive to write, - for stdout"),
+        ),
+        image_command(
+            "import",
+            "Add the content of a tar archive to the image, replacing existing files",
+        )
+        .arg(
+            Arg::with_name("archive")
+                .required(true)
+                .help("Tar archive to read, - for stdin"),
+        ),
+    ]
+}
+
+/// Run a subcommand returned by [`subcommands`]
+pub fn run(name: &str, matches: &ArgMatches) -> io::Result<()> {
+    let root_inode = open_image(matches, name == "import")?.root_inode();
+    let archive = matches.value_of("archive").unwrap();
+    match name {
+        "export" => {
+            let mut writer: Box<dyn Write> = match archive {
+                "-" => Box::new(io::stdout().lock()),
+                _ => Box::new(File::create(archive)?),
+            };
+            export_dir(&root_inode, "", &mut writer)?;
+            // 归档以两个全零的记录结束
+            writer.write_all(&[0u8; RECORD_SZ * 2])?;
+            writer.flush()
+        }
+        "import" => {
+            let mut reader: Box<dyn Read> = match archive {
+                "-" => Box::new(io::stdin().lock()),
+                _ => Box::new(File::open(archive)?),
+            };
+            import(&root_inode, &mut reader)
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Write an octal number into a NUL-terminated field
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+fn get_octal(field: &[u8]) -> io::Result<u64> {
+    // GNU tar 用最高位置 1 的大端二进制表示放不下的数
+    if field[0] & 0x80 != 0 {
+        let value = field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |value, byte| {
+                value << 8 | u64::from(*byte)
+            });
+        return Ok(value);
+    }
+    let text = String::from_utf8_lossy(field);
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| invalid(format!("bad number in tar header: {}", text)))
+}
+
+/// Get a NUL-terminated string field
+fn get_str(field: &[u8]) -> String {
+    let len = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into()
+}
+
+fn checksum(header: &[u8; RECORD_SZ]) -> u64 {
+    // 计算校验和时校验和字段本身按空格计
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| match i {
+            148..=155 => u64::from(b' '),
+            _ => u64::from(*byte),
+        })
+        .sum()
+}
+
+/// Build a ustar header, `None` if the path does not fit into the name and prefix fields
+fn ustar_header(path: &str, typeflag: u8, size: u64, uid: u16) -> Option<[u8; RECORD_SZ]> {
+    let mut header = [0u8; RECORD_SZ];
+    let (prefix, name) = match path.len() {
+        0..=100 => ("", path),
+        _ => {
+            // 在某个 `/` 处把路径拆成不超过 155 字节的前缀和不超过 100 字节的名字
+            let split = path
+                .char_indices()
+                .filter(|(i, c)| *c == '/' && *i <= 155 && path.len() - i - 1 <= 100)
+                .map(|(i, _)| i)
+                .next()?;
+            (&path[..split], &path[split + 1..])
+        }
+    };
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    let mode = if typeflag == b'5' { 0o755 } else { 0o644 };
+    put_octal(&mut header[100..108], mode);
+    put_octal(&mut header[108..116], u64::from(uid));
+    put_octal(&mut header[116..124], 0);
+    put_octal(&mut header[124..136], size);
+    put_octal(&mut header[136..148], 0);
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    let sum = format!("{:06o}\0 ", checksum(&header));
+    header[148..156].copy_from_slice(sum.as_bytes());
+    Some(header)
+}
+
+/// Pad data of `len` bytes to a whole record
+fn write_padding(writer: &mut dyn Write, len: u64) -> io::Result<()> {
+    let padding = (RECORD_SZ - len as usize % RECORD_SZ) % RECORD_SZ;
+    writer.write_all(&[0u8; RECORD_SZ][..padding])
+}
+
+/// Encode a pax record, whose length field counts its own digits
+fn pax_record(key: &str, value: &str) -> String {
+    let body_len = key.len() + value.len() + 3;
+    let mut len = body_len + 1;
+    while len != body_len + len.to_string().len() {
+        len = body_len + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value)
+}
+
+fn export_entry(inode: &Arc<dyn VfsInode>, path: &str, writer: &mut dyn Write) -> io::Result<()> {
+    let (typeflag, size) = match inode.is_dir() {
+        true => (b'5', 0),
+        false => (b'0', inode.size() as u64),
+    };
+    let mut records = String::new();
+    let header = match ustar_header(path, typeflag, size, inode.owner()) {
+        Some(header) => header,
+        None => {
+            records += &pax_record("path", path);
+            ustar_header("", typeflag, size, inode.owner()).unwrap()
+        }
+    };
+    if inode.is_compressed() {
+        records += &pax_record(PAX_COMPRESSED, "1");
+    }
+    if inode.is_encrypted() {
+        records += &pax_record(PAX_ENCRYPTED, "1");
+    }
+    if !records.is_empty() {
+        let pax_header = ustar_header("PaxHeader", b'x', records.len() as u64, 0).unwrap();
+        writer.write_all(&pax_header)?;
+        writer.write_all(records.as_bytes())?;
+        write_padding(writer, records.len() as u64)?;
+    }
+    writer.write_all(&header)?;
+    if !inode.is_dir() {
+        let copied = io::copy(&mut FileHandle::new(Arc::clone(inode)), writer)?;
+        if copied != size {
+            return Err(Error::other(format!("{}: short read from the image", path)));
+        }
+        write_padding(writer, size)?;
+    }
+    Ok(())
+}
+
+/// Write the entries below `dir`, each directory before its content
+fn export_dir(dir: &Arc<dyn VfsInode>, prefix: &str, writer: &mut dyn Write) -> io::Result<()> {
+    for entry in dir.read_dir(0) {
+        let inode = match dir.open_entry(&entry) {
+            Some(inode) => inode,
+            None => continue,
+        };
+        let path = format!("{}{}", prefix, entry.name);
+        if inode.is_dir() {
+            export_entry(&inode, &format!("{}/", path), writer)?;
+            export_dir(&inode, &format!("{}/", path), writer)?;
+        } else {
+            export_entry(&inode, &path, writer)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the data of an entry, padding included
+fn read_data(reader: &mut dyn Read, size: u64) -> io::Result<Vec<u8>> {
+    let padded = (size as usize).div_ceil(RECORD_SZ) * RECORD_SZ;
+    let mut data = vec![0u8; padded];
+    reader.read_exact(&mut data)?;
+    data.truncate(size as usize);
+    Ok(data)
+}
+
+/// Parse pax records into key-value pairs
+fn parse_pax(data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|byte| *byte == b' ')
+            .ok_or_else(|| invalid("bad pax record".into()))?;
+        let len: usize = String::from_utf8_lossy(&rest[..space])
+            .parse()
+            .map_err(|_| invalid("bad pax record length".into()))?;
+        if len <= space || len > rest.len() {
+            return Err(invalid("bad pax record length".into()));
+        }
+        let record = String::from_utf8_lossy(&rest[space + 1..len - 1]).into_owned();
+        if let Some((key, value)) = record.split_once('=') {
+            records.push((key.into(), value.into()));
+        }
+        rest = &rest[len..];
+    }
+    Ok(records)
+}
+
+/// Make every directory on `path` that does not exist yet
+fn make_dirs(root_inode: &Arc<dyn VfsInode>, path: &str) -> io::Result<Arc<dyn VfsInode>> {
+    let mut dir = Arc::clone(root_inode);
+    let mut walked = String::new();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        walked = format!("{}/{}", walked, name);
+        dir = match dir.find(name) {
+            Some(inode) if inode.is_dir() => inode,
+            Some(_) => return Err(Error::other(format!("{}: not a directory", walked))),
+            None => create(&dir, name, &walked, |dir, name| dir.create_dir(name))?,
+        };
+    }
+    Ok(dir)
+}
+
+/// Apply the metadata of a tar entry to a new or emptied inode
+fn set_metadata(
+    inode: &Arc<dyn VfsInode>,
+    path: &str,
+    uid: u16,
+    pax: &[(String, String)],
+) -> io::Result<()> {
+    let flag = |key: &str| pax.iter().any(|(k, value)| k == key && value == "1");
+    // 覆盖的已有文件保留着原来的压缩标志，按归档中的记录设置或清除
+    let compressed = flag(PAX_COMPRESSED);
+    if !inode.is_dir() && inode.is_compressed() != compressed {
+        inode.set_compressed(compressed);
+    }
+    if flag(PAX_ENCRYPTED) && !inode.is_encrypted() && !inode.set_encrypted(false) {
+        return Err(Error::other(format!(
+            "{}: cannot store encrypted, is --key given?",
+            path
+        )));
+    }
+    if inode.owner() != uid && !inode.set_owner(uid) {
+        return Err(Error::other(format!(
+            "{}: cannot hand over to uid {}, its quota is exceeded or the image keeps no owners",
+            path, uid
+        )));
+    }
+    Ok(())
+}
+
+fn import(root_inode: &Arc<dyn VfsInode>, reader: &mut dyn Read) -> io::Result<()> {
+    let mut pax: Vec<(String, String)> = Vec::new();
+    let mut long_name: Option<String> = None;
+    loop {
+        let mut header = [0u8; RECORD_SZ];
+        reader.read_exact(&mut header)?;
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(());
+        }
+        if get_octal(&header[148..156])? != checksum(&header) {
+            return Err(invalid("bad checksum in tar header".into()));
+        }
+        let size = get_octal(&header[124..136])?;
+        let typeflag = header[156];
+        match typeflag {
+            b'x' => {
+                pax.extend(parse_pax(&read_data(reader, size)?)?);
+                continue;
+            }
+            b'L' => {
+                long_name = Some(get_str(&read_data(reader, size)?));
+                continue;
+            }
+            b'g' => {
+                read_data(reader, size)?;
+                continue;
+            }
+            _ => {}
+        }
+        let path = match pax.iter().rev().find(|(key, _)| key == "path") {
+            Some((_, path)) => path.clone(),
+            None => long_name.take().unwrap_or_else(|| {
+                let prefix = get_str(&header[345..500]);
+                let name = get_str(&header[..100]);
+                match prefix.is_empty() {
+                    true => name,
+                    false => format!("{}/{}", prefix, name),
+                }
+            }),
+        };
+        let path = path.trim_start_matches("./").trim_matches('/').to_string();
+        let uid = u16::try_from(get_octal(&header[108..116])?)
+            .map_err(|_| invalid(format!("{}: uid does not fit into 16 bits", path)))?;
+        match typeflag {
+            b'5' if path.is_empty() || path == "." => {}
+            b'5' => {
+                let dir = make_dirs(root_inode, &path)?;
+                set_metadata(&dir, &path, uid, &pax)?;
+            }
+            b'0' | b'\0' | b'7' => {
+                let (dir_path, _) = path.rsplit_once('/').unwrap_or(("", &path));
+                make_dirs(root_inode, dir_path)?;
+                let (parent, file_name) = lookup_parent(root_inode, &path)?;
+                let file = match parent.find(file_name) {
+                    Some(file) if file.is_dir() => {
+                        return Err(Error::other(format!("{}: is a directory", path)));
+                    }
+                    Some(file) => {
+                        file.clear();
+                        file
+                    }
+                    None => create(&parent, file_name, &path, |dir, name| dir.create(name))?,
+                };
+                set_metadata(&file, &path, uid, &pax)?;
+                let data = read_data(reader, size)?;
+                FileHandle::new(file).write_all(&data)?;
+            }
+            _ => {
+                // easy-fs 没有链接和设备文件
+                eprintln!(
+                    "fs-fuse: {}: skipping entry of type {:?}",
+                    path, typeflag as char
+                );
+                read_data(reader, size)?;
+            }
+        }
+        pax.clear();
+        long_name = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fs::{FileSystem, RamBlockDevice, SuperBlockOps, KEY_SZ};
+
+    const TOTAL_BLOCKS: u32 = 4096;
+
+    fn new_root() -> Arc<dyn VfsInode> {
+        let efs: Arc<dyn SuperBlockOps> = FileSystem::create(
+            Arc::new(RamBlockDevice::new(TOTAL_BLOCKS as usize)),
+            TOTAL_BLOCKS,
+            1,
+        );
+        efs.root_inode()
+    }
+
+    fn new_root_with_key() -> Arc<dyn VfsInode> {
+        let efs = FileSystem::create(
+            Arc::new(RamBlockDevice::new(TOTAL_BLOCKS as usize)),
+            TOTAL_BLOCKS,
+            1,
+        );
+        assert!(efs.set_key([7u8; KEY_SZ]));
+        let efs: Arc<dyn SuperBlockOps> = efs;
+        efs.root_inode()
+    }
+
+    fn export(root_inode: &Arc<dyn VfsInode>) -> Vec<u8> {
+        let mut archive = Vec::new();
+        export_dir(root_inode, "", &mut archive).unwrap();
+        archive.extend_from_slice(&[0u8; RECORD_SZ * 2]);
+        archive
+    }
+
+    /// Typeflag, name and prefix field of every header in an archive
+    fn headers(archive: &[u8]) -> Vec<(u8, String, String)> {
+        let mut headers = Vec::new();
+        let mut pos = 0;
+        while archive[pos..pos + RECORD_SZ].iter().any(|byte| *byte != 0) {
+            let header = &archive[pos..pos + RECORD_SZ];
+            let size = get_octal(&header[124..136]).unwrap() as usize;
+            headers.push((header[156], get_str(&header[..100]), get_str(&header[345..500])));
+            pos += RECORD_SZ + size.div_ceil(RECORD_SZ) * RECORD_SZ;
+        }
+        headers
+    }
+
+    /// Create `path` with its directories and write `data` into it
+    fn put(root_inode: &Arc<dyn VfsInode>, path: &str, data: &[u8]) {
+        let (dir_path, file_name) = path.rsplit_once('/').unwrap();
+        let dir = make_dirs(root_inode, dir_path).unwrap();
+        let file = dir.create(file_name).unwrap();
+        assert_eq!(file.write_at(0, data), data.len());
+    }
+
+    fn get(root_inode: &Arc<dyn VfsInode>, path: &str) -> Vec<u8> {
+        let (parent, file_name) = lookup_parent(root_inode, path).unwrap();
+        let file = parent.find(file_name).unwrap();
+        let mut data = vec![0u8; file.size()];
+        assert_eq!(file.read_at(0, &mut data), data.len());
+        data
+    }
+
+    /// Name, uid and the pax records in front of an entry
+    type Entry = (String, u64, Vec<(String, String)>);
+
+    /// Every entry of an archive, in order
+    fn pax_records(archive: &[u8]) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while archive[pos..pos + RECORD_SZ].iter().any(|byte| *byte != 0) {
+            let header = &archive[pos..pos + RECORD_SZ];
+            let size = get_octal(&header[124..136]).unwrap() as usize;
+            pos += RECORD_SZ;
+            match header[156] {
+                b'x' => records.extend(parse_pax(&archive[pos..pos + size]).unwrap()),
+                _ => entries.push((
+                    get_str(&header[..100]),
+                    get_octal(&header[108..116]).unwrap(),
+                    std::mem::take(&mut records),
+                )),
+            }
+            pos += size.div_ceil(RECORD_SZ) * RECORD_SZ;
+        }
+        entries
+    }
+
+    fn round_trip(path: &str) -> Vec<(u8, String, String)> {
+        let root_inode = new_root();
+        put(&root_inode, path, b"hello tar");
+        let archive = export(&root_inode);
+        let imported = new_root();
+        import(&imported, &mut archive.as_slice()).unwrap();
+        assert_eq!(get(&imported, path), b"hello tar");
+        headers(&archive)
+    }
+
+    #[test]
+    fn long_path_is_split_into_prefix() {
+        let dir = "d".repeat(27);
+        let path = format!("{0}/{0}/{0}/{0}/file", dir);
+        assert!(path.len() > 100);
+        let headers = round_trip(&path);
+        assert!(headers.iter().all(|(typeflag, _, _)| *typeflag != b'x'));
+        let (_, name, prefix) = headers.last().unwrap();
+        assert_eq!(format!("{}/{}", prefix, name), path);
+    }
+
+    #[test]
+    fn very_long_path_uses_pax() {
+        let dir = "d".repeat(27);
+        let path = format!("{}/file", vec![dir; 10].join("/"));
+        assert!(path.len() > 255);
+        let headers = round_trip(&path);
+        let last = headers.len() - 1;
+        assert_eq!(headers[last - 1].0, b'x');
+        assert_eq!(headers[last].1, "");
+    }
+
+    #[test]
+    fn gnu_long_name() {
+        let path = format!("{}/file", vec!["d".repeat(27); 5].join("/"));
+        let mut archive = Vec::new();
+        let long_link = ustar_header("././@LongLink", b'L', path.len() as u64 + 1, 0).unwrap();
+        archive.extend_from_slice(&long_link);
+        archive.extend_from_slice(path.as_bytes());
+        archive.push(0);
+        write_padding(&mut archive, path.len() as u64 + 1).unwrap();
+        archive.extend_from_slice(&ustar_header("truncated", b'0', 3, 0).unwrap());
+        archive.extend_from_slice(b"gnu");
+        write_padding(&mut archive, 3).unwrap();
+        archive.extend_from_slice(&[0u8; RECORD_SZ * 2]);
+        let root_inode = new_root();
+        import(&root_inode, &mut archive.as_slice()).unwrap();
+        assert_eq!(get(&root_inode, &path), b"gnu");
+        assert!(root_inode.find("truncated").is_none());
+    }
+
+    #[test]
+    fn checksum_is_verified() {
+        let header = ustar_header("file", b'0', 0, 0).unwrap();
+        assert_eq!(get_octal(&header[148..156]).unwrap(), checksum(&header));
+        let mut archive = header.to_vec();
+        archive[0] = b'g';
+        archive.extend_from_slice(&[0u8; RECORD_SZ * 2]);
+        let error = import(&new_root(), &mut archive.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let root_inode = new_root_with_key();
+        let packed: Vec<u8> = (0..20000u32).map(|i| (i / 100) as u8).collect();
+        put(&root_inode, "dir/plain", b"plain");
+        let dir = root_inode.find("dir").unwrap();
+        dir.find("plain").unwrap().set_owner(5);
+        let file = dir.create("packed").unwrap();
+        assert!(file.set_compressed(true));
+        assert_eq!(file.write_at(0, &packed), packed.len());
+        let file = root_inode.create("secret").unwrap();
+        assert!(file.set_encrypted(false));
+        assert!(file.set_owner(7));
+        assert_eq!(file.write_at(0, b"secret"), 6);
+        let archive = export(&root_inode);
+
+        let compressed = (PAX_COMPRESSED.to_string(), "1".to_string());
+        let encrypted = (PAX_ENCRYPTED.to_string(), "1".to_string());
+        assert_eq!(
+            pax_records(&archive),
+            [
+                ("dir/".to_string(), 0, vec![]),
+                ("dir/plain".to_string(), 5, vec![]),
+                ("dir/packed".to_string(), 0, vec![compressed]),
+                ("secret".to_string(), 7, vec![encrypted]),
+            ]
+        );
+
+        // 导入时覆盖的已有文件按归档设置或清除压缩标志
+        let imported = new_root_with_key();
+        put(&imported, "dir/packed", b"old");
+        let file = imported.find("dir").unwrap().create("plain").unwrap();
+        assert!(file.set_compressed(true));
+        import(&imported, &mut archive.as_slice()).unwrap();
+        let dir = imported.find("dir").unwrap();
+        let plain = dir.find("plain").unwrap();
+        assert_eq!(get(&imported, "dir/plain"), b"plain");
+        assert!(!plain.is_compressed());
+        assert_eq!(plain.owner(), 5);
+        let file = dir.find("packed").unwrap();
+        assert_eq!(get(&imported, "dir/packed"), packed);
+        assert!(file.is_compressed());
+        assert_eq!(file.owner(), 0);
+        let file = imported.find("secret").unwrap();
+        assert_eq!(get(&imported, "secret"), b"secret");
+        assert!(file.is_encrypted());
+        assert_eq!(file.owner(), 7);
+    }
+}
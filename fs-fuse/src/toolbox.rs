@@ -0,0 +1,422 @@
+//! 对已有镜像的 mtools 风格操作：ls、cat、get、put、rm、mkdir，以及离线碎片整理 defrag
+//!
+//! 镜像内的路径以 `/` 分隔，总是从根目录开始解析。除 defrag 外的操作也适用于 FAT32 镜像。
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use fs::{
+    BlockDevice, FatFileSystem, FileBlockDevice, FileHandle, FileSystem, FileType, SuperBlockOps,
+    VfsInode,
+};
+use std::fs::File;
+use std::io::{self, Error, ErrorKind};
+use std::sync::Arc;
+
+/// Subcommands operating on an existing image
+pub fn subcommands() -> Vec<App<'static, 'static>> {
+    vec![
+        image_command("ls", "List a directory of the image")
+            .arg(
+                Arg::with_name("path")
+                    .default_value("/")
+                    .help("Directory in the image"),
+            )
+            .arg(
+                Arg::with_name("recursive")
+                    .short("r")
+                    .long("recursive")
+                    .help("List the whole tree below the directory"),
+            ),
+        image_command("cat", "Print a file of the image").arg(
+            Arg::with_name("path")
+                .required(true)
+                .help("File in the image"),
+        ),
+        image_command("get", "Extract a file from the image")
+            .arg(
+                Arg::with_name("path")
+                    .required(true)
+                    .help("File in the image"),
+            )
+            .arg(
+                Arg::with_name("dest")
+                    .required(true)
+                    .help("Host file to write"),
+            ),
+        image_command(
+            "put",
+            "Insert a host file into the image, replacing an existing one",
+        )
+        .arg(
+            Arg::with_name("src")
+                .required(true)
+                .help("Host file to read"),
+        )
+        .arg(
+            Arg::with_name("path")
+                .required(true)
+                .help("File in the image"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .short("c")
+                .long("compress")
+                .help("Store a new file with transparent compression"),
+        ),
+        image_command("rm", "Delete a file or an empty directory of the image").arg(
+            Arg::with_name("path")
+                .required(true)
+                .help("Entry in the image"),
+        ),
+        image_command("mkdir", "Make a directory in the image").arg(
+            Arg::with_name("path")
+                .required(true)
+                .help("Directory in the image"),
+        ),
+        image_command(
+            "defrag",
+            "Move the blocks of every file into one contiguous run, the image must not be in use",
+        ),
+    ]
+}
+
+/// A subcommand taking the image and its key
+pub fn image_command(name: &'static str, about: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(
+            Arg::with_name("image")
+                .required(true)
+                .help("Path of fs.img"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .takes_value(true)
+                .help("Key for encrypted files, as 64 hex digits"),
+        )
+}
+
+/// Run a subcommand returned by [`subcommands`]
+pub fn run(name: &str, matches: &ArgMatches) -> io::Result<()> {
+    if name == "defrag" {
+        return defrag(matches);
+    }
+    let writable = !matches!(name, "ls" | "cat" | "get");
+    let root_inode = open_image(matches, writable)?.root_inode();
+    let path = matches.value_of("path").unwrap_or("/");
+    match name {
+        "ls" => {
+            let dir = lookup(&root_inode, path)?;
+            if !dir.is_dir() {
+                return Err(Error::other(format!("{}: not a directory", path)));
+            }
+            list(&dir, "", matches.is_present("recursive"));
+        }
+        "cat" => {
+            let file = lookup_file(&root_inode, path)?;
+            io::copy(&mut FileHandle::new(file), &mut io::stdout().lock())?;
+        }
+        "get" => {
+            let file = lookup_file(&root_inode, path)?;
+            let mut dest = File::create(matches.value_of("dest").unwrap())?;
+            io::copy(&mut FileHandle::new(file), &mut dest)?;
+        }
+        "put" => {
+            let mut src = File::open(matches.value_of("src").unwrap())?;
+            let (parent, file_name) = lookup_parent(&root_inode, path)?;
+            let file = match parent.find(file_name) {
+                Some(file) if file.is_dir() => {
+                    return Err(Error::other(format!("{}: is a directory", path)));
+                }
+                Some(file) => {
+                    file.clear();
+                    file
+                }
+                None => {
+                    let file = create(&parent, file_name, path, |dir, name| dir.create(name))?;
+                    if matches.is_present("compress") {
+                        file.set_compressed(true);
+                    }
+                    file
+                }
+            };
+            io::copy(&mut src, &mut FileHandle::new(file))?;
+        }
+        "rm" => {
+            let (parent, file_name) = lookup_parent(&root_inode, path)?;
+            if parent.find(file_name).is_none() {
+                return Err(not_found(path));
+            }
+            if !parent.unlink(file_name) {
+                return Err(Error::other(format!(
+                    "{}: cannot remove, is the directory empty?",
+                    path
+                )));
+            }
+        }
+        "mkdir" => {
+            let (parent, dir_name) = lookup_parent(&root_inode, path)?;
+            create(&parent, dir_name, path, |dir, name| dir.create_dir(name))?;
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn defrag(matches: &ArgMatches) -> io::Result<()> {
+    let block_device: Arc<dyn BlockDevice> =
+        Arc::new(FileBlockDevice::open(matches.value_of("image").unwrap())?);
+    if !FileSystem::read_superblock(&block_device).is_valid() {
+        return Err(Error::other("only easy-fs images can be defragmented"));
+    }
+    let efs = FileSystem::open(block_device);
+    let before = efs.data_usage();
+    let report = efs.defragment();
+    let after = efs.data_usage();
+    println!(
+        "{} inodes: {} relocated, {} left fragmented for lack of space, {} leaked blocks reclaimed",
+        report.inodes, report.relocated, report.skipped, report.reclaimed
+    );
+    println!(
+        "fragments over all inodes: {} before, {} after",
+        report.fragments_before, report.fragments_after
+    );
+    println!(
+        "free data blocks in {} runs before (the longest {}), {} runs after (the longest {})",
+        before.free_runs, before.largest_free_run, after.free_runs, after.largest_free_run
+    );
+    Ok(())
+}
+
+/// Open the image named on the command line, an easy-fs image with its key if given or a FAT32 one.
+/// Unless `writable`, an easy-fs image is loaded as it is, without reclaiming its orphan inodes.
+pub fn open_image(matches: &ArgMatches, writable: bool) -> io::Result<Arc<dyn SuperBlockOps>> {
+    let block_device: Arc<dyn BlockDevice> =
+        Arc::new(FileBlockDevice::open(matches.value_of("image").unwrap())?);
+    let super_block = FileSystem::read_superblock(&block_device);
+    if super_block.is_valid() {
+        let key = matches.value_of("key").map(crate::parse_key);
+        if !writable {
+            let efs = FileSystem::load(block_device);
+            // 没有记录过密钥的镜像中没有加密文件，不必为它记录密钥
+            if let Some(key) = key {
+                if super_block.key_check != [0; 32] && !efs.set_key(key) {
+                    return Err(Error::other("the key does not match the image"));
+                }
+            }
+            return Ok(efs);
+        }
+        // 带着密钥挂载，加密目录下的孤儿 inode 才能回收
+        return match key {
+            Some(key) => FileSystem::open_with_key(block_device, key)
+                .map(|efs| efs as Arc<dyn SuperBlockOps>)
+                .ok_or_else(|| Error::other("the key does not match the image")),
+            None => Ok(FileSystem::open(block_device)),
+        };
+    }
+    match FatFileSystem::open(block_device) {
+        Some(fat) => Ok(fat),
+        None => Err(Error::other("neither an easy-fs nor a FAT32 image")),
+    }
+}
+
+fn not_found(path: &str) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("{}: no such file or directory", path),
+    )
+}
+
+/// Resolve `path` from the root directory
+pub fn lookup(root_inode: &Arc<dyn VfsInode>, path: &str) -> io::Result<Arc<dyn VfsInode>> {
+    let mut inode = Arc::clone(root_inode);
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if !inode.is_dir() {
+            return Err(not_found(path));
+        }
+        inode = inode.find(name).ok_or_else(|| not_found(path))?;
+    }
+    Ok(inode)
+}
+
+fn lookup_file(root_inode: &Arc<dyn VfsInode>, path: &str) -> io::Result<Arc<dyn VfsInode>> {
+    let inode = lookup(root_inode, path)?;
+    if inode.is_dir() {
+        return Err(Error::other(format!("{}: is a directory", path)));
+    }
+    Ok(inode)
+}
+
+/// Resolve the directory holding the last component of `path`, and that component
+pub fn lookup_parent<'a>(
+    root_inode: &Arc<dyn VfsInode>,
+    path: &'a str,
+) -> io::Result<(Arc<dyn VfsInode>, &'a str)> {
+    let path = path.trim_end_matches('/');
+    let (dir_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the root directory has no parent",
+        ));
+    }
+    let dir = lookup(root_inode, dir_path)?;
+    if !dir.is_dir() {
+        return Err(not_found(path));
+    }
+    Ok((dir, name))
+}
+
+/// Create an entry in `parent`, with errors naming `path`
+pub fn create(
+    parent: &Arc<dyn VfsInode>,
+    name: &str,
+    path: &str,
+    create: fn(&dyn VfsInode, &str) -> Option<Arc<dyn VfsInode>>,
+) -> io::Result<Arc<dyn VfsInode>> {
+    let name_length_limit = parent.name_length_limit();
+    if name.len() > name_length_limit {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: names are limited to {} bytes", path, name_length_limit),
+        ));
+    }
+    create(parent.as_ref(), name).ok_or_else(|| {
+        Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{}: cannot create, does it exist?", path),
+        )
+    })
+}
+
+fn list(dir: &Arc<dyn VfsInode>, prefix: &str, recursive: bool) {
+    for entry in dir.read_dir(0) {
+        let path = format!("{}{}", prefix, entry.name);
+        let inode = match dir.open_entry(&entry) {
+            Some(inode) => inode,
+            None => continue,
+        };
+        match entry.type_ {
+            FileType::Directory => {
+                println!("{:>10}  {}/", "-", path);
+                if recursive {
+                    list(&inode, &format!("{}/", path), recursive);
+                }
+            }
+            FileType::File => println!("{:>10}  {}", inode.size(), path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const TOTAL_BLOCKS: u32 = 4096;
+
+    /// A file in the temporary directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("fs-fuse-{}-{}", std::process::id(), name));
+            Self(path)
+        }
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn new_image(name: &str) -> TempFile {
+        let image = TempFile::new(name);
+        let block_device = FileBlockDevice::create(&image.0, TOTAL_BLOCKS).unwrap();
+        FileSystem::create(Arc::new(block_device), TOTAL_BLOCKS, 1);
+        image
+    }
+
+    /// Run a subcommand the way the command line would
+    fn toolbox(args: &[&str]) -> io::Result<()> {
+        let matches = App::new("fs-fuse")
+            .subcommands(subcommands())
+            .get_matches_from(std::iter::once("fs-fuse").chain(args.iter().copied()));
+        let (name, sub_matches) = matches.subcommand();
+        run(name, sub_matches.unwrap())
+    }
+
+    fn load(image: &TempFile) -> Arc<FileSystem> {
+        FileSystem::load(Arc::new(FileBlockDevice::open(&image.0).unwrap()))
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = new_image("toolbox-image");
+        let src = TempFile::new("toolbox-src");
+        let dest = TempFile::new("toolbox-dest");
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&src.0, &data).unwrap();
+        toolbox(&["mkdir", image.path(), "/dir"]).unwrap();
+        toolbox(&["put", image.path(), src.path(), "/dir/file"]).unwrap();
+        toolbox(&["put", image.path(), src.path(), "/packed", "--compress"]).unwrap();
+        toolbox(&["ls", image.path(), "/", "-r"]).unwrap();
+        toolbox(&["get", image.path(), "/dir/file", dest.path()]).unwrap();
+        assert_eq!(std::fs::read(&dest.0).unwrap(), data);
+        // 覆盖已有文件，内容整个替换
+        std::fs::write(&src.0, b"short").unwrap();
+        toolbox(&["put", image.path(), src.path(), "/dir/file"]).unwrap();
+        toolbox(&["get", image.path(), "/dir/file", dest.path()]).unwrap();
+        assert_eq!(std::fs::read(&dest.0).unwrap(), b"short");
+
+        let error = toolbox(&["rm", image.path(), "/dir"]).unwrap_err();
+        assert!(error.to_string().contains("cannot remove"));
+        let error = toolbox(&["mkdir", image.path(), "/dir"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        let error = toolbox(&["ls", image.path(), "/dir/file"]).unwrap_err();
+        assert!(error.to_string().contains("not a directory"));
+        toolbox(&["rm", image.path(), "/dir/file"]).unwrap();
+        toolbox(&["rm", image.path(), "/dir"]).unwrap();
+        let error = toolbox(&["get", image.path(), "/dir/file", dest.path()]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+
+        let efs = load(&image);
+        let root_inode = FileSystem::root_inode(&efs);
+        assert_eq!(root_inode.ls(), ["packed"]);
+        let file = root_inode.find("packed").unwrap();
+        assert!(file.is_compressed());
+        assert_eq!(file.size(), data.len());
+        assert!(efs.check().is_empty());
+    }
+
+    #[test]
+    fn read_only_commands_leave_the_image_alone() {
+        let image = new_image("toolbox-orphan");
+        let dest = TempFile::new("toolbox-orphan-dest");
+        {
+            let efs = load(&image);
+            let root_inode = FileSystem::root_inode(&efs);
+            root_inode.create("file").unwrap().write_at(0, b"kept");
+            // 删除时仍打开、从未关闭的文件，如同崩溃后留在孤儿列表中
+            let orphan = root_inode.create("orphan").unwrap();
+            orphan.write_at(0, &[1u8; 5000]);
+            assert!(root_inode.unlink("orphan"));
+            std::mem::forget(orphan);
+        }
+        let before = std::fs::read(&image.0).unwrap();
+        toolbox(&["ls", image.path()]).unwrap();
+        toolbox(&["cat", image.path(), "/file"]).unwrap();
+        toolbox(&["get", image.path(), "/file", dest.path()]).unwrap();
+        assert_eq!(std::fs::read(&dest.0).unwrap(), b"kept");
+        assert_eq!(std::fs::read(&image.0).unwrap(), before);
+        assert_eq!(load(&image).orphans().len(), 1);
+        // 修改镜像的命令照常回收孤儿
+        toolbox(&["mkdir", image.path(), "/dir"]).unwrap();
+        assert!(load(&image).orphans().is_empty());
+    }
+}
@@ -9,36 +9,49 @@ type BitmapBlock = [u64; 64];
 pub struct Bitmap {
     start_block_id: usize,
     blocks: usize,
+    /// 可分配的位数，不超过位图块能容纳的位数
+    bits: usize,
 }
 
 impl Bitmap {
     pub fn new(start_block_id: usize, blocks: usize) -> Self {
+        Self::with_maximum(start_block_id, blocks, blocks * BLOCK_BITS)
+    }
+    /// A bitmap of which only the first `bits` bits can be allocated
+    pub fn with_maximum(start_block_id: usize, blocks: usize, bits: usize) -> Self {
+        assert!(bits <= blocks * BLOCK_BITS);
         Self {
             start_block_id,
             blocks,
+            bits,
         }
     }
     /// Allocate a new block from a block device
     pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+        let bit = self.first_free(block_device)?;
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+            });
+        Some(bit)
+    }
+    /// Find the bit [`Bitmap::alloc`] would allocate next, without allocating it
+    pub fn first_free(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
         for block_id in 0..self.blocks {
-            let pos = get_block_cache(
-                block_id + self.start_block_id as usize,
-                Arc::clone(block_device),
-            )
+            let pos = get_block_cache(block_id + self.start_block_id, Arc::clone(block_device))
                 .lock()
-                .modify(0, |bitmap_block: &mut BitmapBlock| {
-                    if let Some((bits64_pos, inner_pos)) = bitmap_block
+                .read(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block
                         .iter()
                         .enumerate()
                         .find(|(_, bits64)| **bits64 != u64::MAX)
                         .map(|(bits64_pos, bits64)| (bits64_pos, bits64.trailing_ones() as usize))
-                    {
-                        // modify cache
-                        bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                        Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
-                    } else {
-                        None
-                    }
+                        .filter(|(bits64_pos, inner_pos)| {
+                            block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos < self.bits
+                        })
+                        .map(|(bits64_pos, inner_pos)| block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos)
                 });
             if pos.is_some() {
                 return pos;
@@ -46,6 +59,47 @@ impl Bitmap {
         }
         None
     }
+    /// Allocate `len` contiguous bits from the first run of free bits long enough
+    pub fn alloc_run(&self, block_device: &Arc<dyn BlockDevice>, len: usize) -> Option<usize> {
+        let (mut start, mut current) = (0, 0);
+        let mut found = false;
+        for block_id in 0..self.blocks {
+            let bits = self.bits.saturating_sub(block_id * BLOCK_BITS).min(BLOCK_BITS);
+            found = get_block_cache(block_id + self.start_block_id, Arc::clone(block_device))
+                .lock()
+                .read(0, |bitmap_block: &BitmapBlock| {
+                    for bit in 0..bits {
+                        if bitmap_block[bit / 64] & (1u64 << (bit % 64)) != 0 {
+                            current = 0;
+                            continue;
+                        }
+                        if current == 0 {
+                            start = block_id * BLOCK_BITS + bit;
+                        }
+                        current += 1;
+                        if current == len {
+                            return true;
+                        }
+                    }
+                    false
+                });
+            if found {
+                break;
+            }
+        }
+        if !found || len == 0 {
+            return None;
+        }
+        for bit in start..start + len {
+            let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+            get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+                .lock()
+                .modify(0, |bitmap_block: &mut BitmapBlock| {
+                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                });
+        }
+        Some(start)
+    }
     /// Deallocate a block
     pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
@@ -56,8 +110,64 @@ impl Bitmap {
                 bitmap_block[bits64_pos] -= 1u64 << inner_pos;
             });
     }
+    /// Whether a bit is allocated
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0
+            })
+    }
+    /// Count the allocated bits
+    pub fn count_allocated(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        (0..self.blocks)
+            .map(|block_id| {
+                get_block_cache(block_id + self.start_block_id, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| {
+                        bitmap_block
+                            .iter()
+                            .map(|bits64| bits64.count_ones() as usize)
+                            .sum::<usize>()
+                    })
+            })
+            .sum()
+    }
+    /// Count the runs of free bits and get the length of the longest one
+    pub fn free_runs(&self, block_device: &Arc<dyn BlockDevice>) -> (usize, usize) {
+        let (mut runs, mut longest, mut current) = (0, 0, 0);
+        for block_id in 0..self.blocks {
+            let bits = self.bits.saturating_sub(block_id * BLOCK_BITS).min(BLOCK_BITS);
+            get_block_cache(block_id + self.start_block_id, Arc::clone(block_device))
+                .lock()
+                .read(0, |bitmap_block: &BitmapBlock| {
+                    for bit in 0..bits {
+                        if bitmap_block[bit / 64] & (1u64 << (bit % 64)) != 0 {
+                            current = 0;
+                            continue;
+                        }
+                        if current == 0 {
+                            runs += 1;
+                        }
+                        current += 1;
+                        longest = longest.max(current);
+                    }
+                });
+        }
+        (runs, longest)
+    }
     /// Get the max number of allocatable blocks
     pub fn maximum(&self) -> usize {
+        self.bits
+    }
+    /// Change the number of allocatable bits
+    pub fn set_maximum(&mut self, bits: usize) {
+        assert!(bits <= self.capacity());
+        self.bits = bits;
+    }
+    /// Get the number of bits the bitmap blocks can hold
+    pub fn capacity(&self) -> usize {
         self.blocks * BLOCK_BITS
     }
 }
@@ -67,4 +177,45 @@ fn decomposition(mut bit: usize) -> (usize, usize, usize) {
     let block_pos = bit / BLOCK_BITS;
     bit %= BLOCK_BITS;
     (block_pos, bit / 64, bit % 64)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_dev::ram_device;
+
+    #[test]
+    fn alloc_in_order_across_bitmap_blocks() {
+        let block_device = ram_device(3);
+        let bitmap = Bitmap::new(1, 2);
+        for bit in 0..BLOCK_BITS + 10 {
+            assert_eq!(bitmap.alloc(&block_device), Some(bit));
+        }
+        bitmap.dealloc(&block_device, 3);
+        bitmap.dealloc(&block_device, BLOCK_BITS + 1);
+        assert_eq!(bitmap.alloc(&block_device), Some(3));
+        assert_eq!(bitmap.alloc(&block_device), Some(BLOCK_BITS + 1));
+        assert_eq!(bitmap.alloc(&block_device), Some(BLOCK_BITS + 10));
+    }
+
+    #[test]
+    fn alloc_stops_at_maximum() {
+        let block_device = ram_device(2);
+        let mut bitmap = Bitmap::with_maximum(1, 1, 100);
+        for bit in 0..100 {
+            assert_eq!(bitmap.alloc(&block_device), Some(bit));
+        }
+        assert_eq!(bitmap.alloc(&block_device), None);
+        bitmap.set_maximum(101);
+        assert_eq!(bitmap.alloc(&block_device), Some(100));
+        assert_eq!(bitmap.alloc(&block_device), None);
+        assert_eq!(bitmap.capacity(), BLOCK_BITS);
+    }
+
+    #[test]
+    #[should_panic]
+    fn dealloc_free_bit() {
+        let block_device = ram_device(2);
+        Bitmap::new(1, 1).dealloc(&block_device, 7);
+    }
+}
@@ -1,21 +1,85 @@
-use super::{BlockDevice, BLOCK_SZ};
+use super::{BlockDevice, DiskFormat, BLOCK_SZ};
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use lazy_static::*;
 use spin::Mutex;
 
+/// Default capacity of the block cache
 const BLOCK_CACHE_SIZE: usize = 16;
+
+/// When modified blocks are written back to the device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// On eviction or [`block_cache_sync_all`], the default
+    WriteBack,
+    /// On every modification
+    WriteThrough,
+}
+
+/// Counters of the block cache, see [`block_cache_stats`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    /// Lookups served from the cache
+    pub hits: u64,
+    /// Lookups which had to load the block
+    pub misses: u64,
+    /// Entries dropped to make room for others
+    pub evictions: u64,
+    /// Misses which found every entry pinned, so the cache grew past its capacity.
+    /// 被持有的块缓存不能淘汰，否则同一个块会有两份不一致的缓存；它们被释放后，
+    /// 下一次未命中会把缓存收缩回容量以内
+    pub overflows: u64,
+    /// Modified entries written back to the device
+    pub write_backs: u64,
+    /// Most entries held outside the cache at the same time, sampled on misses.
+    /// 超过容量说明被持有的块缓存无法淘汰，缓存暂时多占用了内存
+    pub pinned_high_water: usize,
+    /// Number of entries now
+    pub entries: usize,
+    /// Number of entries kept before evicting
+    pub capacity: usize,
+}
+
+/// 管理器与它的块缓存共享的状态：写回发生在块缓存自己的锁下，不经过管理器的锁
+#[derive(Default)]
+struct SharedState {
+    write_backs: AtomicU64,
+    write_through: AtomicBool,
+}
+
 pub struct BlockCache {
     cache: [u8; BLOCK_SZ],
     block_id: usize,
     ///底层块设备的引用，可通过它进行块读写
     block_device: Arc<dyn BlockDevice>,
     modified: bool,
+    shared: Arc<SharedState>,
 }
 
 impl BlockCache {
+    /// Create a zeroed BlockCache without reading the disk, for a block whose old contents are of no use.
+    fn zeroed(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        shared: Arc<SharedState>,
+    ) -> Self {
+        Self {
+            cache: [0u8; BLOCK_SZ],
+            block_id,
+            block_device,
+            modified: false,
+            shared,
+        }
+    }
+
     /// Load a new BlockCache from disk.
-    pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
+    fn new(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        shared: Arc<SharedState>,
+    ) -> Self {
         let mut cache = [0u8; BLOCK_SZ];
         block_device.read_block(block_id, &mut cache);
         Self {
@@ -23,47 +87,43 @@ impl BlockCache {
             block_id,
             block_device,
             modified: false,
+            shared,
         }
     }
 
-    /// Load a new BlockCache from disk with offset.
-    fn addr_of_offset(&self, offset: usize) -> usize {
-        &self.cache[offset] as *const _ as usize
-    }
-
-    pub fn get_ref<T>(&self, offset: usize) -> &T
-    where
-        T: Sized,
-    {
-        let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SZ);
-        let addr = self.addr_of_offset(offset);
-        unsafe { &*(addr as *const T) }
+    /// Decode a structure from the cache at offset.
+    pub fn get<T: DiskFormat>(&self, offset: usize) -> T {
+        assert!(offset + T::DISK_SZ <= BLOCK_SZ);
+        T::decode(&self.cache[offset..])
     }
 
-    pub fn get_mut<T>(&mut self, offset: usize) -> &mut T
-    where
-        T: Sized,
-    {
-        let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SZ);
+    /// Encode a structure into the cache at offset, written back at once under [`FlushPolicy::WriteThrough`].
+    pub fn set<T: DiskFormat>(&mut self, offset: usize, value: &T) {
+        assert!(offset + T::DISK_SZ <= BLOCK_SZ);
         self.modified = true;
-        let addr = self.addr_of_offset(offset);
-        unsafe { &mut *(addr as *mut T) }
+        value.encode(&mut self.cache[offset..]);
+        if self.shared.write_through.load(Ordering::Relaxed) {
+            self.sync();
+        }
     }
     pub fn sync(&mut self) {
         if self.modified {
             self.modified = false;
             self.block_device.write_block(self.block_id, &self.cache);
+            self.shared.write_backs.fetch_add(1, Ordering::Relaxed);
         }
     }
     /// Read data from the cache at a specific offset using a closure(闭包).
-    pub fn read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
-        f(self.get_ref(offset))
+    pub fn read<T: DiskFormat, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
+        f(&self.get(offset))
     }
 
-    pub fn modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
-        f(self.get_mut(offset))
+    /// Modify data in the cache at a specific offset using a closure, the value is encoded back afterwards.
+    pub fn modify<T: DiskFormat, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
+        let mut value = self.get(offset);
+        let ret = f(&mut value);
+        self.set(offset, &value);
+        ret
     }
 }
 impl Drop for BlockCache {
@@ -72,15 +132,43 @@ impl Drop for BlockCache {
     }
 }
 
+/// 以 (设备, 块号) 为键，不同设备上相同块号的块各自缓存
+type CacheKey = (usize, usize);
+
 ///FIFO
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    queue: VecDeque<(CacheKey, Arc<Mutex<BlockCache>>)>,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    overflows: u64,
+    pinned_high_water: usize,
+    shared: Arc<SharedState>,
+}
+
+/// Key of a block in the cache, the device is identified by its address.
+/// 块缓存持有设备的引用，缓存存在期间设备的地址不会被复用
+fn cache_key(block_id: usize, block_device: &Arc<dyn BlockDevice>) -> CacheKey {
+    (Arc::as_ptr(block_device) as *const () as usize, block_id)
+}
+
+/// 块缓存在队列之外还被持有，不能淘汰
+fn is_pinned(block_cache: &Arc<Mutex<BlockCache>>) -> bool {
+    Arc::strong_count(block_cache) > 1
 }
 
 impl BlockCacheManager{
     pub fn new() -> Self {
         Self {
             queue: VecDeque::new(),
+            capacity: BLOCK_CACHE_SIZE,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            overflows: 0,
+            pinned_high_water: 0,
+            shared: Arc::new(SharedState::default()),
         }
     }
 
@@ -90,34 +178,103 @@ impl BlockCacheManager{
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == block_id) {
+        self.get_block_cache_with(block_id, block_device, BlockCache::new)
+    }
+
+    /// 同 [`BlockCacheManager::get_block_cache`]，块不在缓存中时用 `load` 创建块缓存
+    fn get_block_cache_with(
+        &mut self,
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        load: fn(usize, Arc<dyn BlockDevice>, Arc<SharedState>) -> BlockCache,
+    ) -> Arc<Mutex<BlockCache>> {
+        let key = cache_key(block_id, &block_device);
+        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == key) {
+            self.hits += 1;
             Arc::clone(&pair.1)
         } else {
+            self.misses += 1;
             // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self
-                    .queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
-                {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
+            // 多个线程同时持有的块缓存可能超过容量，此时暂时多占用一些，等它们空闲后再淘汰
+            self.evict(self.capacity - 1);
+            if self.queue.len() >= self.capacity {
+                self.overflows += 1;
             }
             // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(BlockCache::new(
+            let block_cache = Arc::new(Mutex::new(load(
                 block_id,
                 Arc::clone(&block_device),
+                Arc::clone(&self.shared),
             )));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
+            self.queue.push_back((key, Arc::clone(&block_cache)));
+            // 只在未命中时统计，命中路径不扫描整个队列
+            let pinned = self.queue.iter().filter(|pair| is_pinned(&pair.1)).count();
+            self.pinned_high_water = self.pinned_high_water.max(pinned);
             block_cache
         }
     }
 
+    /// Evict entries which are not held elsewhere, oldest first, until at most `entries` are left
+    fn evict(&mut self, entries: usize) {
+        while self.queue.len() > entries {
+            // from front to tail
+            match self.queue.iter().position(|pair| !is_pinned(&pair.1)) {
+                Some(idx) => {
+                    self.queue.remove(idx);
+                    self.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Get the counters
+    pub fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            overflows: self.overflows,
+            write_backs: self.shared.write_backs.load(Ordering::Relaxed),
+            pinned_high_water: self.pinned_high_water,
+            entries: self.queue.len(),
+            capacity: self.capacity,
+        }
+    }
+
+    /// Reset the counters to 0
+    pub fn reset_stats(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+        self.evictions = 0;
+        self.overflows = 0;
+        self.pinned_high_water = 0;
+        self.shared.write_backs.store(0, Ordering::Relaxed);
+    }
+
+    /// Change the number of entries kept, evicting the oldest ones beyond it
+    pub fn set_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0, "the block cache needs at least one entry");
+        self.capacity = capacity;
+        self.evict(capacity);
+    }
+
+    /// Get the flush policy
+    pub fn flush_policy(&self) -> FlushPolicy {
+        if self.shared.write_through.load(Ordering::Relaxed) {
+            FlushPolicy::WriteThrough
+        } else {
+            FlushPolicy::WriteBack
+        }
+    }
 
+    /// Change the flush policy, it applies from the next modification.
+    /// Blocks already modified are written back as before.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.shared
+            .write_through
+            .store(policy == FlushPolicy::WriteThrough, Ordering::Relaxed);
+    }
 }
 
 lazy_static! {
@@ -135,10 +292,133 @@ pub fn get_block_cache(
         .get_block_cache(block_id, block_device)
 }
 
-/// Sync all block cache to block device
+/// Zero a block through the cache, without reading its old contents from the device
+pub fn zero_block(block_id: usize, block_device: Arc<dyn BlockDevice>) {
+    // 不持有管理器的锁去等待块缓存的锁
+    let block_cache = BLOCK_CACHE_MANAGER
+        .lock()
+        .get_block_cache_with(block_id, block_device, BlockCache::zeroed);
+    block_cache
+        .lock()
+        .modify(0, |data_block: &mut [u8; BLOCK_SZ]| data_block.fill(0));
+}
+
+/// Get the counters of the block cache
+pub fn block_cache_stats() -> BlockCacheStats {
+    BLOCK_CACHE_MANAGER.lock().stats()
+}
+
+/// Reset the counters of the block cache to 0
+pub fn block_cache_reset_stats() {
+    BLOCK_CACHE_MANAGER.lock().reset_stats()
+}
+
+/// Change the number of blocks the cache keeps, 16 by default
+pub fn block_cache_set_capacity(capacity: usize) {
+    BLOCK_CACHE_MANAGER.lock().set_capacity(capacity)
+}
+
+/// Get when the block cache writes modified blocks back
+pub fn block_cache_flush_policy() -> FlushPolicy {
+    BLOCK_CACHE_MANAGER.lock().flush_policy()
+}
+
+/// Change when the block cache writes modified blocks back
+pub fn block_cache_set_flush_policy(policy: FlushPolicy) {
+    BLOCK_CACHE_MANAGER.lock().set_flush_policy(policy)
+}
+
+/// Sync all block cache to block device, then flush the devices
 pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
-        cache.lock().sync();
+    // 先取出所有块缓存再逐个同步，避免持有管理器的锁时等待某个块缓存的锁
+    let caches: Vec<_> = BLOCK_CACHE_MANAGER
+        .lock()
+        .queue
+        .iter()
+        .map(|(key, cache)| (key.0, Arc::clone(cache)))
+        .collect();
+    let mut devices: Vec<(usize, Arc<dyn BlockDevice>)> = Vec::new();
+    for (device, cache) in caches {
+        let mut cache = cache.lock();
+        cache.sync();
+        if devices.iter().all(|(other, _)| *other != device) {
+            devices.push((device, Arc::clone(&cache.block_device)));
+        }
+    }
+    for (_, block_device) in devices {
+        block_device.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_dev::ram_device;
+
+    #[test]
+    fn counters_and_capacity() {
+        let block_device = ram_device(64);
+        let mut manager = BlockCacheManager::new();
+        manager.set_capacity(4);
+        for block_id in 0..6 {
+            manager
+                .get_block_cache(block_id, Arc::clone(&block_device))
+                .lock()
+                .set(0, &(block_id as u32));
+        }
+        // 块 0、1 被淘汰时写回
+        let pinned = manager.get_block_cache(5, Arc::clone(&block_device));
+        let stats = manager.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 6, 2));
+        assert_eq!((stats.write_backs, stats.entries, stats.capacity), (2, 4, 4));
+        assert_eq!(stats.pinned_high_water, 1);
+        // 被持有的块缓存不会被淘汰
+        manager.set_capacity(1);
+        assert_eq!(manager.stats().entries, 1);
+        assert_eq!(manager.stats().write_backs, 5);
+        assert_eq!(pinned.lock().get::<u32>(0), 5);
+        manager.reset_stats();
+        assert_eq!(
+            manager.stats(),
+            BlockCacheStats {
+                entries: 1,
+                capacity: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn overflow_when_every_entry_is_pinned() {
+        let block_device = ram_device(8);
+        let mut manager = BlockCacheManager::new();
+        manager.set_capacity(2);
+        let pinned: Vec<_> = (0..3)
+            .map(|block_id| manager.get_block_cache(block_id, Arc::clone(&block_device)))
+            .collect();
+        let stats = manager.stats();
+        assert_eq!((stats.overflows, stats.entries, stats.evictions), (1, 3, 0));
+        // 释放之后，下一次未命中收缩回容量以内
+        drop(pinned);
+        manager.get_block_cache(3, Arc::clone(&block_device));
+        let stats = manager.stats();
+        assert_eq!((stats.overflows, stats.entries, stats.evictions), (1, 2, 2));
+    }
+
+    #[test]
+    fn write_through() {
+        let block_device = ram_device(4);
+        let mut manager = BlockCacheManager::new();
+        assert_eq!(manager.flush_policy(), FlushPolicy::WriteBack);
+        manager.set_flush_policy(FlushPolicy::WriteThrough);
+        let block_cache = manager.get_block_cache(1, Arc::clone(&block_device));
+        block_cache.lock().set(8, &0x1234_5678u32);
+        let mut buf = [0u8; BLOCK_SZ];
+        block_device.read_block(1, &mut buf);
+        assert_eq!(buf[8..12], 0x1234_5678u32.to_le_bytes());
+        assert_eq!(manager.stats().write_backs, 1);
+        // 已写回的块不会再次写回
+        block_cache.lock().sync();
+        assert_eq!(manager.stats().write_backs, 1);
     }
 }
@@ -1,4 +1,9 @@
+use super::BLOCK_SZ;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::any::Any;
+use core::ops::Range;
+use spin::Mutex;
 /// Trait for block devices
 /// which reads and writes data in the unit of blocks
 /// send :表示类型可以安全地在线程间传递所有权
@@ -14,4 +19,53 @@ pub trait BlockDevice: Send + Sync + Any {
     fn read_block(&self, block_id: usize, buf: &mut [u8]);
     ///Write data from buffer to block
     fn write_block(&self, block_id: usize, buf: &[u8]);
+    ///Make the blocks written so far durable, a device may keep writes in a volatile cache until then
+    fn flush(&self) {}
+    ///Tell the device the blocks in `blocks` are no longer in use, so that it can reclaim them.
+    ///Their contents are undefined afterwards, the default does nothing
+    fn discard(&self, _blocks: Range<usize>) {}
+}
+
+/// A block device over a buffer in memory, which can serve as a ramdisk
+pub struct RamBlockDevice(Mutex<Vec<u8>>);
+
+impl RamBlockDevice {
+    /// Create a zeroed device of `total_blocks` blocks
+    pub fn new(total_blocks: usize) -> Self {
+        Self(Mutex::new(vec![0u8; total_blocks * BLOCK_SZ]))
+    }
+    /// Use an image in memory as a device, its length must be a multiple of [`BLOCK_SZ`]
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        assert_eq!(bytes.len() % BLOCK_SZ, 0, "Not a whole number of blocks!");
+        Self(Mutex::new(bytes))
+    }
+    /// Get a copy of the whole image
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.lock().clone()
+    }
+    /// Number of blocks in the device
+    pub fn total_blocks(&self) -> usize {
+        self.0.lock().len() / BLOCK_SZ
+    }
+}
+
+/// A zeroed ramdisk of `total_blocks` blocks for the tests
+#[cfg(test)]
+pub(crate) fn ram_device(total_blocks: usize) -> alloc::sync::Arc<dyn BlockDevice> {
+    alloc::sync::Arc::new(RamBlockDevice::new(total_blocks))
+}
+
+impl BlockDevice for RamBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let data = self.0.lock();
+        let start = block_id * BLOCK_SZ;
+        assert!(start + buf.len() <= data.len(), "Block {} is out of range!", block_id);
+        buf.copy_from_slice(&data[start..start + buf.len()]);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut data = self.0.lock();
+        let start = block_id * BLOCK_SZ;
+        assert!(start + buf.len() <= data.len(), "Block {} is out of range!", block_id);
+        data[start..start + buf.len()].copy_from_slice(buf);
+    }
 }
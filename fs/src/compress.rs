@@ -0,0 +1,175 @@
+//! 按簇透明压缩文件数据
+//!
+//! 压缩文件的数据按 [`CLUSTER_SZ`] 字节划分为簇，第 `i` 簇固定占用块映射中
+//! `i * CLUSTER_BLOCKS..(i + 1) * CLUSTER_BLOCKS` 这一段表项（簇映射），
+//! 因此随机读取只需解压目标所在的簇。一段表项中实际分配的块数 `k` 决定了簇的存储方式：
+//!
+//! - `k == 0`：空洞，整簇为 0
+//! - `0 < k < CLUSTER_BLOCKS`：前 4 字节为压缩数据长度（小端），随后是 LZ4 块格式的压缩数据
+//! - `k == CLUSTER_BLOCKS`：压缩后放不下时按原样存储
+use super::{get_block_cache, BlockDevice, DiskInode, FileSystem, InodeCipher, BLOCK_SZ};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use lz4_flex::block::{compress_into, decompress_into, get_maximum_output_size};
+
+/// Number of blocks in a compression cluster
+pub const CLUSTER_BLOCKS: usize = 8;
+/// Size of a compression cluster in bytes
+pub const CLUSTER_SZ: usize = CLUSTER_BLOCKS * BLOCK_SZ;
+const HEADER_SZ: usize = 4;
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// Load a whole cluster of plain data into `out`
+fn load_cluster(
+    disk_inode: &DiskInode,
+    cluster: usize,
+    out: &mut [u8],
+    block_device: &Arc<dyn BlockDevice>,
+    cipher: Option<&InodeCipher>,
+) {
+    let first = (cluster * CLUSTER_BLOCKS) as u32;
+    let blocks: Vec<u32> = (0..CLUSTER_BLOCKS as u32)
+        .map(|i| disk_inode.get_block_id(first + i, block_device))
+        .take_while(|block_id| *block_id != 0)
+        .collect();
+    if blocks.is_empty() {
+        out.fill(0);
+        return;
+    }
+    let mut stored = vec![0u8; blocks.len() * BLOCK_SZ];
+    for (i, (chunk, block_id)) in stored.chunks_mut(BLOCK_SZ).zip(blocks.iter()).enumerate() {
+        get_block_cache(*block_id as usize, Arc::clone(block_device))
+            .lock()
+            .read(0, |data_block: &DataBlock| chunk.copy_from_slice(data_block));
+        if let Some(cipher) = cipher {
+            cipher.decrypt_block(first + i as u32, chunk);
+        }
+    }
+    if blocks.len() == CLUSTER_BLOCKS {
+        out.copy_from_slice(&stored);
+        return;
+    }
+    let len = u32::from_le_bytes(stored[..HEADER_SZ].try_into().unwrap()) as usize;
+    let n = decompress_into(&stored[HEADER_SZ..HEADER_SZ + len], out)
+        .expect("corrupted compressed cluster");
+    out[n..].fill(0);
+}
+
+/// Compress a whole cluster and store it, allocating or releasing blocks of its slot.
+/// Return false if the owner's quota does not allow the blocks it needs.
+fn store_cluster(
+    disk_inode: &mut DiskInode,
+    cluster: usize,
+    data: &[u8],
+    fs: &FileSystem,
+    cipher: Option<&InodeCipher>,
+) -> bool {
+    let block_device = Arc::clone(&fs.block_device);
+    let stored = if data.iter().all(|byte| *byte == 0) {
+        Vec::new()
+    } else {
+        let mut compressed = vec![0u8; HEADER_SZ + get_maximum_output_size(CLUSTER_SZ)];
+        let len = compress_into(data, &mut compressed[HEADER_SZ..]).unwrap();
+        if HEADER_SZ + len <= (CLUSTER_BLOCKS - 1) * BLOCK_SZ {
+            compressed[..HEADER_SZ].copy_from_slice(&(len as u32).to_le_bytes());
+            compressed.truncate(HEADER_SZ + len);
+            compressed
+        } else {
+            data.to_vec()
+        }
+    };
+    let used_blocks = stored.len().div_ceil(BLOCK_SZ);
+    let first = cluster * CLUSTER_BLOCKS;
+    let allocated = (0..CLUSTER_BLOCKS)
+        .filter(|i| disk_inode.get_block_id((first + i) as u32, &block_device) != 0)
+        .count();
+    if used_blocks > allocated && !fs.charge(disk_inode.uid(), (used_blocks - allocated) as u32, 0) {
+        return false;
+    }
+    for i in 0..CLUSTER_BLOCKS {
+        let inner_id = (first + i) as u32;
+        let mut block_id = disk_inode.get_block_id(inner_id, &block_device);
+        if i < used_blocks {
+            if block_id == 0 {
+                block_id = fs.alloc_data();
+                disk_inode.set_block_id(inner_id, block_id, &block_device);
+            }
+            let chunk = &stored[i * BLOCK_SZ..stored.len().min((i + 1) * BLOCK_SZ)];
+            get_block_cache(block_id as usize, Arc::clone(&block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    data_block[..chunk.len()].copy_from_slice(chunk);
+                    data_block[chunk.len()..].fill(0);
+                    if let Some(cipher) = cipher {
+                        cipher.encrypt_block(inner_id, data_block);
+                    }
+                });
+        } else if block_id != 0 {
+            disk_inode.set_block_id(inner_id, 0, &block_device);
+            fs.dealloc_data(block_id);
+            fs.release(disk_inode.uid(), 1, 0);
+        }
+    }
+    true
+}
+
+/// Read data from a compressed disk inode, decompressing the clusters it touches.
+/// Return the number of bytes read.
+pub fn read_at(
+    disk_inode: &DiskInode,
+    offset: usize,
+    buf: &mut [u8],
+    block_device: &Arc<dyn BlockDevice>,
+    cipher: Option<&InodeCipher>,
+) -> usize {
+    let end = (offset + buf.len()).min(disk_inode.size as usize);
+    if offset >= end {
+        return 0;
+    }
+    let mut cluster_data = vec![0u8; CLUSTER_SZ];
+    let mut start = offset;
+    while start < end {
+        let cluster = start / CLUSTER_SZ;
+        let end_current_cluster = ((cluster + 1) * CLUSTER_SZ).min(end);
+        load_cluster(disk_inode, cluster, &mut cluster_data, block_device, cipher);
+        let src = &cluster_data[start % CLUSTER_SZ..start % CLUSTER_SZ + end_current_cluster - start];
+        buf[start - offset..end_current_cluster - offset].copy_from_slice(src);
+        start = end_current_cluster;
+    }
+    end - offset
+}
+
+/// Write data into a compressed disk inode, recompressing the clusters it touches.
+/// size must be adjusted properly beforehand.
+/// Stop at the first cluster the owner's quota cannot hold, return the number of bytes written.
+pub fn write_at(
+    disk_inode: &mut DiskInode,
+    offset: usize,
+    buf: &[u8],
+    fs: &FileSystem,
+    cipher: Option<&InodeCipher>,
+) -> usize {
+    let block_device = Arc::clone(&fs.block_device);
+    let end = (offset + buf.len()).min(disk_inode.size as usize);
+    assert!(offset <= end);
+    let mut cluster_data = vec![0u8; CLUSTER_SZ];
+    let mut start = offset;
+    while start < end {
+        let cluster = start / CLUSTER_SZ;
+        let end_current_cluster = ((cluster + 1) * CLUSTER_SZ).min(end);
+        // 只覆盖簇的一部分时需要先解压旧数据
+        if end_current_cluster - start < CLUSTER_SZ {
+            load_cluster(disk_inode, cluster, &mut cluster_data, &block_device, cipher);
+        }
+        cluster_data[start % CLUSTER_SZ..start % CLUSTER_SZ + end_current_cluster - start]
+            .copy_from_slice(&buf[start - offset..end_current_cluster - offset]);
+        if !store_cluster(disk_inode, cluster, &cluster_data, fs, cipher) {
+            return start - offset;
+        }
+        start = end_current_cluster;
+    }
+    end - offset
+}
@@ -0,0 +1,185 @@
+//! 文件内容加密：每个 inode 使用独立密钥的 XTS-AES-128
+//!
+//! 挂载时提供的 32 字节主密钥与 inode 编号经 SHA-256 派生出该 inode 的
+//! XTS 密钥（数据密钥与 tweak 密钥各 16 字节），tweak 为块在文件内的序号。
+//! 加密发生在 [`crate::DiskInode`] 读写数据块时，块缓存中保存的始终是密文。
+//! 全 0 的密文块视为全 0 的明文，这样尚未写过的块和空洞读出来仍然是 0。
+//! 超级块记录第一次提供的主密钥的校验值，之后提供的密钥与之不符时拒绝使用。
+use super::BLOCK_SZ;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use sha2::{Digest, Sha256};
+
+/// Length of the key supplied at mount time
+pub const KEY_SZ: usize = 32;
+const AES_BLOCK_SZ: usize = 16;
+
+/// Key check value of a mount key, a hash which tells whether the right key has been supplied.
+/// 与派生 inode 密钥的输入长度不同，不会与任何 inode 密钥相同
+pub fn key_check(key: &[u8; KEY_SZ]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"easy-fs key check");
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+/// XTS-AES cipher of one inode
+pub struct InodeCipher {
+    data: Aes128,
+    tweak: Aes128,
+}
+
+impl InodeCipher {
+    /// Derive the cipher of inode `inode_id` from the mount key
+    pub fn new(key: &[u8; KEY_SZ], inode_id: u32) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        hasher.update(inode_id.to_le_bytes());
+        let inode_key = hasher.finalize();
+        Self {
+            data: Aes128::new(GenericArray::from_slice(&inode_key[..AES_BLOCK_SZ])),
+            tweak: Aes128::new(GenericArray::from_slice(&inode_key[AES_BLOCK_SZ..])),
+        }
+    }
+
+    /// Initial tweak of the block with inner id `block_index`
+    fn initial_tweak(&self, block_index: u32) -> [u8; AES_BLOCK_SZ] {
+        let mut tweak = [0u8; AES_BLOCK_SZ];
+        tweak[..4].copy_from_slice(&block_index.to_le_bytes());
+        self.tweak
+            .encrypt_block(GenericArray::from_mut_slice(&mut tweak));
+        tweak
+    }
+
+    /// Encrypt a data block in place
+    pub fn encrypt_block(&self, block_index: u32, block: &mut [u8]) {
+        assert_eq!(block.len(), BLOCK_SZ);
+        let mut tweak = self.initial_tweak(block_index);
+        for chunk in block.chunks_mut(AES_BLOCK_SZ) {
+            xor(chunk, &tweak);
+            self.data.encrypt_block(GenericArray::from_mut_slice(chunk));
+            xor(chunk, &tweak);
+            mul_alpha(&mut tweak);
+        }
+    }
+
+    /// Decrypt a data block in place
+    pub fn decrypt_block(&self, block_index: u32, block: &mut [u8]) {
+        assert_eq!(block.len(), BLOCK_SZ);
+        if block.iter().all(|byte| *byte == 0) {
+            return;
+        }
+        let mut tweak = self.initial_tweak(block_index);
+        for chunk in block.chunks_mut(AES_BLOCK_SZ) {
+            xor(chunk, &tweak);
+            self.data.decrypt_block(GenericArray::from_mut_slice(chunk));
+            xor(chunk, &tweak);
+            mul_alpha(&mut tweak);
+        }
+    }
+}
+
+fn xor(chunk: &mut [u8], tweak: &[u8; AES_BLOCK_SZ]) {
+    chunk.iter_mut().zip(tweak.iter()).for_each(|(a, b)| *a ^= *b);
+}
+
+/// Multiply the tweak by the primitive element of GF(2^128), little-endian as in XTS
+fn mul_alpha(tweak: &mut [u8; AES_BLOCK_SZ]) {
+    let mut carry = 0u8;
+    for byte in tweak.iter_mut() {
+        let next_carry = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next_carry;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_dev::ram_device;
+    use crate::{BlockDevice, FileSystem};
+    use alloc::sync::Arc;
+
+    const KEY: [u8; KEY_SZ] = [7; KEY_SZ];
+    const PATTERN: u8 = 0x5a;
+
+    #[test]
+    fn cipher_round_trip() {
+        let cipher = InodeCipher::new(&KEY, 1);
+        let mut block = [PATTERN; BLOCK_SZ];
+        cipher.encrypt_block(3, &mut block);
+        assert_ne!(block, [PATTERN; BLOCK_SZ]);
+        // 同一明文在不同块、不同 inode 中的密文各不相同
+        let mut other_block = [PATTERN; BLOCK_SZ];
+        cipher.encrypt_block(4, &mut other_block);
+        assert_ne!(block, other_block);
+        let mut other_inode = [PATTERN; BLOCK_SZ];
+        InodeCipher::new(&KEY, 2).encrypt_block(3, &mut other_inode);
+        assert_ne!(block, other_inode);
+        cipher.decrypt_block(3, &mut block);
+        assert_eq!(block, [PATTERN; BLOCK_SZ]);
+        let mut hole = [0u8; BLOCK_SZ];
+        cipher.decrypt_block(3, &mut hole);
+        assert_eq!(hole, [0u8; BLOCK_SZ]);
+    }
+
+    /// An image with the encrypted file `a` holding one block of `PATTERN`
+    fn image() -> Arc<dyn BlockDevice> {
+        let block_device = ram_device(4096);
+        let efs = FileSystem::create(Arc::clone(&block_device), 4096, 1);
+        assert!(efs.set_key(KEY));
+        let file = FileSystem::root_inode(&efs).create("a").unwrap();
+        assert!(file.set_encrypted(false));
+        assert_eq!(file.write_at(0, &[PATTERN; BLOCK_SZ]), BLOCK_SZ);
+        efs.sync();
+        block_device
+    }
+
+    #[test]
+    fn encrypted_file_round_trip() {
+        let block_device = image();
+        let mut buf = [0u8; BLOCK_SZ];
+        for block_id in 0..4096 {
+            block_device.read_block(block_id, &mut buf);
+            assert_ne!(buf, [PATTERN; BLOCK_SZ], "plaintext in block {}", block_id);
+        }
+        let efs = FileSystem::open_with_key(block_device, KEY).unwrap();
+        let file = FileSystem::root_inode(&efs).find("a").unwrap();
+        assert!(file.is_encrypted());
+        assert_eq!(file.read_at(0, &mut buf), BLOCK_SZ);
+        assert_eq!(buf, [PATTERN; BLOCK_SZ]);
+    }
+
+    #[test]
+    fn wrong_key_is_refused() {
+        let block_device = image();
+        let wrong_key = [8; KEY_SZ];
+        assert!(FileSystem::open_with_key(Arc::clone(&block_device), wrong_key).is_none());
+        let efs = FileSystem::open(block_device);
+        assert!(!efs.set_key(wrong_key));
+        // 拒绝的密钥不会被使用，文件仍然锁着
+        let file = FileSystem::root_inode(&efs).find("a").unwrap();
+        assert_eq!(file.read_at(0, &mut [0u8; BLOCK_SZ]), 0);
+        assert!(efs.set_key(KEY));
+        assert_eq!(file.read_at(0, &mut [0u8; BLOCK_SZ]), BLOCK_SZ);
+    }
+
+    #[test]
+    fn locked_inode() {
+        let efs = FileSystem::open(image());
+        let root_inode = FileSystem::root_inode(&efs);
+        let file = root_inode.find("a").unwrap();
+        assert!(file.is_encrypted());
+        let mut buf = [0u8; BLOCK_SZ];
+        assert_eq!(file.read_at(0, &mut buf), 0);
+        assert_eq!(file.write_at(0, &[1; BLOCK_SZ]), 0);
+        assert!(!root_inode.create("b").unwrap().set_encrypted(false));
+        assert!(efs.set_key(KEY));
+        assert_eq!(file.read_at(0, &mut buf), BLOCK_SZ);
+        assert_eq!(buf, [PATTERN; BLOCK_SZ]);
+    }
+}
@@ -0,0 +1,236 @@
+//! 离线碎片整理：把每个 inode 的数据块和索引块搬到一段连续的空闲块中
+//!
+//! 每个 inode 按以下顺序搬移，每一步之后都把块缓存写回设备：
+//! 1. 在数据位图中分配目标块；
+//! 2. 把旧块复制到目标块，并改写目标索引块中的块号；
+//! 3. 写入指向目标块的磁盘 inode，它只占一个块，这一步要么完成要么没有发生；
+//! 4. 释放旧块。
+//!
+//! 在任何一步中断，每个文件都完整地指向旧块或新块，镜像中至多有一些已分配却无人使用的块，
+//! 下一次整理开始时会回收它们。
+
+use super::{block_cache_sync_all, get_block_cache, BlockRole, FileSystem, SuperBlock};
+use crate::{AreaKind, BLOCK_SZ, FEATURE_COMPAT_VERITY};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// What a defragmentation pass did
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefragReport {
+    /// Number of inodes examined
+    pub inodes: u32,
+    /// Number of inodes moved into contiguous blocks
+    pub relocated: u32,
+    /// Number of fragmented inodes left as they are for lack of a long enough free run
+    pub skipped: u32,
+    /// Number of leaked blocks reclaimed before starting, left by an interrupted pass or crash
+    pub reclaimed: u32,
+    /// Runs of contiguous blocks summed over all inodes before the pass
+    pub fragments_before: u32,
+    /// Runs of contiguous blocks summed over all inodes after the pass
+    pub fragments_after: u32,
+}
+
+/// Count the runs of consecutive block ids
+fn count_runs(blocks: &[u32]) -> u32 {
+    let breaks = blocks
+        .windows(2)
+        .filter(|pair| pair[1] != pair[0] + 1)
+        .count() as u32;
+    if blocks.is_empty() {
+        0
+    } else {
+        breaks + 1
+    }
+}
+
+impl FileSystem {
+    /// Count the runs of contiguous blocks of an inode, index blocks included,
+    /// taking the blocks in the order they are allocated
+    pub fn inode_fragments(&self, inode_id: u32) -> u32 {
+        count_runs(
+            &self
+                .disk_inode(inode_id)
+                .blocks_in_order(&self.block_device),
+        )
+    }
+    /// Free the allocated data blocks which no inode refers to
+    fn reclaim_leaked_blocks(&self) -> u32 {
+        let owners = self.block_owners();
+        let mut reclaimed = 0;
+        for area in self.areas() {
+            if area.kind != AreaKind::DataArea {
+                continue;
+            }
+            for block_id in area.start_block..area.start_block + area.blocks {
+                if self.block_role(block_id, &owners) == BlockRole::Unowned {
+                    self.dealloc_data(block_id);
+                    reclaimed += 1;
+                }
+            }
+        }
+        self.sync();
+        reclaimed
+    }
+    /// Move the blocks of every fragmented inode into one run of free blocks,
+    /// see the module documentation for why an interrupted pass leaves a consistent image.
+    /// The filesystem must not be in use meanwhile. A hash tree built before is dropped,
+    /// as it no longer covers the image.
+    pub fn defragment(&self) -> DefragReport {
+        let mut report = DefragReport::default();
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.compat_features &= !FEATURE_COMPAT_VERITY;
+                super_block.verity_levels = 0;
+                super_block.verity_root = [0; 32];
+            });
+        report.reclaimed = self.reclaim_leaked_blocks();
+        for inode_id in self.allocated_inodes() {
+            let (inode_block_id, inode_offset) = self.get_disk_inode_pos(inode_id);
+            let mut disk_inode = self.disk_inode(inode_id);
+            let blocks = disk_inode.blocks_in_order(&self.block_device);
+            let fragments = count_runs(&blocks);
+            report.inodes += 1;
+            report.fragments_before += fragments;
+            if fragments <= 1 {
+                report.fragments_after += fragments;
+                continue;
+            }
+            // 1. 分配目标块
+            let start_block = match self.alloc_data_run(blocks.len() as u32) {
+                Some(start_block) => start_block,
+                None => {
+                    report.skipped += 1;
+                    report.fragments_after += fragments;
+                    continue;
+                }
+            };
+            block_cache_sync_all();
+            // 2. 复制到尚无引用的目标块，再改写其中的索引
+            let new_block_ids: BTreeMap<u32, u32> = blocks
+                .iter()
+                .enumerate()
+                .map(|(i, block_id)| (*block_id, start_block + i as u32))
+                .collect();
+            for (old_block_id, new_block_id) in new_block_ids.iter() {
+                let data: DataBlock =
+                    get_block_cache(*old_block_id as usize, Arc::clone(&self.block_device))
+                        .lock()
+                        .get(0);
+                get_block_cache(*new_block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .set(0, &data);
+            }
+            disk_inode.relocate(|block_id| new_block_ids[&block_id], &self.block_device);
+            block_cache_sync_all();
+            // 3. 切换到新块
+            get_block_cache(inode_block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .set(inode_offset, &disk_inode);
+            block_cache_sync_all();
+            // 4. 释放旧块
+            for block_id in blocks {
+                self.dealloc_data(block_id);
+            }
+            self.sync();
+            report.relocated += 1;
+            report.fragments_after += 1;
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{replay_crashes, CrashPolicy, FaultyBlockDevice, Inode, RamBlockDevice};
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    const TOTAL_BLOCKS: usize = 2048;
+
+    /// 交替追加两个文件，使它们的块互相穿插
+    fn fragmented_image() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let block_device = Arc::new(RamBlockDevice::new(TOTAL_BLOCKS));
+        let efs = FileSystem::create(block_device.clone(), TOTAL_BLOCKS as u32, 1);
+        let root_inode = FileSystem::root_inode(&efs);
+        let a = root_inode.create("a").unwrap();
+        let b = root_inode.create("b").unwrap();
+        b.set_compressed(true);
+        let data_a: Vec<u8> = (0..160 * BLOCK_SZ).map(|i| (i * 7 % 251) as u8).collect();
+        let data_b: Vec<u8> = (0..160 * BLOCK_SZ).map(|i| (i / 1000) as u8).collect();
+        for i in 0..160 / 8 {
+            let range = i * 8 * BLOCK_SZ..(i + 1) * 8 * BLOCK_SZ;
+            a.write_at(range.start, &data_a[range.clone()]);
+            b.write_at(range.start, &data_b[range]);
+        }
+        block_cache_sync_all();
+        (block_device.to_bytes(), data_a, data_b)
+    }
+
+    fn read_all(inode: &Inode) -> Vec<u8> {
+        let mut buf = vec![0u8; inode.size()];
+        inode.read_at(0, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn defragment_makes_files_contiguous() {
+        let (image, data_a, data_b) = fragmented_image();
+        let efs = FileSystem::open(Arc::new(RamBlockDevice::from_bytes(image)));
+        let root_inode = FileSystem::root_inode(&efs);
+        let a = root_inode.find("a").unwrap();
+        assert!(efs.inode_fragments(a.inode_id()) > 1);
+        let report = efs.defragment();
+        assert_eq!(report.relocated, 2);
+        assert_eq!(report.skipped, 0);
+        assert!(report.fragments_before > report.fragments_after);
+        assert_eq!(report.fragments_after, report.inodes);
+        assert_eq!(efs.inode_fragments(a.inode_id()), 1);
+        assert_eq!(read_all(&a), data_a);
+        assert_eq!(read_all(&root_inode.find("b").unwrap()), data_b);
+        assert!(efs.check().is_empty());
+        // 已经连续的文件不再搬移
+        assert_eq!(efs.defragment().relocated, 0);
+    }
+
+    #[test]
+    fn interrupted_defragment_only_leaks_blocks() {
+        let (base, data_a, data_b) = fragmented_image();
+        let failures = replay_crashes(
+            &base,
+            |efs| {
+                efs.defragment();
+            },
+            &[CrashPolicy::Keep, CrashPolicy::Reverse],
+        );
+        for failure in failures.iter() {
+            assert!(failure.mounted, "{:?}", failure);
+            assert!(
+                failure
+                    .problems
+                    .iter()
+                    .all(|problem| problem.ends_with("is allocated but unused")),
+                "{:?}",
+                failure
+            );
+        }
+        // 中断后再整理一次，泄漏的块被回收，文件内容不变
+        let block_device = Arc::new(FaultyBlockDevice::new(Arc::new(
+            RamBlockDevice::from_bytes(base.clone()),
+        )));
+        FileSystem::open(block_device.clone()).defragment();
+        for point in (0..=block_device.write_count()).step_by(17) {
+            let image = block_device.image_at(&base, point, CrashPolicy::Keep);
+            let efs = FileSystem::open(Arc::new(RamBlockDevice::from_bytes(image)));
+            efs.defragment();
+            assert!(efs.check().is_empty(), "crash point {}", point);
+            let root_inode = FileSystem::root_inode(&efs);
+            assert_eq!(read_all(&root_inode.find("a").unwrap()), data_a);
+            assert_eq!(read_all(&root_inode.find("b").unwrap()), data_b);
+        }
+    }
+}
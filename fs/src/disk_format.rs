@@ -0,0 +1,84 @@
+//! 磁盘数据结构的显式编解码
+//!
+//! 磁盘上的所有多字节整数都按小端存放，每个结构按字段顺序紧密排列，
+//! 与 Rust 结构体的内存布局无关。块缓存只通过 [`DiskFormat`] 读写结构，
+//! 因此磁盘上任意的字节都不会被直接当作 Rust 值使用。
+
+/// A structure with a fixed little-endian encoding on disk
+pub trait DiskFormat: Sized {
+    /// Size of the encoding in bytes
+    const DISK_SZ: usize;
+    /// Decode from the first `DISK_SZ` bytes
+    fn decode(bytes: &[u8]) -> Self;
+    /// Encode into the first `DISK_SZ` bytes
+    fn encode(&self, bytes: &mut [u8]);
+}
+
+macro_rules! impl_disk_format_for_int {
+    ($($t:ty),*) => {
+        $(
+            impl DiskFormat for $t {
+                const DISK_SZ: usize = core::mem::size_of::<$t>();
+                fn decode(bytes: &[u8]) -> Self {
+                    let mut buf = [0u8; core::mem::size_of::<$t>()];
+                    buf.copy_from_slice(&bytes[..Self::DISK_SZ]);
+                    <$t>::from_le_bytes(buf)
+                }
+                fn encode(&self, bytes: &mut [u8]) {
+                    bytes[..Self::DISK_SZ].copy_from_slice(&self.to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+impl_disk_format_for_int!(u8, u16, u32, u64);
+
+impl<T: DiskFormat, const N: usize> DiskFormat for [T; N] {
+    const DISK_SZ: usize = T::DISK_SZ * N;
+    fn decode(bytes: &[u8]) -> Self {
+        core::array::from_fn(|i| T::decode(&bytes[i * T::DISK_SZ..]))
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        for (i, item) in self.iter().enumerate() {
+            item.encode(&mut bytes[i * T::DISK_SZ..]);
+        }
+    }
+}
+
+/// Read fields one after another
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    /// Start decoding at the beginning of `bytes`
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+    /// Decode the next field
+    pub fn get<T: DiskFormat>(&mut self) -> T {
+        let value = T::decode(&self.bytes[self.pos..self.pos + T::DISK_SZ]);
+        self.pos += T::DISK_SZ;
+        value
+    }
+}
+
+/// Write fields one after another
+pub struct Encoder<'a> {
+    bytes: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Encoder<'a> {
+    /// Start encoding at the beginning of `bytes`
+    pub fn new(bytes: &'a mut [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+    /// Encode the next field
+    pub fn put<T: DiskFormat>(&mut self, value: &T) {
+        value.encode(&mut self.bytes[self.pos..self.pos + T::DISK_SZ]);
+        self.pos += T::DISK_SZ;
+    }
+}
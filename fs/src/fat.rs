@@ -0,0 +1,1609 @@
+//! FAT32 文件系统，支持读写和长文件名
+//!
+//! 与 easy-fs 共用 [`BlockDevice`] 和块缓存，通过 [`SuperBlockOps`] 等接口使用，只支持 512 字节的扇区。
+//! FAT 没有 inode，这里把短目录项在磁盘上的位置（扇区号乘 16 加扇区内的序号）当作 inode 编号，
+//! 根目录的编号为 0。删除目录项时只做标记，其余目录项不会移动，编号因此保持不变。
+//! 被删除的槽位可能被新的目录项复用，删除时仍被打开的句柄随之作废，之后的读写一律失败。
+//!
+//! 文件名比较只忽略 ASCII 字母的大小写。没有时钟，新目录项的时间戳固定为 1980-01-01 00:00。
+
+use super::{
+    block_cache_sync_all, get_block_cache, BlockDevice, Decoder, DirEntryInfo, DirIter, DirOps,
+    DiskFormat, Encoder, FileOps, FileType, InodeOps, SuperBlockOps, VfsInode, VfsStat, BLOCK_SZ,
+};
+use crate::fs::InodeTable;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, RwLock};
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// 引导扇区和 FSInfo 扇区末尾的签名
+const BOOT_SIGNATURE: u16 = 0xAA55;
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+/// FAT 表项只有低 28 位有效
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+/// 簇链结束的标记
+const FAT_EOC: u32 = 0x0FFF_FFFF;
+/// 簇数少于该值的卷会被识别为 FAT12 或 FAT16
+const MIN_CLUSTERS: u32 = 65525;
+/// inode 编号由扇区号乘 16 得到，扇区号不能超过 2^28
+const MAX_SECTORS: u32 = 1 << 28;
+const DIR_ENTRY_SZ: usize = 32;
+const ENTRIES_PER_BLOCK: usize = BLOCK_SZ / DIR_ENTRY_SZ;
+/// 一个目录最多有 65536 个目录项
+const MAX_DIR_ENTRIES: usize = 65536;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_LONG_NAME_MASK: u8 = 0x3F;
+/// 目录项首字节：已删除，以及本项和之后都未使用
+const ENTRY_DELETED: u8 = 0xE5;
+const ENTRY_END: u8 = 0x00;
+/// 短文件名首字节为 0xE5 时存为 0x05
+const ENTRY_KANJI_E5: u8 = 0x05;
+/// 长文件名目录项的序号中标记最后一项的位
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+/// 长文件名最多 255 个 UTF-16 码元
+const LONG_NAME_LIMIT: usize = 255;
+/// 短文件名的主名和扩展名以小写显示
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+/// 1980-01-01
+const FAT_DATE: u16 = (1 << 5) | 1;
+const RESERVED_SECTORS: u16 = 32;
+const FAT_COUNT: u8 = 2;
+const FSINFO_SECTOR: u16 = 1;
+const BACKUP_BOOT_SECTOR: u16 = 6;
+const MEDIA_FIXED_DISK: u8 = 0xF8;
+/// 短文件名中除字母和数字外允许的字符
+const SHORT_NAME_EXTRA: &[u8] = b"$%'-_@~`!(){}^#&";
+/// 长文件名中不允许的字符
+const LONG_NAME_INVALID: &str = "\"*/:<>?\\|";
+const ROOT_INODE_ID: u32 = 0;
+
+/// The boot sector with the BIOS parameter block of FAT32
+struct BootSector {
+    jump: [u8; 3],
+    oem_name: [u8; 8],
+    bytes_per_sector: u16,
+    sectors_per_cluster: u8,
+    reserved_sectors: u16,
+    fat_count: u8,
+    root_entries: u16,
+    total_sectors_16: u16,
+    media: u8,
+    fat_size_16: u16,
+    sectors_per_track: u16,
+    heads: u16,
+    hidden_sectors: u32,
+    total_sectors_32: u32,
+    fat_size_32: u32,
+    ext_flags: u16,
+    fs_version: u16,
+    root_cluster: u32,
+    fs_info_sector: u16,
+    backup_boot_sector: u16,
+    reserved: [u8; 12],
+    drive_number: u8,
+    reserved1: u8,
+    boot_signature: u8,
+    volume_id: u32,
+    volume_label: [u8; 11],
+    fs_type: [u8; 8],
+    signature: u16,
+}
+
+impl BootSector {
+    fn total_sectors(&self) -> u32 {
+        match self.total_sectors_16 {
+            0 => self.total_sectors_32,
+            total_sectors => total_sectors as u32,
+        }
+    }
+    fn is_valid(&self) -> bool {
+        self.signature == BOOT_SIGNATURE
+            && self.bytes_per_sector as usize == BLOCK_SZ
+            && self.sectors_per_cluster.is_power_of_two()
+            && self.reserved_sectors != 0
+            && self.fat_count != 0
+            // FAT32 的根目录在数据区中，没有 FAT16 的 16 位字段
+            && self.root_entries == 0
+            && self.fat_size_16 == 0
+            && self.fat_size_32 != 0
+            && self.root_cluster >= 2
+    }
+}
+
+impl DiskFormat for BootSector {
+    const DISK_SZ: usize = BLOCK_SZ;
+    fn decode(bytes: &[u8]) -> Self {
+        let mut d = Decoder::new(bytes);
+        Self {
+            jump: d.get(),
+            oem_name: d.get(),
+            bytes_per_sector: d.get(),
+            sectors_per_cluster: d.get(),
+            reserved_sectors: d.get(),
+            fat_count: d.get(),
+            root_entries: d.get(),
+            total_sectors_16: d.get(),
+            media: d.get(),
+            fat_size_16: d.get(),
+            sectors_per_track: d.get(),
+            heads: d.get(),
+            hidden_sectors: d.get(),
+            total_sectors_32: d.get(),
+            fat_size_32: d.get(),
+            ext_flags: d.get(),
+            fs_version: d.get(),
+            root_cluster: d.get(),
+            fs_info_sector: d.get(),
+            backup_boot_sector: d.get(),
+            reserved: d.get(),
+            drive_number: d.get(),
+            reserved1: d.get(),
+            boot_signature: d.get(),
+            volume_id: d.get(),
+            volume_label: d.get(),
+            fs_type: d.get(),
+            signature: u16::decode(&bytes[510..]),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let mut e = Encoder::new(bytes);
+        e.put(&self.jump);
+        e.put(&self.oem_name);
+        e.put(&self.bytes_per_sector);
+        e.put(&self.sectors_per_cluster);
+        e.put(&self.reserved_sectors);
+        e.put(&self.fat_count);
+        e.put(&self.root_entries);
+        e.put(&self.total_sectors_16);
+        e.put(&self.media);
+        e.put(&self.fat_size_16);
+        e.put(&self.sectors_per_track);
+        e.put(&self.heads);
+        e.put(&self.hidden_sectors);
+        e.put(&self.total_sectors_32);
+        e.put(&self.fat_size_32);
+        e.put(&self.ext_flags);
+        e.put(&self.fs_version);
+        e.put(&self.root_cluster);
+        e.put(&self.fs_info_sector);
+        e.put(&self.backup_boot_sector);
+        e.put(&self.reserved);
+        e.put(&self.drive_number);
+        e.put(&self.reserved1);
+        e.put(&self.boot_signature);
+        e.put(&self.volume_id);
+        e.put(&self.volume_label);
+        e.put(&self.fs_type);
+        // 引导代码留空
+        bytes[90..510].fill(0);
+        self.signature.encode(&mut bytes[510..]);
+    }
+}
+
+/// The FSInfo sector, a hint of the free clusters
+struct FsInfo {
+    lead_signature: u32,
+    struct_signature: u32,
+    free_count: u32,
+    next_free: u32,
+    trail_signature: u32,
+}
+
+impl FsInfo {
+    fn new(free_count: u32, next_free: u32) -> Self {
+        Self {
+            lead_signature: FSINFO_LEAD_SIGNATURE,
+            struct_signature: FSINFO_STRUCT_SIGNATURE,
+            free_count,
+            next_free,
+            trail_signature: FSINFO_TRAIL_SIGNATURE,
+        }
+    }
+    fn is_valid(&self) -> bool {
+        self.lead_signature == FSINFO_LEAD_SIGNATURE
+            && self.struct_signature == FSINFO_STRUCT_SIGNATURE
+            && self.trail_signature == FSINFO_TRAIL_SIGNATURE
+    }
+}
+
+impl DiskFormat for FsInfo {
+    const DISK_SZ: usize = BLOCK_SZ;
+    fn decode(bytes: &[u8]) -> Self {
+        Self {
+            lead_signature: u32::decode(bytes),
+            struct_signature: u32::decode(&bytes[484..]),
+            free_count: u32::decode(&bytes[488..]),
+            next_free: u32::decode(&bytes[492..]),
+            trail_signature: u32::decode(&bytes[508..]),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes[..BLOCK_SZ].fill(0);
+        self.lead_signature.encode(bytes);
+        self.struct_signature.encode(&mut bytes[484..]);
+        self.free_count.encode(&mut bytes[488..]);
+        self.next_free.encode(&mut bytes[492..]);
+        self.trail_signature.encode(&mut bytes[508..]);
+    }
+}
+
+/// A directory entry with a short 8.3 name
+#[derive(Clone, Copy)]
+struct ShortEntry {
+    name: [u8; 11],
+    attr: u8,
+    nt_reserved: u8,
+    create_time_tenth: u8,
+    create_time: u16,
+    create_date: u16,
+    access_date: u16,
+    cluster_high: u16,
+    write_time: u16,
+    write_date: u16,
+    cluster_low: u16,
+    size: u32,
+}
+
+impl ShortEntry {
+    fn new(name: [u8; 11], attr: u8, first_cluster: u32) -> Self {
+        let mut entry = Self {
+            name,
+            attr,
+            nt_reserved: 0,
+            create_time_tenth: 0,
+            create_time: 0,
+            create_date: FAT_DATE,
+            access_date: FAT_DATE,
+            cluster_high: 0,
+            write_time: 0,
+            write_date: FAT_DATE,
+            cluster_low: 0,
+            size: 0,
+        };
+        entry.set_first_cluster(first_cluster);
+        entry
+    }
+    fn first_cluster(&self) -> u32 {
+        (self.cluster_high as u32) << 16 | self.cluster_low as u32
+    }
+    fn set_first_cluster(&mut self, cluster: u32) {
+        self.cluster_high = (cluster >> 16) as u16;
+        self.cluster_low = cluster as u16;
+    }
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+    /// The `.` or `..` entry of a directory
+    fn is_dot(&self) -> bool {
+        self.name[0] == b'.'
+    }
+    /// Name as shown when there is no long name
+    fn display_name(&self) -> String {
+        let mut name = self.name;
+        if name[0] == ENTRY_KANJI_E5 {
+            name[0] = ENTRY_DELETED;
+        }
+        let base = short_name_part(&name[..8], self.nt_reserved & NT_LOWER_BASE != 0);
+        let ext = short_name_part(&name[8..], self.nt_reserved & NT_LOWER_EXT != 0);
+        match ext.is_empty() {
+            true => base,
+            false => format!("{}.{}", base, ext),
+        }
+    }
+}
+
+impl DiskFormat for ShortEntry {
+    const DISK_SZ: usize = DIR_ENTRY_SZ;
+    fn decode(bytes: &[u8]) -> Self {
+        let mut d = Decoder::new(bytes);
+        Self {
+            name: d.get(),
+            attr: d.get(),
+            nt_reserved: d.get(),
+            create_time_tenth: d.get(),
+            create_time: d.get(),
+            create_date: d.get(),
+            access_date: d.get(),
+            cluster_high: d.get(),
+            write_time: d.get(),
+            write_date: d.get(),
+            cluster_low: d.get(),
+            size: d.get(),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let mut e = Encoder::new(bytes);
+        e.put(&self.name);
+        e.put(&self.attr);
+        e.put(&self.nt_reserved);
+        e.put(&self.create_time_tenth);
+        e.put(&self.create_time);
+        e.put(&self.create_date);
+        e.put(&self.access_date);
+        e.put(&self.cluster_high);
+        e.put(&self.write_time);
+        e.put(&self.write_date);
+        e.put(&self.cluster_low);
+        e.put(&self.size);
+    }
+}
+
+/// A directory entry holding 13 UTF-16 code units of a long name
+struct LongEntry {
+    order: u8,
+    name1: [u16; 5],
+    attr: u8,
+    type_: u8,
+    checksum: u8,
+    name2: [u16; 6],
+    cluster: u16,
+    name3: [u16; 2],
+}
+
+impl LongEntry {
+    fn units(&self) -> impl Iterator<Item = u16> + '_ {
+        self.name1
+            .iter()
+            .chain(self.name2.iter())
+            .chain(self.name3.iter())
+            .copied()
+    }
+}
+
+impl DiskFormat for LongEntry {
+    const DISK_SZ: usize = DIR_ENTRY_SZ;
+    fn decode(bytes: &[u8]) -> Self {
+        let mut d = Decoder::new(bytes);
+        Self {
+            order: d.get(),
+            name1: d.get(),
+            attr: d.get(),
+            type_: d.get(),
+            checksum: d.get(),
+            name2: d.get(),
+            cluster: d.get(),
+            name3: d.get(),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let mut e = Encoder::new(bytes);
+        e.put(&self.order);
+        e.put(&self.name1);
+        e.put(&self.attr);
+        e.put(&self.type_);
+        e.put(&self.checksum);
+        e.put(&self.name2);
+        e.put(&self.cluster);
+        e.put(&self.name3);
+    }
+}
+
+/// A long name being assembled from its entries, which come in descending order
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    /// 下一个应出现的序号，为 0 时已经收齐
+    next_order: u8,
+    first_slot: usize,
+}
+
+impl LongName {
+    fn start(entry: &LongEntry, slot: usize) -> Option<Self> {
+        let count = (entry.order & !LFN_LAST) as usize;
+        if entry.order & LFN_LAST == 0
+            || count == 0
+            || count * LFN_CHARS > LONG_NAME_LIMIT + LFN_CHARS
+        {
+            return None;
+        }
+        Self {
+            units: vec![0xFFFF; count * LFN_CHARS],
+            checksum: entry.checksum,
+            next_order: count as u8,
+            first_slot: slot,
+        }
+        .push(entry)
+    }
+    fn push(mut self, entry: &LongEntry) -> Option<Self> {
+        if entry.order & !LFN_LAST != self.next_order || entry.checksum != self.checksum {
+            return None;
+        }
+        let start = (self.next_order as usize - 1) * LFN_CHARS;
+        for (unit, value) in self.units[start..].iter_mut().zip(entry.units()) {
+            *unit = value;
+        }
+        self.next_order -= 1;
+        Some(self)
+    }
+    /// The name if every entry is there and they belong to `short`
+    fn finish(self, short: &ShortEntry) -> Option<String> {
+        if self.next_order != 0 || self.checksum != short_name_checksum(&short.name) {
+            return None;
+        }
+        let len = self
+            .units
+            .iter()
+            .position(|unit| *unit == 0 || *unit == 0xFFFF)
+            .unwrap_or(self.units.len());
+        Some(
+            core::char::decode_utf16(self.units[..len].iter().copied())
+                .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+}
+
+/// Checksum of a short name stored in its long name entries
+fn short_name_checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte))
+}
+
+/// The long name entries of `name` in the order they are stored
+fn long_name_entries(name: &str, checksum: u8) -> Vec<LongEntry> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LFN_CHARS);
+    // 名字正好填满时没有结尾的 0，其余位置用 0xFFFF 填充
+    if units.len() < count * LFN_CHARS {
+        units.push(0);
+    }
+    units.resize(count * LFN_CHARS, 0xFFFF);
+    (0..count)
+        .rev()
+        .map(|i| {
+            let part = &units[i * LFN_CHARS..(i + 1) * LFN_CHARS];
+            LongEntry {
+                order: (i + 1) as u8 | if i + 1 == count { LFN_LAST } else { 0 },
+                name1: core::array::from_fn(|j| part[j]),
+                attr: ATTR_LONG_NAME,
+                type_: 0,
+                checksum,
+                name2: core::array::from_fn(|j| part[5 + j]),
+                cluster: 0,
+                name3: core::array::from_fn(|j| part[11 + j]),
+            }
+        })
+        .collect()
+}
+
+fn short_name_part(bytes: &[u8], lower: bool) -> String {
+    let len = bytes
+        .iter()
+        .rposition(|byte| *byte != b' ')
+        .map_or(0, |pos| pos + 1);
+    bytes[..len]
+        .iter()
+        .map(|byte| match lower {
+            true => char::from(*byte).to_ascii_lowercase(),
+            false => char::from(*byte),
+        })
+        .collect()
+}
+
+/// A character allowed in short names, upper-cased
+fn short_name_char(c: char) -> Option<u8> {
+    match c.is_ascii_alphanumeric() || (c.is_ascii() && SHORT_NAME_EXTRA.contains(&(c as u8))) {
+        true => Some(c.to_ascii_uppercase() as u8),
+        false => None,
+    }
+}
+
+/// The short name spelling `name` exactly, when it needs no long name,
+/// with the flags of the parts stored in lower case
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || name.ends_with('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut nt_reserved = 0;
+    for (part, start, lower_flag) in [(base, 0, NT_LOWER_BASE), (ext, 8, NT_LOWER_EXT)] {
+        // 每一部分只能全部大写或全部小写
+        if part.chars().any(|c| c.is_ascii_lowercase()) {
+            if part.chars().any(|c| c.is_ascii_uppercase()) {
+                return None;
+            }
+            nt_reserved |= lower_flag;
+        }
+        for (i, c) in part.chars().enumerate() {
+            short[start + i] = short_name_char(c)?;
+        }
+    }
+    Some((short, nt_reserved))
+}
+
+/// Base and extension of the short name generated for a long name, before the numeric tail
+fn short_name_basis(name: &str) -> (Vec<u8>, Vec<u8>) {
+    let name = name.trim_start_matches('.');
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let convert = |part: &str, max: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| short_name_char(c).unwrap_or(b'_'))
+            .take(max)
+            .collect()
+    };
+    let base = match convert(base, 8) {
+        base if base.is_empty() => vec![b'_'],
+        base => base,
+    };
+    (base, convert(ext, 3))
+}
+
+fn valid_long_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with('.')
+        && !name.ends_with(' ')
+        && name.encode_utf16().count() <= LONG_NAME_LIMIT
+        && !name
+            .chars()
+            .any(|c| (c as u32) < 0x20 || LONG_NAME_INVALID.contains(c))
+}
+
+/// A directory entry with its long name assembled
+struct FatDirEntry {
+    name: String,
+    short: ShortEntry,
+    /// 第一个长文件名目录项的序号，没有长文件名时就是短目录项的序号
+    first_slot: usize,
+    slot: usize,
+    /// 短目录项所在的块和块内偏移
+    pos: (usize, usize),
+}
+
+impl FatDirEntry {
+    fn inode_id(&self) -> u32 {
+        (self.pos.0 * ENTRIES_PER_BLOCK + self.pos.1 / DIR_ENTRY_SZ) as u32
+    }
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.short.display_name().eq_ignore_ascii_case(name)
+    }
+}
+
+/// 空闲簇的计数和下一次分配开始查找的位置，与 FSInfo 扇区同步
+struct ClusterAlloc {
+    free_count: u32,
+    next_free: u32,
+}
+
+/// A FAT32 volume
+pub struct FatFileSystem {
+    block_device: Arc<dyn BlockDevice>,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    fat_size: u32,
+    fat_count: u32,
+    /// 读取时使用的 FAT，关闭镜像时也是唯一写入的 FAT
+    active_fat: u32,
+    mirrored: bool,
+    data_start: u32,
+    cluster_count: u32,
+    root_cluster: u32,
+    fs_info_sector: Option<u32>,
+    alloc: Mutex<ClusterAlloc>,
+    inode_table: Mutex<InodeTable<FatInode>>,
+}
+
+impl FatFileSystem {
+    /// Format a block device as FAT32, it must be large enough for 65525 clusters
+    pub fn format(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        volume_id: u32,
+    ) -> Arc<Self> {
+        assert!(total_blocks < MAX_SECTORS, "Too large for FAT32 here!");
+        // 按 Microsoft 推荐的簇大小
+        let sectors_per_cluster: u32 = match total_blocks {
+            0..=532_480 => 1,
+            532_481..=16_777_216 => 8,
+            16_777_217..=33_554_432 => 16,
+            33_554_433..=67_108_864 => 32,
+            _ => 64,
+        };
+        let per_fat_sector = (256 * sectors_per_cluster + FAT_COUNT as u32) / 2;
+        let fat_size = (total_blocks - RESERVED_SECTORS as u32).div_ceil(per_fat_sector);
+        let data_start = RESERVED_SECTORS as u32 + FAT_COUNT as u32 * fat_size;
+        let cluster_count = total_blocks.saturating_sub(data_start) / sectors_per_cluster;
+        assert!(cluster_count >= MIN_CLUSTERS, "Too small for FAT32!");
+        // 清空保留区、FAT 和根目录的簇
+        for block_id in 0..data_start + sectors_per_cluster {
+            get_block_cache(block_id as usize, Arc::clone(&block_device))
+                .lock()
+                .set(0, &[0u8; BLOCK_SZ]);
+        }
+        let boot_sector = BootSector {
+            jump: [0xEB, 0x58, 0x90],
+            oem_name: *b"EASYFS  ",
+            bytes_per_sector: BLOCK_SZ as u16,
+            sectors_per_cluster: sectors_per_cluster as u8,
+            reserved_sectors: RESERVED_SECTORS,
+            fat_count: FAT_COUNT,
+            root_entries: 0,
+            total_sectors_16: 0,
+            media: MEDIA_FIXED_DISK,
+            fat_size_16: 0,
+            sectors_per_track: 32,
+            heads: 64,
+            hidden_sectors: 0,
+            total_sectors_32: total_blocks,
+            fat_size_32: fat_size,
+            ext_flags: 0,
+            fs_version: 0,
+            root_cluster: 2,
+            fs_info_sector: FSINFO_SECTOR,
+            backup_boot_sector: BACKUP_BOOT_SECTOR,
+            reserved: [0; 12],
+            drive_number: 0x80,
+            reserved1: 0,
+            boot_signature: 0x29,
+            volume_id,
+            volume_label: *b"NO NAME    ",
+            fs_type: *b"FAT32   ",
+            signature: BOOT_SIGNATURE,
+        };
+        // 根目录占用第一个簇
+        let fs_info = FsInfo::new(cluster_count - 1, 3);
+        for offset in [0, BACKUP_BOOT_SECTOR] {
+            get_block_cache(offset as usize, Arc::clone(&block_device))
+                .lock()
+                .set(0, &boot_sector);
+            get_block_cache((offset + FSINFO_SECTOR) as usize, Arc::clone(&block_device))
+                .lock()
+                .set(0, &fs_info);
+        }
+        for fat in 0..FAT_COUNT as u32 {
+            let fat_start = RESERVED_SECTORS as u32 + fat * fat_size;
+            get_block_cache(fat_start as usize, Arc::clone(&block_device))
+                .lock()
+                .set(
+                    0,
+                    &[0x0FFF_FF00 | MEDIA_FIXED_DISK as u32, FAT_EOC, FAT_EOC],
+                );
+        }
+        block_cache_sync_all();
+        Self::open(block_device).unwrap()
+    }
+    /// Open a FAT32 volume, `None` if the device holds none this implementation supports
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Option<Arc<Self>> {
+        let boot_sector: BootSector = get_block_cache(0, Arc::clone(&block_device)).lock().get(0);
+        if !boot_sector.is_valid() || boot_sector.total_sectors() >= MAX_SECTORS {
+            return None;
+        }
+        let sectors_per_cluster = boot_sector.sectors_per_cluster as u32;
+        let data_start = boot_sector.reserved_sectors as u32
+            + boot_sector.fat_count as u32 * boot_sector.fat_size_32;
+        let cluster_count =
+            boot_sector.total_sectors().checked_sub(data_start)? / sectors_per_cluster;
+        // FAT 的容量也限制了簇数
+        let cluster_count = cluster_count.min(boot_sector.fat_size_32 * (BLOCK_SZ as u32 / 4) - 2);
+        if boot_sector.root_cluster >= cluster_count + 2 {
+            return None;
+        }
+        let mirrored = boot_sector.ext_flags & 0x80 == 0;
+        let active_fat = match mirrored {
+            true => 0,
+            false => (boot_sector.ext_flags & 0x0F) as u32,
+        };
+        if active_fat >= boot_sector.fat_count as u32 {
+            return None;
+        }
+        let fs_info_sector = match boot_sector.fs_info_sector {
+            0 => None,
+            sector if sector < boot_sector.reserved_sectors => Some(sector as u32),
+            _ => None,
+        };
+        let fs = Self {
+            block_device,
+            sectors_per_cluster,
+            reserved_sectors: boot_sector.reserved_sectors as u32,
+            fat_size: boot_sector.fat_size_32,
+            fat_count: boot_sector.fat_count as u32,
+            active_fat,
+            mirrored,
+            data_start,
+            cluster_count,
+            root_cluster: boot_sector.root_cluster,
+            fs_info_sector,
+            alloc: Mutex::new(ClusterAlloc {
+                free_count: 0,
+                next_free: 2,
+            }),
+            inode_table: Mutex::new(InodeTable::new()),
+        };
+        // 不信任 FSInfo 中的提示，重新统计空闲簇
+        let free_count = fs.count_free_clusters();
+        let fs_info = fs.fs_info_sector.map(|sector| -> FsInfo {
+            get_block_cache(sector as usize, Arc::clone(&fs.block_device))
+                .lock()
+                .get(0)
+        });
+        let fs_info_sector = match fs_info {
+            Some(fs_info) if fs_info.is_valid() => fs.fs_info_sector,
+            _ => None,
+        };
+        let fs = Self {
+            fs_info_sector,
+            alloc: Mutex::new(ClusterAlloc {
+                free_count,
+                next_free: 2,
+            }),
+            ..fs
+        };
+        Some(Arc::new(fs))
+    }
+    fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster as usize * BLOCK_SZ
+    }
+    /// First block of a cluster in the data area
+    fn cluster_block(&self, cluster: u32) -> usize {
+        (self.data_start + (cluster - 2) * self.sectors_per_cluster) as usize
+    }
+    /// Whether `cluster` is a data cluster of the volume
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+    /// Block and offset of the entry of `cluster` in a FAT
+    fn fat_entry_pos(&self, fat: u32, cluster: u32) -> (usize, usize) {
+        let offset = cluster as usize * 4;
+        (
+            (self.reserved_sectors + fat * self.fat_size) as usize + offset / BLOCK_SZ,
+            offset % BLOCK_SZ,
+        )
+    }
+    fn fat_entry(&self, cluster: u32) -> u32 {
+        let (block_id, offset) = self.fat_entry_pos(self.active_fat, cluster);
+        get_block_cache(block_id, Arc::clone(&self.block_device))
+            .lock()
+            .get::<u32>(offset)
+            & FAT_ENTRY_MASK
+    }
+    fn set_fat_entry(&self, cluster: u32, value: u32) {
+        for fat in 0..self.fat_count {
+            if !self.mirrored && fat != self.active_fat {
+                continue;
+            }
+            let (block_id, offset) = self.fat_entry_pos(fat, cluster);
+            get_block_cache(block_id, Arc::clone(&self.block_device))
+                .lock()
+                .modify(offset, |entry: &mut u32| {
+                    // 高 4 位保留，写入时保持不变
+                    *entry = (*entry & !FAT_ENTRY_MASK) | value;
+                });
+        }
+    }
+    fn count_free_clusters(&self) -> u32 {
+        let entries_per_block = (BLOCK_SZ / 4) as u32;
+        let mut free_count = 0;
+        let end = self.cluster_count + 2;
+        for first in (0..end).step_by(entries_per_block as usize) {
+            let (block_id, _) = self.fat_entry_pos(self.active_fat, first);
+            let entries: [u32; BLOCK_SZ / 4] =
+                get_block_cache(block_id, Arc::clone(&self.block_device))
+                    .lock()
+                    .get(0);
+            free_count += (first.max(2)..end.min(first + entries_per_block))
+                .filter(|cluster| entries[(cluster - first) as usize] & FAT_ENTRY_MASK == 0)
+                .count() as u32;
+        }
+        free_count
+    }
+    /// Follow the cluster chain starting at `first`, stopping at a corrupted link
+    fn cluster_chain(&self, first: u32) -> Vec<u32> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while self.is_data_cluster(cluster) && chain.len() < self.cluster_count as usize {
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster);
+        }
+        chain
+    }
+    /// Write the free cluster count into the FSInfo sector
+    fn store_fs_info(&self, alloc: &ClusterAlloc) {
+        if let Some(sector) = self.fs_info_sector {
+            get_block_cache(sector as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |fs_info: &mut FsInfo| {
+                    fs_info.free_count = alloc.free_count;
+                    fs_info.next_free = alloc.next_free;
+                });
+        }
+    }
+    /// Append `count` zeroed clusters to `chain`, return false if there are not enough free ones
+    fn extend_chain(&self, chain: &mut Vec<u32>, count: usize) -> bool {
+        let mut alloc = self.alloc.lock();
+        if (alloc.free_count as usize) < count {
+            return false;
+        }
+        // 先找齐空闲簇再修改 FAT，找不够时什么也不改
+        let start = alloc.next_free.max(2);
+        let clusters: Vec<u32> = (0..self.cluster_count)
+            .map(|i| 2 + (start - 2 + i) % self.cluster_count)
+            .filter(|cluster| self.fat_entry(*cluster) == 0)
+            .take(count)
+            .collect();
+        if clusters.len() < count {
+            // 空闲簇计数与 FAT 不一致，按扫描到的数目更正
+            alloc.free_count = clusters.len() as u32;
+            self.store_fs_info(&alloc);
+            return false;
+        }
+        for cluster in clusters {
+            self.set_fat_entry(cluster, FAT_EOC);
+            if let Some(last) = chain.last() {
+                self.set_fat_entry(*last, cluster);
+            }
+            for i in 0..self.sectors_per_cluster as usize {
+                get_block_cache(
+                    self.cluster_block(cluster) + i,
+                    Arc::clone(&self.block_device),
+                )
+                .lock()
+                .set(0, &[0u8; BLOCK_SZ]);
+            }
+            chain.push(cluster);
+            alloc.free_count -= 1;
+            alloc.next_free = cluster + 1;
+        }
+        self.store_fs_info(&alloc);
+        true
+    }
+    /// Free the cluster chain starting at `first`
+    fn free_chain(&self, first: u32) {
+        let chain = self.cluster_chain(first);
+        let mut alloc = self.alloc.lock();
+        for cluster in chain.iter() {
+            self.set_fat_entry(*cluster, 0);
+        }
+        alloc.free_count += chain.len() as u32;
+        self.store_fs_info(&alloc);
+    }
+    /// Call `f` with each block, the range within it and the range within the data
+    /// for the bytes `range` of the data in `chain`
+    fn for_each_block(
+        &self,
+        chain: &[u32],
+        range: Range<usize>,
+        mut f: impl FnMut(usize, Range<usize>, Range<usize>),
+    ) {
+        // 损坏的卷上簇链可能比记录的大小短，超出簇链的部分不处理
+        let end = range.end.min(chain.len() * self.cluster_bytes());
+        let mut pos = range.start;
+        while pos < end {
+            let within = pos % self.cluster_bytes();
+            let block_id =
+                self.cluster_block(chain[pos / self.cluster_bytes()]) + within / BLOCK_SZ;
+            let block_offset = within % BLOCK_SZ;
+            let len = (BLOCK_SZ - block_offset).min(end - pos);
+            f(
+                block_id,
+                block_offset..block_offset + len,
+                pos - range.start..pos - range.start + len,
+            );
+            pos += len;
+        }
+    }
+    /// Block and offset of the directory entry `slot` of a directory, `None` past its clusters
+    fn slot_pos(&self, chain: &[u32], slot: usize) -> Option<(usize, usize)> {
+        let offset = slot * DIR_ENTRY_SZ;
+        let cluster = chain.get(offset / self.cluster_bytes())?;
+        let within = offset % self.cluster_bytes();
+        Some((
+            self.cluster_block(*cluster) + within / BLOCK_SZ,
+            within % BLOCK_SZ,
+        ))
+    }
+    fn get_inode(self: &Arc<Self>, inode_id: u32) -> Arc<FatInode> {
+        self.inode_table.lock().get_or_insert(inode_id, || {
+            Arc::new(FatInode {
+                inode_id,
+                fs: Arc::clone(self),
+                lock: RwLock::new(()),
+                deleted: AtomicBool::new(false),
+            })
+        })
+    }
+    /// Check the consistency of the volume, return the problems found
+    pub fn check(self: &Arc<Self>) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut owners: BTreeMap<u32, String> = BTreeMap::new();
+        let mut dirs = vec![(String::from("/"), self.get_inode(ROOT_INODE_ID))];
+        while let Some((path, dir)) = dirs.pop() {
+            let chain = dir.chain();
+            for cluster in chain.iter() {
+                if let Some(other) = owners.insert(*cluster, path.clone()) {
+                    problems.push(format!(
+                        "cluster {} is shared by {} and {}",
+                        cluster, other, path
+                    ));
+                }
+            }
+            let mut slot = 0;
+            while let Some(entry) = dir.next_entry(&chain, slot) {
+                slot = entry.slot + 1;
+                if entry.short.is_dot() {
+                    continue;
+                }
+                let entry_path = format!("{}{}", path, entry.name);
+                if entry.short.is_dir() {
+                    dirs.push((format!("{}/", entry_path), self.get_inode(entry.inode_id())));
+                    continue;
+                }
+                let chain = self.cluster_chain(entry.short.first_cluster());
+                if chain.len() != (entry.short.size as usize).div_ceil(self.cluster_bytes()) {
+                    problems.push(format!(
+                        "{}: {} clusters for {} bytes",
+                        entry_path,
+                        chain.len(),
+                        entry.short.size
+                    ));
+                }
+                for cluster in chain {
+                    if let Some(other) = owners.insert(cluster, entry_path.clone()) {
+                        problems.push(format!(
+                            "cluster {} is shared by {} and {}",
+                            cluster, other, entry_path
+                        ));
+                    }
+                }
+            }
+        }
+        let mut free_count = 0;
+        for cluster in 2..self.cluster_count + 2 {
+            match (self.fat_entry(cluster), owners.contains_key(&cluster)) {
+                (0, true) => problems.push(format!("cluster {} is used but free", cluster)),
+                (0, false) => free_count += 1,
+                (_, false) => problems.push(format!("cluster {} is allocated but unused", cluster)),
+                _ => {}
+            }
+        }
+        let alloc = self.alloc.lock();
+        if alloc.free_count != free_count {
+            problems.push(format!(
+                "{} free clusters counted but {} in the FAT",
+                alloc.free_count, free_count
+            ));
+        }
+        problems
+    }
+}
+
+impl SuperBlockOps for FatFileSystem {
+    fn fs_type(&self) -> &'static str {
+        "fat32"
+    }
+    fn block_size(&self) -> usize {
+        self.cluster_bytes()
+    }
+    fn root_inode(self: Arc<Self>) -> Arc<dyn VfsInode> {
+        self.get_inode(ROOT_INODE_ID)
+    }
+    /// FAT 没有 inode 表，文件数都为 0，数据块以簇为单位
+    fn stat(&self) -> VfsStat {
+        VfsStat {
+            files: 0,
+            free_files: 0,
+            blocks: self.cluster_count.into(),
+            free_blocks: self.alloc.lock().free_count.into(),
+        }
+    }
+    fn sync(&self) {
+        block_cache_sync_all();
+    }
+}
+
+/// A file or directory of a FAT32 volume
+pub struct FatInode {
+    inode_id: u32,
+    fs: Arc<FatFileSystem>,
+    /// 保护目录项中的大小和首簇，以及目录的内容
+    lock: RwLock<()>,
+    /// 目录项已被删除，槽位可能已属于别的文件
+    deleted: AtomicBool,
+}
+
+impl FatInode {
+    /// Block and offset of the short entry, the root directory has none
+    fn entry_pos(&self) -> Option<(usize, usize)> {
+        match self.inode_id {
+            ROOT_INODE_ID => None,
+            inode_id => Some((
+                inode_id as usize / ENTRIES_PER_BLOCK,
+                inode_id as usize % ENTRIES_PER_BLOCK * DIR_ENTRY_SZ,
+            )),
+        }
+    }
+    fn load_entry(&self) -> Option<ShortEntry> {
+        let (block_id, offset) = self.entry_pos()?;
+        Some(
+            get_block_cache(block_id, Arc::clone(&self.fs.block_device))
+                .lock()
+                .get(offset),
+        )
+    }
+    fn store_entry(&self, entry: &ShortEntry) -> Option<()> {
+        let (block_id, offset) = self.entry_pos()?;
+        get_block_cache(block_id, Arc::clone(&self.fs.block_device))
+            .lock()
+            .set(offset, entry);
+        Some(())
+    }
+    fn is_deleted(&self) -> bool {
+        self.deleted.load(Ordering::Acquire)
+    }
+    fn is_dir_locked(&self) -> bool {
+        !self.is_deleted() && self.load_entry().is_none_or(|entry| entry.is_dir())
+    }
+    fn chain(&self) -> Vec<u32> {
+        let first = match self.load_entry() {
+            Some(entry) => entry.first_cluster(),
+            None => self.fs.root_cluster,
+        };
+        self.fs.cluster_chain(first)
+    }
+    fn read_slot(&self, chain: &[u32], slot: usize) -> Option<[u8; DIR_ENTRY_SZ]> {
+        let (block_id, offset) = self.fs.slot_pos(chain, slot)?;
+        Some(
+            get_block_cache(block_id, Arc::clone(&self.fs.block_device))
+                .lock()
+                .get(offset),
+        )
+    }
+    fn write_slot<T: DiskFormat>(&self, chain: &[u32], slot: usize, entry: &T) -> Option<()> {
+        let (block_id, offset) = self.fs.slot_pos(chain, slot)?;
+        get_block_cache(block_id, Arc::clone(&self.fs.block_device))
+            .lock()
+            .set(offset, entry);
+        Some(())
+    }
+    /// The first entry at or after `slot` which is in use, `.` and `..` included
+    fn next_entry(&self, chain: &[u32], mut slot: usize) -> Option<FatDirEntry> {
+        let mut long_name: Option<LongName> = None;
+        loop {
+            let bytes = self.read_slot(chain, slot)?;
+            let attr = bytes[11];
+            match bytes[0] {
+                ENTRY_END => return None,
+                ENTRY_DELETED => long_name = None,
+                _ if attr & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME => {
+                    let entry = LongEntry::decode(&bytes);
+                    long_name = match long_name.take() {
+                        _ if entry.order & LFN_LAST != 0 => LongName::start(&entry, slot),
+                        Some(long_name) => long_name.push(&entry),
+                        None => None,
+                    };
+                }
+                _ if attr & ATTR_VOLUME_ID != 0 => long_name = None,
+                _ => {
+                    let short = ShortEntry::decode(&bytes);
+                    let first_slot = long_name.as_ref().map_or(slot, |name| name.first_slot);
+                    let (name, first_slot) = match long_name.and_then(|name| name.finish(&short)) {
+                        Some(name) => (name, first_slot),
+                        None => (short.display_name(), slot),
+                    };
+                    return Some(FatDirEntry {
+                        name,
+                        short,
+                        first_slot,
+                        slot,
+                        pos: self.fs.slot_pos(chain, slot)?,
+                    });
+                }
+            }
+            slot += 1;
+        }
+    }
+    fn find_entry(&self, chain: &[u32], name: &str) -> Option<FatDirEntry> {
+        let mut slot = 0;
+        while let Some(entry) = self.next_entry(chain, slot) {
+            if !entry.short.is_dot() && entry.matches(name) {
+                return Some(entry);
+            }
+            slot = entry.slot + 1;
+        }
+        None
+    }
+    /// Find `count` consecutive unused slots, growing the directory if needed
+    fn find_free_slots(&self, chain: &mut Vec<u32>, count: usize) -> Option<usize> {
+        let mut start = 0;
+        for slot in 0..MAX_DIR_ENTRIES {
+            if slot - start == count {
+                return Some(start);
+            }
+            let first_byte = match self.read_slot(chain, slot) {
+                Some(bytes) => bytes[0],
+                None => ENTRY_END,
+            };
+            match first_byte {
+                ENTRY_DELETED => {}
+                // 之后的目录项都未使用，不够时扩展目录
+                ENTRY_END => {
+                    let end = (start + count) * DIR_ENTRY_SZ;
+                    if start + count > MAX_DIR_ENTRIES {
+                        return None;
+                    }
+                    let needed = end.div_ceil(self.fs.cluster_bytes());
+                    let missing = needed.saturating_sub(chain.len());
+                    if missing > 0 && !self.fs.extend_chain(chain, missing) {
+                        return None;
+                    }
+                    return Some(start);
+                }
+                _ => start = slot + 1,
+            }
+        }
+        None
+    }
+    /// Short names already used in a directory
+    fn short_names(&self, chain: &[u32]) -> BTreeSet<[u8; 11]> {
+        let mut names = BTreeSet::new();
+        let mut slot = 0;
+        while let Some(entry) = self.next_entry(chain, slot) {
+            names.insert(entry.short.name);
+            slot = entry.slot + 1;
+        }
+        names
+    }
+    /// Short name for a long name, with the lowest numeric tail not used yet
+    fn unique_short_name(&self, chain: &[u32], name: &str) -> Option<[u8; 11]> {
+        let (base, ext) = short_name_basis(name);
+        let used = self.short_names(chain);
+        (1..1_000_000).find_map(|n| {
+            let tail = format!("~{}", n);
+            let keep = base.len().min(8 - tail.len());
+            let mut short = [b' '; 11];
+            short[..keep].copy_from_slice(&base[..keep]);
+            short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+            short[8..8 + ext.len()].copy_from_slice(&ext);
+            Some(short).filter(|short| !used.contains(short))
+        })
+    }
+    fn create_entry(&self, name: &str, is_dir: bool) -> Option<Arc<dyn VfsInode>> {
+        if !valid_long_name(name) {
+            return None;
+        }
+        // 持有目录的写锁直到目录项写入，避免同名文件被并发创建两次
+        let _guard = self.lock.write();
+        if !self.is_dir_locked() {
+            return None;
+        }
+        let mut chain = self.chain();
+        if self.find_entry(&chain, name).is_some() {
+            return None;
+        }
+        let (short_name, nt_reserved, long_entries) = match exact_short_name(name) {
+            Some((short_name, nt_reserved)) => (short_name, nt_reserved, Vec::new()),
+            None => {
+                let short_name = self.unique_short_name(&chain, name)?;
+                let checksum = short_name_checksum(&short_name);
+                (short_name, 0, long_name_entries(name, checksum))
+            }
+        };
+        let first_slot = self.find_free_slots(&mut chain, long_entries.len() + 1)?;
+        let mut entry = ShortEntry::new(short_name, ATTR_ARCHIVE, 0);
+        if is_dir {
+            let mut dir_chain = Vec::new();
+            if !self.fs.extend_chain(&mut dir_chain, 1) {
+                return None;
+            }
+            entry = ShortEntry::new(short_name, ATTR_DIRECTORY, dir_chain[0]);
+            // 指向根目录的 .. 记为簇 0
+            let parent_cluster = match self.inode_id {
+                ROOT_INODE_ID => 0,
+                _ => chain[0],
+            };
+            let dot = ShortEntry::new(*b".          ", ATTR_DIRECTORY, dir_chain[0]);
+            self.write_slot(&dir_chain, 0, &dot)?;
+            let dot_dot = ShortEntry::new(*b"..         ", ATTR_DIRECTORY, parent_cluster);
+            self.write_slot(&dir_chain, 1, &dot_dot)?;
+        }
+        entry.nt_reserved = nt_reserved;
+        for (i, long_entry) in long_entries.iter().enumerate() {
+            self.write_slot(&chain, first_slot + i, long_entry)?;
+        }
+        let slot = first_slot + long_entries.len();
+        self.write_slot(&chain, slot, &entry)?;
+        block_cache_sync_all();
+        let (block_id, offset) = self.fs.slot_pos(&chain, slot)?;
+        Some(
+            self.fs
+                .get_inode((block_id * ENTRIES_PER_BLOCK + offset / DIR_ENTRY_SZ) as u32),
+        )
+    }
+    /// Whether a directory has no entries other than `.` and `..`
+    fn is_empty_dir(&self) -> bool {
+        let chain = self.chain();
+        let mut slot = 0;
+        while let Some(entry) = self.next_entry(&chain, slot) {
+            if !entry.short.is_dot() {
+                return false;
+            }
+            slot = entry.slot + 1;
+        }
+        true
+    }
+    /// Size of the data, a directory spans its clusters
+    fn size_locked(&self) -> usize {
+        if self.is_deleted() {
+            return 0;
+        }
+        match self.load_entry() {
+            Some(entry) if !entry.is_dir() => entry.size as usize,
+            _ => self.chain().len() * self.fs.cluster_bytes(),
+        }
+    }
+}
+
+impl InodeOps for FatInode {
+    fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+    fn is_dir(&self) -> bool {
+        let _guard = self.lock.read();
+        self.is_dir_locked()
+    }
+    fn size(&self) -> usize {
+        let _guard = self.lock.read();
+        self.size_locked()
+    }
+}
+
+impl DirOps for FatInode {
+    fn name_length_limit(&self) -> usize {
+        LONG_NAME_LIMIT
+    }
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        let _guard = self.lock.read();
+        if !self.is_dir_locked() {
+            return None;
+        }
+        let entry = self.find_entry(&self.chain(), name)?;
+        Some(self.fs.get_inode(entry.inode_id()))
+    }
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.create_entry(name, false)
+    }
+    fn create_dir(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.create_entry(name, true)
+    }
+    fn unlink(&self, name: &str) -> bool {
+        let _guard = self.lock.write();
+        if !self.is_dir_locked() {
+            return false;
+        }
+        let chain = self.chain();
+        let entry = match self.find_entry(&chain, name) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let inode = self.fs.get_inode(entry.inode_id());
+        let _inode_guard = inode.lock.write();
+        if entry.short.is_dir() && !inode.is_empty_dir() {
+            return false;
+        }
+        // 先让目录项落盘为已删除再释放簇链，崩溃时最多泄漏簇，不会有目录项指向空闲簇
+        let mut short = entry.short;
+        short.name[0] = ENTRY_DELETED;
+        short.set_first_cluster(0);
+        short.size = 0;
+        if inode.store_entry(&short).is_none() {
+            return false;
+        }
+        for (block_id, offset) in
+            (entry.first_slot..entry.slot).filter_map(|slot| self.fs.slot_pos(&chain, slot))
+        {
+            get_block_cache(block_id, Arc::clone(&self.fs.block_device))
+                .lock()
+                .set(offset, &ENTRY_DELETED);
+        }
+        block_cache_sync_all();
+        self.fs.free_chain(entry.short.first_cluster());
+        block_cache_sync_all();
+        // 槽位复用时要得到新的句柄，旧句柄作废
+        inode.deleted.store(true, Ordering::Release);
+        self.fs.inode_table.lock().remove(inode.inode_id);
+        true
+    }
+    fn dir_entry(&self, cookie: usize) -> Option<DirEntryInfo> {
+        let _guard = self.lock.read();
+        if !self.is_dir_locked() {
+            return None;
+        }
+        let chain = self.chain();
+        let mut slot = cookie;
+        loop {
+            let entry = self.next_entry(&chain, slot)?;
+            slot = entry.slot + 1;
+            if entry.short.is_dot() {
+                continue;
+            }
+            return Some(DirEntryInfo {
+                inode_number: entry.inode_id(),
+                type_: match entry.short.is_dir() {
+                    true => FileType::Directory,
+                    false => FileType::File,
+                },
+                name: entry.name,
+                next_cookie: slot,
+            });
+        }
+    }
+    fn read_dir(&self, cookie: usize) -> DirIter<'_> {
+        DirIter::new(self, cookie)
+    }
+    fn open_entry(&self, entry: &DirEntryInfo) -> Option<Arc<dyn VfsInode>> {
+        let _guard = self.lock.read();
+        let inode = self.fs.get_inode(entry.inode_number);
+        match inode.load_entry()?.name[0] {
+            ENTRY_DELETED | ENTRY_END => None,
+            _ => Some(inode),
+        }
+    }
+}
+
+impl FileOps for FatInode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let _guard = self.lock.read();
+        let chain = self.chain();
+        let end = (offset + buf.len())
+            .min(self.size_locked())
+            .min(chain.len() * self.fs.cluster_bytes());
+        if offset >= end {
+            return 0;
+        }
+        self.fs
+            .for_each_block(&chain, offset..end, |block_id, range, buf_range| {
+                get_block_cache(block_id, Arc::clone(&self.fs.block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        buf[buf_range].copy_from_slice(&data_block[range]);
+                    });
+            });
+        end - offset
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let _guard = self.lock.write();
+        let mut entry = match self.load_entry() {
+            Some(entry) if !entry.is_dir() && !self.is_deleted() => entry,
+            _ => return 0,
+        };
+        let end = offset + buf.len();
+        if buf.is_empty() || end > u32::MAX as usize {
+            return 0;
+        }
+        let mut chain = self.fs.cluster_chain(entry.first_cluster());
+        let needed = end.div_ceil(self.fs.cluster_bytes());
+        let missing = needed.saturating_sub(chain.len());
+        if missing > 0 && !self.fs.extend_chain(&mut chain, missing) {
+            return 0;
+        }
+        // 末尾簇中旧的文件结尾之后可能有残留数据，写入空洞前先清零
+        let size = entry.size as usize;
+        if offset > size {
+            self.fs
+                .for_each_block(&chain, size..offset, |block_id, range, _| {
+                    get_block_cache(block_id, Arc::clone(&self.fs.block_device))
+                        .lock()
+                        .modify(0, |data_block: &mut DataBlock| data_block[range].fill(0));
+                });
+        }
+        self.fs
+            .for_each_block(&chain, offset..end, |block_id, range, buf_range| {
+                get_block_cache(block_id, Arc::clone(&self.fs.block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| {
+                        data_block[range].copy_from_slice(&buf[buf_range]);
+                    });
+            });
+        entry.set_first_cluster(chain[0]);
+        entry.size = entry.size.max(end as u32);
+        if self.store_entry(&entry).is_none() {
+            return 0;
+        }
+        block_cache_sync_all();
+        buf.len()
+    }
+    fn clear(&self) {
+        let _guard = self.lock.write();
+        let mut entry = match self.load_entry() {
+            Some(entry) if !entry.is_dir() && !self.is_deleted() => entry,
+            _ => return,
+        };
+        self.fs.free_chain(entry.first_cluster());
+        entry.set_first_cluster(0);
+        entry.size = 0;
+        self.store_entry(&entry);
+        block_cache_sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RamBlockDevice;
+
+    /// 最小的 FAT32 卷约 33 MiB
+    const TOTAL_BLOCKS: u32 = 70_000;
+
+    fn new_fat() -> (Arc<RamBlockDevice>, Arc<FatFileSystem>) {
+        let block_device = Arc::new(RamBlockDevice::new(TOTAL_BLOCKS as usize));
+        let fat = FatFileSystem::format(block_device.clone(), TOTAL_BLOCKS, 0x1234_5678);
+        (block_device, fat)
+    }
+
+    #[test]
+    fn format_and_reopen() {
+        let (block_device, fat) = new_fat();
+        let boot_sector: BootSector = get_block_cache(0, block_device.clone()).lock().get(0);
+        assert!(boot_sector.is_valid());
+        assert_eq!(boot_sector.fs_type, *b"FAT32   ");
+        let stat = fat.stat();
+        assert!(stat.blocks >= u64::from(MIN_CLUSTERS));
+        assert_eq!(stat.free_blocks, stat.blocks - 1);
+        assert!(fat.check().is_empty());
+        let root_inode = Arc::clone(&fat).root_inode();
+        root_inode.create("a.txt").unwrap().write_at(0, b"hello");
+        let fat = FatFileSystem::open(block_device.clone()).unwrap();
+        assert_eq!(fat.stat().free_blocks, stat.free_blocks - 1);
+        let fs_info: FsInfo = get_block_cache(1, block_device).lock().get(0);
+        assert_eq!(u64::from(fs_info.free_count), stat.free_blocks - 1);
+        let mut buf = [0u8; 16];
+        let file = fat.root_inode().find("A.TXT").unwrap();
+        assert_eq!(file.read_at(0, &mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert!(FatFileSystem::open(Arc::new(RamBlockDevice::new(64))).is_none());
+    }
+
+    #[test]
+    fn short_and_long_names() {
+        assert_eq!(exact_short_name("README.TXT"), Some((*b"README  TXT", 0)));
+        assert_eq!(
+            exact_short_name("readme.TXT"),
+            Some((*b"README  TXT", NT_LOWER_BASE))
+        );
+        assert_eq!(exact_short_name("ReadMe.txt"), None);
+        assert_eq!(exact_short_name("LONGERNAME"), None);
+        assert_eq!(
+            short_name_basis("My Document.backup"),
+            (b"MYDOCUME".to_vec(), b"BAC".to_vec())
+        );
+        assert_eq!(
+            short_name_basis(".bashrc"),
+            (b"BASHRC".to_vec(), Vec::new())
+        );
+        let (_, fat) = new_fat();
+        let root_inode = Arc::clone(&fat).root_inode();
+        let names = [
+            "README.TXT",
+            "readme.md",
+            "A long file name.tar.gz",
+            "A long file name 2.gz",
+            "exactly13char",
+            "中文文件名.txt",
+        ];
+        for name in names.iter() {
+            root_inode.create(name).unwrap();
+        }
+        assert!(root_inode.create("readme.MD").is_none());
+        assert!(root_inode.create("bad:name").is_none());
+        let listed: Vec<String> = root_inode.read_dir(0).map(|entry| entry.name).collect();
+        assert_eq!(listed, names);
+        // 生成的短文件名也能找到
+        let chain = fat.get_inode(ROOT_INODE_ID).chain();
+        let short_names = fat.get_inode(ROOT_INODE_ID).short_names(&chain);
+        assert!(short_names.contains(b"README  MD "));
+        assert!(short_names.contains(b"ALONGF~1GZ "));
+        assert!(short_names.contains(b"ALONGF~2GZ "));
+        assert!(root_inode.find("alongf~2.gz").is_some());
+        assert!(root_inode.find("EXACTLY13CHAR").is_some());
+        assert!(fat.check().is_empty());
+    }
+
+    #[test]
+    fn files_and_directories() {
+        let (_, fat) = new_fat();
+        let root_inode = Arc::clone(&fat).root_inode();
+        let free = fat.stat().free_blocks;
+        let dir = root_inode.create_dir("usr").unwrap();
+        assert!(dir.is_dir());
+        let file = dir.create("data.bin").unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(file.write_at(0, &data), data.len());
+        assert_eq!(file.write_at(120_000, b"tail"), 4);
+        assert_eq!(file.size(), 120_004);
+        let mut buf = vec![0u8; 120_004];
+        assert_eq!(file.read_at(0, &mut buf), buf.len());
+        assert!(buf[..100_000] == data[..]);
+        assert!(buf[100_000..120_000].iter().all(|byte| *byte == 0));
+        // 目录扩展到多个簇
+        for i in 0..100 {
+            dir.create(&format!("file number {}", i)).unwrap();
+        }
+        assert_eq!(dir.read_dir(0).count(), 101);
+        assert!(fat.check().is_empty());
+        assert!(!root_inode.unlink("usr"));
+        assert!(dir.unlink("data.bin"));
+        for i in 0..100 {
+            assert!(dir.unlink(&format!("FILE NUMBER {}", i)));
+        }
+        assert!(dir.read_dir(0).next().is_none());
+        // 删除后空出的目录项被重新使用
+        dir.create("again").unwrap();
+        assert!(dir.unlink("again"));
+        assert!(root_inode.unlink("usr"));
+        assert!(fat.check().is_empty());
+        // 目录扩展出的簇在目录删除时一起释放
+        assert_eq!(fat.stat().free_blocks, free);
+    }
+
+    #[test]
+    fn deleted_handles_are_refused() {
+        let (_, fat) = new_fat();
+        let root_inode = Arc::clone(&fat).root_inode();
+        let old = root_inode.create("old.txt").unwrap();
+        assert_eq!(old.write_at(0, b"old"), 3);
+        assert!(root_inode.unlink("old.txt"));
+        // 新文件复用同一个槽位，得到的是新的句柄
+        let new = root_inode.create("new.txt").unwrap();
+        assert_eq!(new.inode_id(), old.inode_id());
+        assert_eq!(new.write_at(0, b"new"), 3);
+        let mut buf = [0u8; 3];
+        assert_eq!(old.size(), 0);
+        assert_eq!(old.read_at(0, &mut buf), 0);
+        assert_eq!(old.write_at(0, b"bad"), 0);
+        old.clear();
+        assert_eq!(new.read_at(0, &mut buf), 3);
+        assert_eq!(&buf, b"new");
+        let entry = root_inode.read_dir(0).next().unwrap();
+        assert!(root_inode.unlink("new.txt"));
+        assert!(root_inode.open_entry(&entry).is_none());
+        assert!(fat.check().is_empty());
+    }
+
+    #[test]
+    fn reads_stop_at_end_of_chain() {
+        let (_, fat) = new_fat();
+        let root_inode = Arc::clone(&fat).root_inode();
+        root_inode.create("short.bin").unwrap().write_at(0, b"data");
+        // 大小超出簇链，像损坏的卷那样
+        let file = fat.get_inode(root_inode.find("short.bin").unwrap().inode_id());
+        let mut entry = file.load_entry().unwrap();
+        entry.size = 10 * fat.cluster_bytes() as u32;
+        file.store_entry(&entry).unwrap();
+        let mut buf = vec![0u8; entry.size as usize];
+        assert_eq!(file.read_at(0, &mut buf), fat.cluster_bytes());
+        assert_eq!(&buf[..4], b"data");
+    }
+
+    #[test]
+    fn free_count_out_of_sync() {
+        let (_, fat) = new_fat();
+        let root_inode = Arc::clone(&fat).root_inode();
+        let file = root_inode.create("big.bin").unwrap();
+        let free = fat.count_free_clusters();
+        // 损坏的 FSInfo 多记了空闲簇，扩展失败时不改 FAT，并更正计数
+        fat.alloc.lock().free_count = free + 10;
+        let data = vec![1u8; (free as usize + 1) * fat.cluster_bytes()];
+        assert_eq!(file.write_at(0, &data), 0);
+        assert_eq!(fat.alloc.lock().free_count, free);
+        assert_eq!(fat.count_free_clusters(), free);
+        assert_eq!(file.size(), 0);
+        assert_eq!(file.write_at(0, b"fits"), 4);
+    }
+}
@@ -0,0 +1,407 @@
+//! 用于崩溃一致性测试的故障注入块设备
+//!
+//! [`FaultyBlockDevice`] 记录每一次写入，可以在第 N 次写入后模拟崩溃、让指定的块读写出错。
+//! 设备还把写入按 [`BlockDevice::flush`] 分成若干段，崩溃时最后一段中尚未刷新的写入
+//! 可能丢失或乱序落盘。[`replay_crashes`] 利用记录重建每个崩溃点的镜像，重新挂载并检查。
+
+use super::{BlockDevice, BLOCK_SZ};
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
+
+/// A write seen by a [`FaultyBlockDevice`]
+#[derive(Clone)]
+pub struct WriteRecord {
+    /// Block written
+    pub block_id: usize,
+    /// Data written
+    pub data: Vec<u8>,
+}
+
+/// What becomes of the unflushed writes before a crash point
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrashPolicy {
+    /// All of them reach the disk in order
+    Keep,
+    /// None of them reaches the disk
+    Drop,
+    /// All of them reach the disk in reverse order
+    Reverse,
+    /// A random subset reaches the disk in random order, decided by the seed
+    Random(u64),
+}
+
+struct FaultState {
+    writes: Vec<WriteRecord>,
+    /// 每次刷新时已有的写入数
+    flushes: Vec<usize>,
+    /// 达到该写入数后设备“崩溃”，之后的写入全部丢弃
+    crash_after: Option<usize>,
+    /// 读写时报错的块
+    error_blocks: BTreeSet<usize>,
+}
+
+impl FaultState {
+    fn crashed(&self) -> bool {
+        self.crash_after.is_some_and(|n| self.writes.len() >= n)
+    }
+}
+
+/// A block device decorator that records every write and injects faults.
+/// 块设备接口无法返回错误，对出错块的读写以 panic 表示 I/O 错误
+pub struct FaultyBlockDevice {
+    inner: Arc<dyn BlockDevice>,
+    state: Mutex<FaultState>,
+}
+
+impl FaultyBlockDevice {
+    /// Wrap a block device
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            inner,
+            state: Mutex::new(FaultState {
+                writes: Vec::new(),
+                flushes: Vec::new(),
+                crash_after: None,
+                error_blocks: BTreeSet::new(),
+            }),
+        }
+    }
+    /// Crash after `writes` writes in total, later writes are silently dropped
+    pub fn crash_after(&self, writes: usize) {
+        self.state.lock().crash_after = Some(writes);
+    }
+    /// Whether the device has crashed
+    pub fn crashed(&self) -> bool {
+        self.state.lock().crashed()
+    }
+    /// Fail every read and write of `block_id`
+    pub fn fail_block(&self, block_id: usize) {
+        self.state.lock().error_blocks.insert(block_id);
+    }
+    /// Stop failing `block_id`
+    pub fn heal_block(&self, block_id: usize) {
+        self.state.lock().error_blocks.remove(&block_id);
+    }
+    /// Get the writes that reached the device so far
+    pub fn writes(&self) -> Vec<WriteRecord> {
+        self.state.lock().writes.clone()
+    }
+    /// Number of writes that reached the device so far
+    pub fn write_count(&self) -> usize {
+        self.state.lock().writes.len()
+    }
+    /// Whether the device was flushed right after the first `point` writes
+    pub fn flushed_at(&self, point: usize) -> bool {
+        self.state.lock().flushes.contains(&point)
+    }
+    /// Rebuild the image as it would be on disk after a crash right after the first
+    /// `point` writes, starting from `base`, the image before any recorded write.
+    /// Writes up to the last flush before the crash point are durable,
+    /// the rest are treated according to `policy`.
+    pub fn image_at(&self, base: &[u8], point: usize, policy: CrashPolicy) -> Vec<u8> {
+        let state = self.state.lock();
+        assert!(point <= state.writes.len());
+        let durable = state
+            .flushes
+            .iter()
+            .copied()
+            .filter(|flushed| *flushed <= point)
+            .max()
+            .unwrap_or(0);
+        let mut image = base.to_vec();
+        let mut apply = |record: &WriteRecord| {
+            let start = record.block_id * BLOCK_SZ;
+            image[start..start + BLOCK_SZ].copy_from_slice(&record.data);
+        };
+        state.writes[..durable].iter().for_each(&mut apply);
+        let unflushed = &state.writes[durable..point];
+        match policy {
+            CrashPolicy::Keep => unflushed.iter().for_each(&mut apply),
+            CrashPolicy::Drop => {}
+            CrashPolicy::Reverse => unflushed.iter().rev().for_each(&mut apply),
+            CrashPolicy::Random(seed) => {
+                let mut rng = XorShift(seed | 1);
+                let mut order: Vec<&WriteRecord> = unflushed
+                    .iter()
+                    .filter(|_| rng.next() & 1 == 0)
+                    .collect();
+                // Fisher-Yates 洗牌
+                for i in (1..order.len()).rev() {
+                    order.swap(i, rng.next() as usize % (i + 1));
+                }
+                order.into_iter().for_each(&mut apply);
+            }
+        }
+        image
+    }
+    fn check_error(&self, state: &FaultState, block_id: usize) {
+        if state.error_blocks.contains(&block_id) {
+            panic!("I/O error on block {}", block_id);
+        }
+    }
+}
+
+impl BlockDevice for FaultyBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.check_error(&self.state.lock(), block_id);
+        self.inner.read_block(block_id, buf);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut state = self.state.lock();
+        self.check_error(&state, block_id);
+        if state.crashed() {
+            return;
+        }
+        state.writes.push(WriteRecord {
+            block_id,
+            data: buf.to_vec(),
+        });
+        self.inner.write_block(block_id, buf);
+    }
+    fn flush(&self) {
+        let mut state = self.state.lock();
+        let writes = state.writes.len();
+        state.flushes.push(writes);
+        drop(state);
+        self.inner.flush();
+    }
+    fn discard(&self, blocks: Range<usize>) {
+        if self.state.lock().crashed() {
+            return;
+        }
+        self.inner.discard(blocks);
+    }
+}
+
+/// 测试用的伪随机数，不需要密码学强度
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// A crash point at which the reopened image is broken
+#[derive(Debug)]
+pub struct CrashFailure {
+    /// Number of writes before the crash
+    pub point: usize,
+    /// What became of the unflushed writes
+    pub policy: CrashPolicy,
+    /// Whether the device was flushed right at the crash point, so no write was lost
+    pub flushed: bool,
+    /// Whether the image could be mounted at all
+    pub mounted: bool,
+    /// Problems found by [`crate::FileSystem::check`], or the panic message of mounting
+    pub problems: Vec<alloc::string::String>,
+}
+
+/// Run `workload` on a copy of the image `base`, then replay every crash point of it under
+/// each of `policies`: the image at that point is mounted and checked with
+/// [`crate::FileSystem::check`]. Return the crash points that left a broken image.
+#[cfg(any(feature = "std", test))]
+pub fn replay_crashes(
+    base: &[u8],
+    workload: impl FnOnce(&Arc<crate::FileSystem>),
+    policies: &[CrashPolicy],
+) -> Vec<CrashFailure> {
+    use super::{block_cache_sync_all, FileSystem, RamBlockDevice};
+    use alloc::string::String;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let block_device = Arc::new(FaultyBlockDevice::new(Arc::new(RamBlockDevice::from_bytes(
+        base.to_vec(),
+    ))));
+    let efs = FileSystem::open(block_device.clone());
+    workload(&efs);
+    block_cache_sync_all();
+    drop(efs);
+    let mut failures = Vec::new();
+    for point in 0..=block_device.write_count() {
+        for policy in policies.iter().copied() {
+            let image = block_device.image_at(base, point, policy);
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                FileSystem::open(Arc::new(RamBlockDevice::from_bytes(image))).check()
+            }));
+            let (mounted, problems) = match result {
+                Ok(problems) => (true, problems),
+                Err(payload) => {
+                    let message = payload
+                        .downcast_ref::<&str>()
+                        .map(|message| String::from(*message))
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| String::from("panic while mounting"));
+                    (false, alloc::vec![message])
+                }
+            };
+            if !problems.is_empty() {
+                failures.push(CrashFailure {
+                    point,
+                    policy,
+                    flushed: block_device.flushed_at(point),
+                    mounted,
+                    problems,
+                });
+            }
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_cache_sync_all, FileSystem, RamBlockDevice};
+    use alloc::vec;
+
+    const TOTAL_BLOCKS: usize = 2048;
+
+    /// 只有根目录和一个文件的镜像
+    fn base_image() -> Vec<u8> {
+        let block_device = Arc::new(RamBlockDevice::new(TOTAL_BLOCKS));
+        let efs = FileSystem::create(block_device.clone(), TOTAL_BLOCKS as u32, 1);
+        let file = FileSystem::root_inode(&efs).create("old").unwrap();
+        file.write_at(0, &[1u8; 1000]);
+        block_cache_sync_all();
+        block_device.to_bytes()
+    }
+
+    #[test]
+    fn completed_operations_leave_consistent_images() {
+        let base = base_image();
+        let failures = replay_crashes(
+            &base,
+            |efs| {
+                let root_inode = FileSystem::root_inode(efs);
+                let file = root_inode.create("new").unwrap();
+                assert_eq!(file.write_at(0, &[2u8; 20000]), 20000);
+                let old = root_inode.find("old").unwrap();
+                assert_eq!(old.write_at(1000, &[3u8; 3000]), 3000);
+                old.clear();
+            },
+            &[
+                CrashPolicy::Keep,
+                CrashPolicy::Drop,
+                CrashPolicy::Reverse,
+                CrashPolicy::Random(7),
+            ],
+        );
+        // 操作中途崩溃至多泄漏一些块，不会留下悬空的目录项或引用空闲块的 inode
+        for failure in failures.iter() {
+            assert!(failure.mounted, "{:?}", failure);
+            assert!(
+                failure
+                    .problems
+                    .iter()
+                    .all(|problem| problem.ends_with("is allocated but unused")),
+                "{:?}",
+                failure
+            );
+        }
+    }
+
+    #[test]
+    fn orphans_are_reclaimed_after_crash() {
+        // old 之后还有两项，删除时最后一项 last 移入 old 的位置
+        let block_device = Arc::new(RamBlockDevice::from_bytes(base_image()));
+        let efs = FileSystem::open(block_device.clone());
+        let root_inode = FileSystem::root_inode(&efs);
+        root_inode.create("keep").unwrap();
+        root_inode.create("last").unwrap();
+        block_cache_sync_all();
+        drop((root_inode, efs));
+        let base = block_device.to_bytes();
+        let block_device = Arc::new(FaultyBlockDevice::new(Arc::new(RamBlockDevice::from_bytes(
+            base.clone(),
+        ))));
+        let efs = FileSystem::open(block_device.clone());
+        let root_inode = FileSystem::root_inode(&efs);
+        let old = root_inode.find("old").unwrap();
+        assert!(root_inode.unlink("old"));
+        assert_eq!(old.write_at(1000, &[3u8; 3000]), 3000);
+        drop(old);
+        let file = root_inode.create("new").unwrap();
+        assert_eq!(file.write_at(0, &[2u8; 3000]), 3000);
+        root_inode.create_dir("dir").unwrap();
+        block_cache_sync_all();
+        drop((file, root_inode, efs));
+        for point in 0..=block_device.write_count() {
+            for policy in [CrashPolicy::Keep, CrashPolicy::Reverse] {
+                let image = block_device.image_at(&base, point, policy);
+                let efs = FileSystem::open(Arc::new(RamBlockDevice::from_bytes(image)));
+                // 挂载时处理完孤儿列表，中途崩溃最多泄漏数据块，其余目录项不受影响
+                assert!(efs.orphans().is_empty(), "point {} {:?}", point, policy);
+                let root_inode = FileSystem::root_inode(&efs);
+                assert!(root_inode.find("keep").is_some(), "point {} {:?}", point, policy);
+                assert!(root_inode.find("last").is_some(), "point {} {:?}", point, policy);
+                let problems = efs.check();
+                assert!(
+                    problems
+                        .iter()
+                        .all(|problem| problem.ends_with("is allocated but unused")),
+                    "point {} {:?}: {:?}",
+                    point,
+                    policy,
+                    problems
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn crash_after_drops_later_writes() {
+        let inner = Arc::new(RamBlockDevice::new(4));
+        let block_device = FaultyBlockDevice::new(inner.clone());
+        block_device.crash_after(2);
+        for block_id in 0..3 {
+            block_device.write_block(block_id, &[block_id as u8 + 1; BLOCK_SZ]);
+        }
+        assert!(block_device.crashed());
+        assert_eq!(block_device.write_count(), 2);
+        let image = inner.to_bytes();
+        assert_eq!(image[0], 1);
+        assert_eq!(image[BLOCK_SZ], 2);
+        assert_eq!(image[2 * BLOCK_SZ], 0);
+    }
+
+    #[test]
+    fn unflushed_writes_follow_policy() {
+        let block_device = FaultyBlockDevice::new(Arc::new(RamBlockDevice::new(3)));
+        block_device.write_block(1, &[1; BLOCK_SZ]);
+        block_device.flush();
+        block_device.write_block(1, &[2; BLOCK_SZ]);
+        block_device.write_block(1, &[3; BLOCK_SZ]);
+        block_device.write_block(2, &[4; BLOCK_SZ]);
+        let base = vec![0u8; 3 * BLOCK_SZ];
+        let at = |point, policy| {
+            let image = block_device.image_at(&base, point, policy);
+            (image[BLOCK_SZ], image[2 * BLOCK_SZ])
+        };
+        assert_eq!(at(0, CrashPolicy::Keep), (0, 0));
+        assert_eq!(at(1, CrashPolicy::Drop), (1, 0));
+        assert_eq!(at(4, CrashPolicy::Keep), (3, 4));
+        assert_eq!(at(4, CrashPolicy::Drop), (1, 0));
+        assert_eq!(at(4, CrashPolicy::Reverse), (2, 4));
+        assert_eq!(at(3, CrashPolicy::Reverse), (2, 0));
+        let (block1, block2) = at(4, CrashPolicy::Random(42));
+        assert!([1, 2, 3].contains(&block1) && [0, 4].contains(&block2));
+        assert!(block_device.flushed_at(1) && !block_device.flushed_at(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "I/O error on block 5")]
+    fn failed_block() {
+        let block_device = FaultyBlockDevice::new(Arc::new(RamBlockDevice::new(8)));
+        block_device.fail_block(5);
+        let mut buf = [0u8; BLOCK_SZ];
+        block_device.read_block(4, &mut buf);
+        block_device.read_block(5, &mut buf);
+    }
+}
@@ -1,23 +1,97 @@
 use super::{
-    block_cache_sync_all, get_block_cache, Bitmap, BlockDevice, DiskInode, DiskInodeType, Inode,
-    SuperBlock,
+    block_cache_sync_all, get_block_cache, zero_block, Bitmap, BlockDevice, DataExtent, DirEntry, DiskFormat,
+    DiskInode, DiskInodeType, Inode, Orphan, SuperBlock, SuperBlockOps, VfsInode, VfsStat, DIRENT_SZ, FEATURE_COMPAT_QUOTA, FEATURE_COMPAT_VERITY,
+    FEATURE_INCOMPAT_DATA_EXTENTS, FORMAT_VERSION, LABEL_SZ, MAX_DATA_EXTENTS,
 };
+use crate::crypt::{self, InodeCipher, KEY_SZ};
+use crate::quota::{Quota, QuotaTable, QUOTA_BLOCKS};
+use crate::verity::{self, VerifiedBlockDevice};
 use crate::BLOCK_SZ;
-use alloc::sync::Arc;
-use spin::Mutex;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::{Mutex, RwLock};
 
-type DataBlock = [u8; BLOCK_SZ];
+/// 内存 inode 表在清理失效表项前至少容纳的表项数
+const INODE_TABLE_MIN: usize = 64;
 
 ///On the memory layout of the filesystem:
+///
+/// 没有整个文件系统的大锁：分配只锁对应的位图，配额表、密钥和 inode 表各有自己的锁，
+/// 磁盘 inode 的大小和块映射由 [`Inode`] 自己的锁保护。
 pub struct FileSystem {
     ///Real device
     pub block_device: Arc<dyn BlockDevice>,
     ///Inode bitmap
-    pub inode_bitmap: Bitmap,
-    ///Data bitmap
-    pub data_bitmap: Bitmap,
+    inode_bitmap: Mutex<Bitmap>,
+    ///Data bitmap and extents
+    data_area: Mutex<DataArea>,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    /// 挂载时提供的加密密钥
+    key: RwLock<Option<[u8; KEY_SZ]>>,
+    quota_table: Mutex<QuotaTable>,
+    /// 内存 inode 表
+    inode_table: Mutex<InodeTable<Inode>>,
+    /// 已释放、尚未 discard 的数据块。引用它们的元数据落盘之前不能丢弃其内容，见 [`FileSystem::sync`]
+    freed_blocks: Mutex<BTreeSet<u32>>,
+}
+
+/// Capacity and free space of an easy-fs image, mapped onto [`crate::VfsStat`] for the VFS layer
+#[derive(Clone, Copy, Debug)]
+pub struct FsStat {
+    /// Number of inodes
+    pub inodes: u32,
+    /// Number of free inodes
+    pub free_inodes: u32,
+    /// Number of data blocks, including those of the extents
+    pub data_blocks: u32,
+    /// Number of free data blocks
+    pub free_data_blocks: u32,
+}
+
+/// 数据区的分配状态
+struct DataArea {
+    bitmap: Bitmap,
+    /// 扩容追加的数据区
+    extents: Vec<DataExtent>,
+}
+
+/// 内存 inode 表，同一个 inode 只有一个活跃的 [`Inode`]
+pub(crate) struct InodeTable<T> {
+    inodes: BTreeMap<u32, Weak<T>>,
+    /// 表项数达到该值时清理已无引用的表项
+    limit: usize,
+}
+
+impl<T> InodeTable<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            inodes: BTreeMap::new(),
+            limit: INODE_TABLE_MIN,
+        }
+    }
+    /// Get the live inode of `inode_id`, or insert the one made by `make`
+    pub(crate) fn get_or_insert(&mut self, inode_id: u32, make: impl FnOnce() -> Arc<T>) -> Arc<T> {
+        if let Some(inode) = self.inodes.get(&inode_id).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let inode = make();
+        // 不在 inode 析构时加锁删除表项，而是在表变大时统一清理
+        if self.inodes.len() >= self.limit {
+            self.inodes.retain(|_, inode| inode.strong_count() > 0);
+            self.limit = INODE_TABLE_MIN.max(self.inodes.len() * 2);
+        }
+        self.inodes.insert(inode_id, Arc::downgrade(&inode));
+        inode
+    }
+    /// Forget the inode of `inode_id`, later lookups make a new one
+    pub(crate) fn remove(&mut self, inode_id: u32) {
+        self.inodes.remove(&inode_id);
+    }
 }
 
 impl FileSystem {
@@ -26,37 +100,36 @@ impl FileSystem {
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
-    ) -> Arc<Mutex<Self>> {
+    ) -> Arc<Self> {
         // calculate block size of areas & create bitmaps
         let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
-        let inode_num = inode_bitmap.maximum();
-        let inode_area_blocks =
-            ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+        let (inode_area_blocks, data_bitmap_blocks, data_area_blocks) =
+            Self::layout(total_blocks, inode_bitmap_blocks).expect("Device too small!");
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
-        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
-        let data_area_blocks = data_total_blocks - data_bitmap_blocks;
-        let data_bitmap = Bitmap::new(
+        let data_bitmap = Bitmap::with_maximum(
             (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
             data_bitmap_blocks as usize,
+            data_area_blocks as usize,
         );
-        let mut fs = Self {
+        let fs = Self {
             block_device: Arc::clone(&block_device),
-            inode_bitmap,
-            data_bitmap,
+            inode_bitmap: Mutex::new(inode_bitmap),
+            data_area: Mutex::new(DataArea {
+                bitmap: data_bitmap,
+                extents: Vec::new(),
+            }),
             inode_area_start_block: 1 + inode_bitmap_blocks,
             data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            key: RwLock::new(None),
+            quota_table: Mutex::new(QuotaTable::new(0, 0)),
+            inode_table: Mutex::new(InodeTable::new()),
+            freed_blocks: Mutex::new(BTreeSet::new()),
         };
-        // clear all blocks
-        for i in 0..total_blocks {
-            get_block_cache(i as usize, Arc::clone(&block_device))
-                .lock()
-                .modify(0, |data_block: &mut DataBlock| {
-                    for byte in data_block.iter_mut() {
-                        *byte = 0;
-                    }
-                });
+        // clear the metadata blocks, data blocks are zeroed when allocated
+        for i in 0..fs.data_area_start_block {
+            zero_block(i as usize, Arc::clone(&block_device));
         }
+        block_device.discard(fs.data_area_start_block as usize..total_blocks as usize);
         // initialize SuperBlock
         get_block_cache(0, Arc::clone(&block_device)).lock().modify(
             0,
@@ -79,35 +152,274 @@ impl FileSystem {
             .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
                 disk_inode.initialize(DiskInodeType::Directory);
             });
+        // reserve the quota table, the root inode is owned by uid 0
+        let quota_start_block = fs.alloc_data();
+        for i in 1..QUOTA_BLOCKS {
+            assert_eq!(fs.alloc_data(), quota_start_block + i);
+        }
+        *fs.quota_table.lock() = QuotaTable::new(quota_start_block as usize, QUOTA_BLOCKS as usize);
+        get_block_cache(0, Arc::clone(&block_device)).lock().modify(
+            0,
+            |super_block: &mut SuperBlock| {
+                super_block.quota_start_block = quota_start_block;
+                super_block.quota_blocks = QUOTA_BLOCKS;
+                super_block.compat_features |= FEATURE_COMPAT_QUOTA;
+            },
+        );
+        fs.charge(0, 0, 1);
         block_cache_sync_all();
-        Arc::new(Mutex::new(fs))
+        Arc::new(fs)
+    }
+    /// Blocks of the inode area, the data bitmap and the data area, `None` if the metadata does not fit
+    fn layout(total_blocks: u32, inode_bitmap_blocks: u32) -> Option<(u32, u32, u32)> {
+        let inode_num = inode_bitmap_blocks as usize * BLOCK_SZ * 8;
+        let inode_area_blocks = (inode_num * DiskInode::DISK_SZ).div_ceil(BLOCK_SZ) as u32;
+        let data_total_blocks =
+            total_blocks.checked_sub(1 + inode_bitmap_blocks + inode_area_blocks)?;
+        // 每个数据位图块管理 4096 个数据块
+        let data_bitmap_blocks = data_total_blocks.div_ceil(4097);
+        Some((
+            inode_area_blocks,
+            data_bitmap_blocks,
+            data_total_blocks - data_bitmap_blocks,
+        ))
+    }
+    /// Data blocks left for files and directories by [`FileSystem::create`] with these arguments,
+    /// `None` if the device cannot even hold the metadata and the quota table
+    pub fn usable_blocks(total_blocks: u32, inode_bitmap_blocks: u32) -> Option<u32> {
+        let (_, _, data_area_blocks) = Self::layout(total_blocks, inode_bitmap_blocks)?;
+        data_area_blocks.checked_sub(QUOTA_BLOCKS)
+    }
+    /// Open a block device as a filesystem.
+    /// The orphan inodes left by a crash or by files deleted while open are reclaimed.
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        let efs = Self::load(block_device);
+        efs.recover_orphans();
+        efs
     }
-    /// Open a block device as a filesystem
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+    /// Open a block device as a filesystem without writing to it, orphan inodes are left alone.
+    /// For read-only devices, and to look at an image as a crash left it.
+    pub fn load(block_device: Arc<dyn BlockDevice>) -> Arc<Self> {
         // read SuperBlock
         get_block_cache(0, Arc::clone(&block_device))
             .lock()
             .read(0, |super_block: &SuperBlock| {
                 assert!(super_block.is_valid(), "Error loading fs!");
+                assert_eq!(
+                    super_block.version, FORMAT_VERSION,
+                    "Unsupported format version {}!",
+                    super_block.version
+                );
+                assert_eq!(
+                    super_block.unsupported_features(),
+                    0,
+                    "Unsupported incompatible features {:#x}!",
+                    super_block.unsupported_features()
+                );
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
                 let fs = Self {
                     block_device,
-                    inode_bitmap: Bitmap::new(1, super_block.inode_bitmap_blocks as usize),
-                    data_bitmap: Bitmap::new(
-                        (1 + inode_total_blocks) as usize,
-                        super_block.data_bitmap_blocks as usize,
-                    ),
+                    inode_bitmap: Mutex::new(Bitmap::new(
+                        1,
+                        super_block.inode_bitmap_blocks as usize,
+                    )),
+                    data_area: Mutex::new(DataArea {
+                        bitmap: Bitmap::with_maximum(
+                            (1 + inode_total_blocks) as usize,
+                            super_block.data_bitmap_blocks as usize,
+                            super_block.data_area_blocks as usize,
+                        ),
+                        extents: super_block.data_extents
+                            [..super_block.data_extent_count as usize]
+                            .to_vec(),
+                    }),
                     inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
                     data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
+                    key: RwLock::new(None),
+                    quota_table: Mutex::new(QuotaTable::new(
+                        super_block.quota_start_block as usize,
+                        super_block.quota_blocks as usize,
+                    )),
+                    inode_table: Mutex::new(InodeTable::new()),
+                    freed_blocks: Mutex::new(BTreeSet::new()),
                 };
-                Arc::new(Mutex::new(fs))
+                Arc::new(fs)
             })
     }
+    /// Open a block device as a filesystem, with the key of its encrypted files.
+    /// Return `None` if the key is not the one the image was encrypted with.
+    pub fn open_with_key(
+        block_device: Arc<dyn BlockDevice>,
+        key: [u8; KEY_SZ],
+    ) -> Option<Arc<Self>> {
+        // 先提供密钥，加密目录下的孤儿才能判断是否仍被引用
+        let efs = Self::load(block_device);
+        if !efs.set_key(key) {
+            return None;
+        }
+        efs.recover_orphans();
+        Some(efs)
+    }
+    /// Supply the key of encrypted files, which is never stored on disk.
+    /// The first key supplied to an image is recorded by its check value, a different key is
+    /// refused later and false is returned.
+    pub fn set_key(&self, key: [u8; KEY_SZ]) -> bool {
+        let check = crypt::key_check(&key);
+        let block_cache = get_block_cache(0, Arc::clone(&self.block_device));
+        let mut block_cache = block_cache.lock();
+        let recorded = block_cache.read(0, |super_block: &SuperBlock| super_block.key_check);
+        if recorded == [0; 32] {
+            block_cache.modify(0, |super_block: &mut SuperBlock| super_block.key_check = check);
+        } else if recorded != check {
+            return false;
+        }
+        drop(block_cache);
+        *self.key.write() = Some(key);
+        true
+    }
+    /// Get the cipher of an inode, `None` if no key has been supplied
+    pub(crate) fn inode_cipher(&self, inode_id: u32) -> Option<InodeCipher> {
+        self.key.read().as_ref().map(|key| InodeCipher::new(key, inode_id))
+    }
+    /// Open a read-only image and check every block read against its hash tree.
+    /// `root_hash` is the trusted root hash, the one recorded in the image is not trusted.
+    pub fn open_verified(block_device: Arc<dyn BlockDevice>, root_hash: [u8; 32]) -> Arc<Self> {
+        Self::load(Arc::new(VerifiedBlockDevice::new(block_device, root_hash)))
+    }
+    /// Append a hash tree over the whole image and return its root hash.
+    /// The device must have room for [`crate::hash_tree_blocks`] blocks after the filesystem.
+    pub fn build_hash_tree(&self) -> [u8; 32] {
+        self.enable_features(FEATURE_COMPAT_VERITY, 0);
+        verity::build(&self.block_device)
+    }
+    /// Mark features as in use in the superblock
+    pub(crate) fn enable_features(&self, compat: u32, incompat: u32) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.compat_features |= compat;
+                super_block.incompat_features |= incompat;
+            });
+    }
+    /// Get the compatible and incompatible features in use
+    pub fn features(&self) -> (u32, u32) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                (super_block.compat_features, super_block.incompat_features)
+            })
+    }
+    /// Get the UUID of the volume
+    pub fn uuid(&self) -> [u8; 16] {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.uuid)
+    }
+    /// Set the UUID of the volume, `create` leaves it all zero
+    pub fn set_uuid(&self, uuid: [u8; 16]) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| super_block.uuid = uuid);
+    }
+    /// Get the label of the volume
+    pub fn label(&self) -> String {
+        let label = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.label);
+        let len = label.iter().position(|byte| *byte == 0).unwrap_or(LABEL_SZ);
+        String::from_utf8_lossy(&label[..len]).into()
+    }
+    /// Set the label of the volume, at most [`crate::LABEL_SZ`] bytes
+    pub fn set_label(&self, label: &str) {
+        assert!(label.len() <= LABEL_SZ, "The label is too long!");
+        let mut bytes = [0u8; LABEL_SZ];
+        bytes[..label.len()].copy_from_slice(label.as_bytes());
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| super_block.label = bytes);
+    }
+
+    /// Grow the filesystem onto a device which now holds `new_total_blocks` blocks.
+    /// The free bits left in the last data bitmap are used first, the rest of the new blocks
+    /// become a data extent with its own bitmap. The inode area keeps its size.
+    /// A hash tree built before is dropped as it no longer covers the image.
+    /// Return the number of blocks the filesystem takes afterwards, which is smaller than
+    /// `new_total_blocks` if the tail is too short for an extent or no extent slot is left.
+    pub fn grow(&self, new_total_blocks: u32) -> u32 {
+        let mut data_area = self.data_area.lock();
+        block_cache_sync_all();
+        let mut total_blocks = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.total_blocks);
+        assert!(new_total_blocks >= total_blocks, "cannot shrink the filesystem");
+        let old_total_blocks = total_blocks;
+        // 最后一个数据区紧挨着镜像末尾，先用掉其位图中尚未对应数据块的位
+        let spare = |bitmap: &Bitmap| (bitmap.capacity() - bitmap.maximum()) as u32;
+        let extra = new_total_blocks - total_blocks;
+        if let Some(extent) = data_area.extents.last_mut() {
+            let extra = extra.min(spare(&Self::extent_bitmap(extent)));
+            extent.area_blocks += extra;
+            total_blocks += extra;
+        } else {
+            let extra = extra.min(spare(&data_area.bitmap));
+            let maximum = data_area.bitmap.maximum() + extra as usize;
+            data_area.bitmap.set_maximum(maximum);
+            total_blocks += extra;
+        }
+        let rest = new_total_blocks - total_blocks;
+        let mut new_bitmap = total_blocks..total_blocks;
+        if rest >= 2 && data_area.extents.len() < MAX_DATA_EXTENTS {
+            let bitmap_blocks = rest.div_ceil(4097);
+            data_area.extents.push(DataExtent {
+                bitmap_start_block: total_blocks,
+                bitmap_blocks,
+                area_blocks: rest - bitmap_blocks,
+            });
+            new_bitmap = total_blocks..total_blocks + bitmap_blocks;
+            total_blocks = new_total_blocks;
+        }
+        // 只清零新的位图块。数据块分配时才清零，其余新块 discard 掉，稀疏镜像不会被填满
+        for block_id in new_bitmap.clone() {
+            zero_block(block_id as usize, Arc::clone(&self.block_device));
+        }
+        for blocks in [old_total_blocks..new_bitmap.start, new_bitmap.end..total_blocks] {
+            if !blocks.is_empty() {
+                self.block_device
+                    .discard(blocks.start as usize..blocks.end as usize);
+            }
+        }
+        let data_area_blocks = data_area.bitmap.maximum() as u32;
+        let extents = &data_area.extents;
+        get_block_cache(0, Arc::clone(&self.block_device)).lock().modify(
+            0,
+            |super_block: &mut SuperBlock| {
+                super_block.total_blocks = total_blocks;
+                super_block.data_area_blocks = data_area_blocks;
+                super_block.data_extent_count = extents.len() as u32;
+                super_block.data_extents[..extents.len()].copy_from_slice(extents);
+                if !extents.is_empty() {
+                    super_block.incompat_features |= FEATURE_INCOMPAT_DATA_EXTENTS;
+                }
+                super_block.compat_features &= !FEATURE_COMPAT_VERITY;
+                super_block.verity_levels = 0;
+                super_block.verity_root = [0; 32];
+            },
+        );
+        block_cache_sync_all();
+        total_blocks
+    }
+    fn extent_bitmap(extent: &DataExtent) -> Bitmap {
+        Bitmap::with_maximum(
+            extent.bitmap_start_block as usize,
+            extent.bitmap_blocks as usize,
+            extent.area_blocks as usize,
+        )
+    }
 
     /// Get inode by id
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
-        let inode_size = core::mem::size_of::<DiskInode>();
+        let inode_size = DiskInode::DISK_SZ;
         let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
         let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
         (
@@ -120,34 +432,760 @@ impl FileSystem {
         self.data_area_start_block + data_block_id
     }
     /// Allocate a new inode
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    pub fn alloc_inode(&self) -> u32 {
+        self.inode_bitmap.lock().alloc(&self.block_device).unwrap() as u32
+    }
+    /// Allocate a new inode listed as an orphan of directory `parent_id`, so that it is reclaimed
+    /// on the next mount if a crash comes before its directory entry is written.
+    /// The inode is left unprotected if the orphan list is full.
+    pub(crate) fn alloc_orphan_inode(&self, parent_id: u32) -> Orphan {
+        let inode_bitmap = self.inode_bitmap.lock();
+        // 孤儿记录须先于 inode 位图落盘：先找出将要分配的 inode 并记录，再置位
+        let inode_id = inode_bitmap.first_free(&self.block_device).unwrap() as u32;
+        let orphan = Orphan { inode_id, parent_id };
+        self.add_orphan(orphan);
+        assert_eq!(inode_bitmap.alloc(&self.block_device), Some(inode_id as usize));
+        orphan
+    }
+    /// Deallocate an orphan inode, then drop its record from the orphan list.
+    /// The inode cannot be allocated again before the record is gone.
+    pub(crate) fn dealloc_orphan_inode(&self, orphan: Orphan) {
+        let inode_bitmap = self.inode_bitmap.lock();
+        inode_bitmap.dealloc(&self.block_device, orphan.inode_id as usize);
+        // 记录先于位图落盘的话，崩溃会泄漏该 inode
+        self.sync();
+        self.remove_orphan(orphan);
     }
 
-    /// Allocate a data block
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    /// Allocate a data block, which is zeroed
+    pub fn alloc_data(&self) -> u32 {
+        let data_area = self.data_area.lock();
+        let block_id = match data_area.bitmap.alloc(&self.block_device) {
+            Some(bit) => bit as u32 + self.data_area_start_block,
+            None => data_area
+                .extents
+                .iter()
+                .find_map(|extent| {
+                    Self::extent_bitmap(extent)
+                        .alloc(&self.block_device)
+                        .map(|bit| bit as u32 + extent.area_start_block())
+                })
+                .expect("no free data block"),
+        };
+        drop(data_area);
+        self.zero_data(block_id..block_id + 1);
+        block_id
     }
-    /// Deallocate a data block
-    pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+    /// Allocate `len` contiguous zeroed data blocks within one data area and return the first
+    pub(crate) fn alloc_data_run(&self, len: u32) -> Option<u32> {
+        let data_area = self.data_area.lock();
+        let start_block = match data_area.bitmap.alloc_run(&self.block_device, len as usize) {
+            Some(bit) => bit as u32 + self.data_area_start_block,
+            None => data_area.extents.iter().find_map(|extent| {
+                Self::extent_bitmap(extent)
+                    .alloc_run(&self.block_device, len as usize)
+                    .map(|bit| bit as u32 + extent.area_start_block())
+            })?,
+        };
+        drop(data_area);
+        self.zero_data(start_block..start_block + len);
+        Some(start_block)
+    }
+    /// Zero newly allocated blocks, which may hold stale data as freed blocks are discarded rather than cleared.
+    /// 先从待 discard 的集合中移除，之后的 discard 不会再丢弃新写入的内容
+    fn zero_data(&self, blocks: Range<u32>) {
+        let mut freed_blocks = self.freed_blocks.lock();
+        for block_id in blocks {
+            freed_blocks.remove(&block_id);
+            zero_block(block_id as usize, Arc::clone(&self.block_device));
+        }
+    }
+    /// Allocate `count` data blocks in as few contiguous runs as possible, in ascending order
+    /// within each run. Return `None` without allocating anything if there are not enough free blocks.
+    pub(crate) fn alloc_data_runs(&self, count: u32) -> Option<Vec<u32>> {
+        if self.stat().free_data_blocks < count {
+            return None;
+        }
+        let mut blocks = Vec::with_capacity(count as usize);
+        let mut len = count;
+        while (blocks.len() as u32) < count {
+            len = len.min(count - blocks.len() as u32);
+            match self.alloc_data_run(len) {
+                Some(first_block) => blocks.extend(first_block..first_block + len),
+                None => {
+                    assert!(len > 1, "no free data block");
+                    len /= 2;
+                }
+            }
+        }
+        Some(blocks)
+    }
+    /// Deallocate a data block, it is discarded on the next [`FileSystem::sync`]
+    pub fn dealloc_data(&self, block_id: u32) {
+        self.freed_blocks.lock().insert(block_id);
+        let data_area = self.data_area.lock();
+        match data_area.extents.iter().find(|extent| extent.contains(block_id)) {
+            Some(extent) => Self::extent_bitmap(extent).dealloc(
+                &self.block_device,
+                (block_id - extent.area_start_block()) as usize,
+            ),
+            None => data_area.bitmap.dealloc(
+                &self.block_device,
+                (block_id - self.data_area_start_block) as usize,
+            ),
+        }
+    }
+    /// Get the orphan list
+    pub fn orphans(&self) -> Vec<Orphan> {
+        get_block_cache(0, Arc::clone(&self.block_device))
             .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                data_block.iter_mut().for_each(|p| {
-                    *p = 0;
-                })
-            });
-        self.data_bitmap.dealloc(
+            .read(0, |super_block: &SuperBlock| super_block.orphans().to_vec())
+    }
+    /// Add an entry to the orphan list and write it to the device at once.
+    /// Return false if the list is full.
+    pub(crate) fn add_orphan(&self, orphan: Orphan) -> bool {
+        let super_block = get_block_cache(0, Arc::clone(&self.block_device));
+        let mut super_block = super_block.lock();
+        if !super_block.modify(0, |super_block: &mut SuperBlock| super_block.add_orphan(orphan)) {
+            return false;
+        }
+        super_block.sync();
+        drop(super_block);
+        self.block_device.flush();
+        true
+    }
+    /// Remove an entry from the orphan list, it is written back on the next sync
+    pub(crate) fn remove_orphan(&self, orphan: Orphan) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| super_block.remove_orphan(orphan));
+    }
+    /// Reclaim every listed orphan which no directory refers to, with its blocks,
+    /// and empty the list. Orphans under an encrypted directory are kept if the key is missing.
+    fn recover_orphans(&self) {
+        let orphans = self.orphans();
+        if orphans.is_empty() {
+            return;
+        }
+        let mut parent_ids: Vec<u32> = orphans.iter().map(|orphan| orphan.parent_id).collect();
+        parent_ids.sort_unstable();
+        parent_ids.dedup();
+        for parent_id in parent_ids {
+            self.drop_duplicate_last_dirent(parent_id);
+        }
+        let mut inode_ids: Vec<u32> = orphans.iter().map(|orphan| orphan.inode_id).collect();
+        inode_ids.sort_unstable();
+        inode_ids.dedup();
+        for inode_id in inode_ids {
+            // inode 释放后可能被重新分配，同一 inode 可有多条记录，任一目录引用它就保留
+            let records: Vec<Orphan> = orphans
+                .iter()
+                .filter(|orphan| orphan.inode_id == inode_id)
+                .copied()
+                .collect();
+            let linked: Vec<Option<bool>> = records
+                .iter()
+                .map(|orphan| self.dir_refers_to(orphan.parent_id, inode_id))
+                .collect();
+            if !linked.contains(&Some(true)) {
+                if linked.contains(&None) {
+                    continue;
+                }
+                if self.is_inode_allocated(inode_id) {
+                    self.reclaim_inode(inode_id);
+                }
+            }
+            for orphan in records {
+                self.remove_orphan(orphan);
+            }
+        }
+        self.sync();
+    }
+    /// Whether an inode is allocated, false if it is out of range
+    pub(crate) fn is_inode_allocated(&self, inode_id: u32) -> bool {
+        let inode_bitmap = self.inode_bitmap.lock();
+        (inode_id as usize) < inode_bitmap.maximum()
+            && inode_bitmap.is_allocated(&self.block_device, inode_id as usize)
+    }
+    /// Whether directory `dir_id` has an entry for `inode_id`, `None` if it cannot be listed
+    /// without the key. False if `dir_id` is no longer an allocated directory.
+    fn dir_refers_to(&self, dir_id: u32, inode_id: u32) -> Option<bool> {
+        if !self.is_inode_allocated(dir_id) {
+            return Some(false);
+        }
+        let disk_inode = self.disk_inode(dir_id);
+        if !disk_inode.is_dir() {
+            return Some(false);
+        }
+        let cipher = match disk_inode.is_encrypted() {
+            true => Some(self.inode_cipher(dir_id)?),
+            false => None,
+        };
+        let mut bytes = [0u8; DIRENT_SZ];
+        Some((0..disk_inode.size as usize / DIRENT_SZ).any(|i| {
+            disk_inode.read_at(i * DIRENT_SZ, &mut bytes, &self.block_device, cipher.as_ref());
+            DirEntry::from_bytes(&bytes).inode_number() == inode_id
+        }))
+    }
+    /// unlink 把最后一项移入空位之后、缩小目录之前崩溃，最后一项会出现两次，这里去掉末尾那一项
+    fn drop_duplicate_last_dirent(&self, dir_id: u32) {
+        if !self.is_inode_allocated(dir_id) {
+            return;
+        }
+        let (block_id, block_offset) = self.get_disk_inode_pos(dir_id);
+        let inode_block = get_block_cache(block_id as usize, Arc::clone(&self.block_device));
+        let mut disk_inode: DiskInode = inode_block.lock().get(block_offset);
+        if !disk_inode.is_dir() {
+            return;
+        }
+        let cipher = match disk_inode.is_encrypted() {
+            true => match self.inode_cipher(dir_id) {
+                Some(cipher) => Some(cipher),
+                None => return,
+            },
+            false => None,
+        };
+        let count = disk_inode.size as usize / DIRENT_SZ;
+        if count < 2 {
+            return;
+        }
+        let mut bytes = [0u8; DIRENT_SZ];
+        disk_inode.read_at(
+            (count - 1) * DIRENT_SZ,
+            &mut bytes,
             &self.block_device,
-            (block_id - self.data_area_start_block) as usize,
-        )
+            cipher.as_ref(),
+        );
+        let last = DirEntry::from_bytes(&bytes);
+        let duplicated = (0..count - 1).any(|i| {
+            disk_inode.read_at(i * DIRENT_SZ, &mut bytes, &self.block_device, cipher.as_ref());
+            last.name().is_some() && DirEntry::from_bytes(&bytes).name() == last.name()
+        });
+        if !duplicated {
+            return;
+        }
+        let data_blocks_dealloc =
+            disk_inode.decrease_size(((count - 1) * DIRENT_SZ) as u32, &self.block_device);
+        inode_block.lock().set(block_offset, &disk_inode);
+        block_cache_sync_all();
+        self.release(disk_inode.uid(), data_blocks_dealloc.len() as u32, 0);
+        for data_block in data_blocks_dealloc {
+            self.dealloc_data(data_block);
+        }
+    }
+    /// Free an unreachable inode and its blocks. After a crash some of the blocks may
+    /// already be free in the bitmap, they are skipped.
+    fn reclaim_inode(&self, inode_id: u32) {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        let inode_block = get_block_cache(block_id as usize, Arc::clone(&self.block_device));
+        let mut disk_inode: DiskInode = inode_block.lock().get(block_offset);
+        let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
+        inode_block.lock().set(block_offset, &disk_inode);
+        let data_area = self.data_area.lock();
+        let data_blocks_dealloc: Vec<u32> = data_blocks_dealloc
+            .into_iter()
+            .filter(|block_id| self.is_data_allocated(&data_area, *block_id) == Some(true))
+            .collect();
+        drop(data_area);
+        self.release(disk_inode.uid(), data_blocks_dealloc.len() as u32, 1);
+        for data_block in data_blocks_dealloc {
+            self.dealloc_data(data_block);
+        }
+        self.dealloc_inode(inode_id);
+    }
+    /// Deallocate an inode
+    pub fn dealloc_inode(&self, inode_id: u32) {
+        self.inode_bitmap
+            .lock()
+            .dealloc(&self.block_device, inode_id as usize)
+    }
+    /// Charge blocks and inodes to the quota of `uid`, refused if a hard limit would be exceeded
+    pub fn charge(&self, uid: u16, blocks: u32, inodes: u32) -> bool {
+        self.quota_table
+            .lock()
+            .charge(uid, blocks, inodes, &self.block_device)
+    }
+    /// Give back blocks and inodes charged to `uid`
+    pub fn release(&self, uid: u16, blocks: u32, inodes: u32) {
+        self.quota_table
+            .lock()
+            .release(uid, blocks, inodes, &self.block_device)
+    }
+    /// Set the limits of `quota.uid`, usage fields are ignored.
+    /// Return false if the image has no quota table or it is full.
+    pub fn set_quota(&self, quota: &Quota) -> bool {
+        self.quota_table.lock().set_limits(quota, &self.block_device)
+    }
+    /// Get the limits and usage of `uid`
+    pub fn quota(&self, uid: u16) -> Option<Quota> {
+        self.quota_table.lock().get(uid, &self.block_device)
+    }
+    /// Get the capacity and free space of the filesystem
+    pub fn stat(&self) -> FsStat {
+        let inode_bitmap = self.inode_bitmap.lock();
+        let inodes = inode_bitmap.maximum() as u32;
+        let used_inodes = inode_bitmap.count_allocated(&self.block_device) as u32;
+        drop(inode_bitmap);
+        let data_area = self.data_area.lock();
+        let mut data_blocks = data_area.bitmap.maximum() as u32;
+        let mut used_data_blocks = data_area.bitmap.count_allocated(&self.block_device) as u32;
+        for extent in data_area.extents.iter() {
+            let bitmap = Self::extent_bitmap(extent);
+            data_blocks += extent.area_blocks;
+            used_data_blocks += bitmap.count_allocated(&self.block_device) as u32;
+        }
+        FsStat {
+            inodes,
+            free_inodes: inodes - used_inodes,
+            data_blocks,
+            free_data_blocks: data_blocks - used_data_blocks,
+        }
+    }
+    /// Get the limits and usage of every owner with a quota entry
+    pub fn quotas(&self) -> Vec<Quota> {
+        self.quota_table.lock().list(&self.block_device)
+    }
+    /// Get the vfs inode of `inode_id` from the inode table.
+    /// The same `Arc` is handed out as long as some handle to it is alive.
+    pub fn get_inode(self: &Arc<Self>, inode_id: u32) -> Arc<Inode> {
+        self.inode_table.lock().get_or_insert(inode_id, || {
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+            Arc::new(Inode::new(
+                inode_id,
+                block_id,
+                block_offset,
+                Arc::clone(self),
+                Arc::clone(&self.block_device),
+            ))
+        })
+    }
+    /// Write every cached block back to its device, then discard the blocks freed before.
+    /// Contiguous freed blocks are discarded as one range.
+    pub fn sync(&self) {
+        // 只丢弃同步之前已释放的块：它们不再被落盘的元数据引用
+        let freed: Vec<u32> = self.freed_blocks.lock().iter().copied().collect();
+        block_cache_sync_all();
+        let mut freed_blocks = self.freed_blocks.lock();
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for block_id in freed {
+            // 期间又被分配的块已不在集合中
+            if !freed_blocks.remove(&block_id) {
+                continue;
+            }
+            let block_id = block_id as usize;
+            match ranges.last_mut() {
+                Some(range) if range.end == block_id => range.end += 1,
+                _ => ranges.push(block_id..block_id + 1),
+            }
+        }
+        for range in ranges {
+            self.block_device.discard(range);
+        }
     }
     /// Get the root inode of the filesystem
-    pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
-        let block_device = Arc::clone(&efs.lock().block_device);
-        // acquire efs lock temporarily
-        let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
-        // release efs lock
-        Inode::new(block_id, block_offset, Arc::clone(efs), block_device)
+    pub fn root_inode(efs: &Arc<Self>) -> Arc<Inode> {
+        efs.get_inode(0)
+    }
+    /// Whether a data block is allocated, `None` if it is outside every data area
+    fn is_data_allocated(&self, data_area: &DataArea, block_id: u32) -> Option<bool> {
+        if let Some(extent) = data_area.extents.iter().find(|extent| extent.contains(block_id)) {
+            let bit = (block_id - extent.area_start_block()) as usize;
+            return Some(Self::extent_bitmap(extent).is_allocated(&self.block_device, bit));
+        }
+        let bit = block_id.checked_sub(self.data_area_start_block)? as usize;
+        if bit >= data_area.bitmap.maximum() {
+            return None;
+        }
+        Some(data_area.bitmap.is_allocated(&self.block_device, bit))
+    }
+    /// Check that the directory tree agrees with the bitmaps and return the problems found,
+    /// nothing is repaired. The filesystem should not be modified meanwhile.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let inode_bitmap = self.inode_bitmap.lock();
+        let data_area = self.data_area.lock();
+        let inode_num = inode_bitmap.maximum() as u32;
+        // 从根目录出发遍历目录树，记录每个块属于哪个 inode
+        let mut reachable = BTreeSet::new();
+        let mut owners: BTreeMap<u32, u32> = BTreeMap::new();
+        let mut unlisted_dirs = false;
+        // 孤儿垫在栈底，在目录树之后遍历，已经从目录树到达的不算重复引用
+        let orphans: Vec<u32> = self
+            .orphans()
+            .iter()
+            .map(|orphan| orphan.inode_id)
+            .filter(|inode_id| *inode_id < inode_num)
+            .collect();
+        let mut stack = orphans.clone();
+        stack.push(0);
+        while let Some(inode_id) = stack.pop() {
+            if !reachable.insert(inode_id) {
+                if stack.len() >= orphans.len() {
+                    problems.push(format!("inode {} is linked more than once", inode_id));
+                }
+                continue;
+            }
+            if !inode_bitmap.is_allocated(&self.block_device, inode_id as usize) {
+                problems.push(format!("inode {} is reachable but free", inode_id));
+            }
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+            let disk_inode: DiskInode =
+                get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .get(block_offset);
+            for block_id in disk_inode.all_blocks(&self.block_device) {
+                if let Some(owner) = owners.insert(block_id, inode_id) {
+                    problems.push(format!(
+                        "block {} is used by both inode {} and inode {}",
+                        block_id, owner, inode_id
+                    ));
+                }
+                match self.is_data_allocated(&data_area, block_id) {
+                    None => problems.push(format!(
+                        "block {} of inode {} is outside the data area",
+                        block_id, inode_id
+                    )),
+                    Some(false) => problems.push(format!(
+                        "block {} of inode {} is free in the bitmap",
+                        block_id, inode_id
+                    )),
+                    Some(true) => {}
+                }
+            }
+            if !disk_inode.is_dir() {
+                continue;
+            }
+            let cipher = self.inode_cipher(inode_id);
+            if disk_inode.is_encrypted() && cipher.is_none() {
+                unlisted_dirs = true;
+                continue;
+            }
+            let mut bytes = [0u8; DIRENT_SZ];
+            for i in 0..disk_inode.size as usize / DIRENT_SZ {
+                disk_inode.read_at(i * DIRENT_SZ, &mut bytes, &self.block_device, cipher.as_ref());
+                let child = DirEntry::from_bytes(&bytes).inode_number();
+                if child >= inode_num {
+                    problems.push(format!(
+                        "entry {} of directory {} refers to inode {} out of range",
+                        i, inode_id, child
+                    ));
+                } else {
+                    stack.push(child);
+                }
+            }
+        }
+        // 有目录因缺少密钥无法列出时，无法判断 inode 和块是否被引用
+        if unlisted_dirs {
+            return problems;
+        }
+        for inode_id in 0..inode_num {
+            if !reachable.contains(&inode_id)
+                && inode_bitmap.is_allocated(&self.block_device, inode_id as usize)
+            {
+                problems.push(format!("inode {} is allocated but unreachable", inode_id));
+            }
+        }
+        let quota_blocks = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                let start_block = super_block.quota_start_block;
+                start_block..start_block + super_block.quota_blocks
+            });
+        let mut areas = alloc::vec![(self.data_area_start_block, data_area.bitmap.maximum() as u32)];
+        areas.extend(
+            data_area
+                .extents
+                .iter()
+                .map(|extent| (extent.area_start_block(), extent.area_blocks)),
+        );
+        for (start_block, blocks) in areas {
+            for block_id in start_block..start_block + blocks {
+                if !owners.contains_key(&block_id)
+                    && !quota_blocks.contains(&block_id)
+                    && self.is_data_allocated(&data_area, block_id) == Some(true)
+                {
+                    problems.push(format!("block {} is allocated but unused", block_id));
+                }
+            }
+        }
+        problems
+    }
+}
+
+impl SuperBlockOps for FileSystem {
+    fn fs_type(&self) -> &'static str {
+        "easy-fs"
+    }
+    fn block_size(&self) -> usize {
+        BLOCK_SZ
+    }
+    fn root_inode(self: Arc<Self>) -> Arc<dyn VfsInode> {
+        self.get_inode(0)
+    }
+    fn stat(&self) -> VfsStat {
+        let stat = FileSystem::stat(self);
+        VfsStat {
+            files: stat.inodes.into(),
+            free_files: stat.free_inodes.into(),
+            blocks: stat.data_blocks.into(),
+            free_blocks: stat.free_data_blocks.into(),
+        }
+    }
+    fn sync(&self) {
+        FileSystem::sync(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_dev::ram_device;
+    use crate::{
+        FaultyBlockDevice, RamBlockDevice, FEATURE_INCOMPAT_COMPRESSION, FEATURE_INCOMPAT_ORPHANS,
+    };
+
+    #[test]
+    fn create_then_open() {
+        let block_device = ram_device(4096);
+        let efs = FileSystem::create(Arc::clone(&block_device), 4096, 1);
+        efs.set_uuid([7; 16]);
+        efs.set_label("rootfs");
+        FileSystem::root_inode(&efs).create("hello").unwrap();
+        block_cache_sync_all();
+        drop(efs);
+        let efs = FileSystem::open(block_device);
+        assert_eq!(efs.uuid(), [7; 16]);
+        assert_eq!(efs.label(), "rootfs");
+        assert_eq!(efs.features(), (FEATURE_COMPAT_QUOTA, 0));
+        let root_inode = FileSystem::root_inode(&efs);
+        assert_eq!(root_inode.ls(), ["hello"]);
+        assert_eq!(efs.quota(0).unwrap().inodes_used, 2);
+    }
+
+    #[test]
+    fn open_reclaims_orphans() {
+        let block_device = ram_device(4096);
+        let efs = FileSystem::create(Arc::clone(&block_device), 4096, 1);
+        let root_inode = FileSystem::root_inode(&efs);
+        let free_inodes = efs.stat().free_inodes;
+        let kept = root_inode.create("kept").unwrap();
+        kept.write_at(0, &[1u8; 3000]);
+        let open = root_inode.create("open").unwrap();
+        open.write_at(0, &[2u8; 3000]);
+        assert!(root_inode.unlink("open"));
+        // 像创建中途崩溃那样留下记录，目录项却已写入
+        efs.add_orphan(Orphan {
+            inode_id: kept.inode_id(),
+            parent_id: 0,
+        });
+        assert_eq!(efs.orphans().len(), 2);
+        assert_eq!(efs.features().1, FEATURE_INCOMPAT_ORPHANS);
+        efs.sync();
+        // 文件仍打开时掉电
+        core::mem::forget(open);
+        drop((kept, root_inode, efs));
+        let efs = FileSystem::open(block_device);
+        assert!(efs.orphans().is_empty());
+        assert_eq!(efs.features(), (FEATURE_COMPAT_QUOTA, 0));
+        assert_eq!(FileSystem::root_inode(&efs).ls(), ["kept"]);
+        assert_eq!(efs.stat().free_inodes, free_inodes - 1);
+        assert!(efs.check().is_empty());
+    }
+
+    #[test]
+    fn alloc_and_dealloc() {
+        let efs = FileSystem::create(ram_device(4096), 4096, 1);
+        // inode 0 是根目录
+        assert_eq!(efs.alloc_inode(), 1);
+        assert_eq!(efs.alloc_inode(), 2);
+        efs.dealloc_inode(1);
+        assert_eq!(efs.alloc_inode(), 1);
+        let first = efs.alloc_data();
+        let second = efs.alloc_data();
+        assert_eq!(second, first + 1);
+        let stat = efs.stat();
+        assert_eq!(stat.inodes, 4096);
+        assert_eq!(stat.free_inodes, 4096 - 3);
+        // 配额表占用 4 块，另有刚分配的 2 块
+        assert_eq!(stat.free_data_blocks, stat.data_blocks - 4 - 2);
+        efs.dealloc_data(first);
+        assert_eq!(efs.alloc_data(), first);
+    }
+
+    /// Content of block `i` of the files written by [`fill`]
+    fn pattern(i: usize) -> [u8; BLOCK_SZ] {
+        [(i % 251) as u8 + 1; BLOCK_SZ]
+    }
+
+    /// Append blocks to `file` until the filesystem is nearly full, return how many were written.
+    /// 每次追加最多需要 3 块（数据块和两级索引块）
+    fn fill(efs: &FileSystem, file: &Inode) -> usize {
+        let mut blocks = 0;
+        while efs.stat().free_data_blocks >= 3 {
+            assert_eq!(file.write_at(blocks * BLOCK_SZ, &pattern(blocks)), BLOCK_SZ);
+            blocks += 1;
+        }
+        blocks
+    }
+
+    fn assert_content(file: &Inode, blocks: usize) {
+        assert_eq!(file.size(), blocks * BLOCK_SZ);
+        let mut buf = [0u8; BLOCK_SZ];
+        for i in 0..blocks {
+            file.read_at(i * BLOCK_SZ, &mut buf);
+            assert_eq!(buf, pattern(i), "block {}", i);
+        }
+    }
+
+    #[test]
+    fn grow_then_fill() {
+        let block_device = ram_device(8192);
+        let efs = FileSystem::create(Arc::clone(&block_device), 4096, 1);
+        let root_inode = FileSystem::root_inode(&efs);
+        let old_blocks = fill(&efs, &root_inode.create("old").unwrap());
+        // 先用完原数据位图的空余位，剩下的成为一个新的数据区
+        assert_eq!(efs.grow(8192), 8192);
+        assert_eq!(efs.data_area.lock().extents.len(), 1);
+        let free_data_blocks = efs.stat().free_data_blocks;
+        assert!(free_data_blocks > 4096 - 2);
+        let new = root_inode.create("new").unwrap();
+        let new_blocks = fill(&efs, &new);
+        let extent_start = efs.data_area.lock().extents[0].area_start_block();
+        let block_map = efs.disk_inode(new.inode_id()).block_map(&efs.block_device);
+        assert!(block_map.iter().any(|block_id| *block_id >= extent_start));
+        drop(new);
+        let free_data_blocks = efs.stat().free_data_blocks;
+        efs.sync();
+        drop(root_inode);
+        drop(efs);
+        let efs = FileSystem::open(block_device);
+        assert!(efs.check().is_empty(), "{:?}", efs.check());
+        assert_eq!(efs.stat().free_data_blocks, free_data_blocks);
+        let root_inode = FileSystem::root_inode(&efs);
+        assert_content(&root_inode.find("old").unwrap(), old_blocks);
+        assert_content(&root_inode.find("new").unwrap(), new_blocks);
+    }
+
+    /// 记录 discard 的块设备
+    struct DiscardLog {
+        inner: RamBlockDevice,
+        discards: Mutex<Vec<Range<usize>>>,
+    }
+
+    impl BlockDevice for DiscardLog {
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+            self.inner.read_block(block_id, buf);
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) {
+            self.inner.write_block(block_id, buf);
+        }
+        fn discard(&self, blocks: Range<usize>) {
+            self.discards.lock().push(blocks);
+        }
+    }
+
+    #[test]
+    fn freed_blocks_are_discarded() {
+        let log = Arc::new(DiscardLog {
+            inner: RamBlockDevice::new(4096),
+            discards: Mutex::new(Vec::new()),
+        });
+        let efs = FileSystem::create(Arc::clone(&log) as Arc<dyn BlockDevice>, 4096, 1);
+        // 创建时整个数据区被 discard
+        let data_area = efs.data_area_start_block as usize..4096;
+        assert_eq!(core::mem::take(&mut *log.discards.lock()), [data_area]);
+        let file = FileSystem::root_inode(&efs).create("file").unwrap();
+        file.write_at(0, &[1u8; 20 * BLOCK_SZ]);
+        let blocks = efs.disk_inode(file.inode_id()).block_map(&efs.block_device);
+        let first = blocks[0] as usize;
+        assert_eq!(blocks.len(), 20);
+        file.clear();
+        // 释放的块不再写零，连续的块合并成一次 discard
+        let freed = first..first + 20;
+        assert_eq!(core::mem::take(&mut *log.discards.lock()), [freed]);
+        // 重新分配的块读出 0
+        file.write_at(0, &[2u8; 100]);
+        let mut data = [0xffu8; BLOCK_SZ];
+        log.inner.read_block(first, &mut data);
+        assert!(data[..100].iter().all(|byte| *byte == 2));
+        assert!(data[100..].iter().all(|byte| *byte == 0));
+        // 同步之前又被分配的块不会被丢弃
+        let block_id = efs.alloc_data();
+        efs.dealloc_data(block_id);
+        assert_eq!(efs.alloc_data(), block_id);
+        efs.sync();
+        assert!(log.discards.lock().is_empty());
+        efs.dealloc_data(block_id);
+        assert!(efs.check().is_empty());
+    }
+
+    #[test]
+    fn grow_discards_the_new_blocks() {
+        // 镜像之后的块里有旧数据，扩容时不清零，分配时才清零
+        let mut image = alloc::vec![0u8; 4096 * BLOCK_SZ];
+        image.resize(8192 * BLOCK_SZ, 0xaa);
+        let log = Arc::new(DiscardLog {
+            inner: RamBlockDevice::from_bytes(image),
+            discards: Mutex::new(Vec::new()),
+        });
+        let block_device = Arc::new(FaultyBlockDevice::new(Arc::clone(&log) as _));
+        let efs = FileSystem::create(Arc::clone(&block_device) as _, 4096, 1);
+        log.discards.lock().clear();
+        let writes = block_device.write_count();
+        assert_eq!(efs.grow(8192), 8192);
+        // 只写超级块和新的位图块
+        let extent = efs.data_area.lock().extents[0];
+        assert_eq!(extent.bitmap_blocks, 1);
+        assert_eq!(block_device.write_count() - writes, 2);
+        let bitmap_start = extent.bitmap_start_block as usize;
+        assert_eq!(
+            *log.discards.lock(),
+            [4096..bitmap_start, bitmap_start + 1..8192]
+        );
+        let block_id = loop {
+            let block_id = efs.alloc_data();
+            if block_id >= extent.area_start_block() {
+                break block_id as usize;
+            }
+        };
+        block_cache_sync_all();
+        let mut data = [0xffu8; BLOCK_SZ];
+        log.inner.read_block(block_id, &mut data);
+        assert_eq!(data, [0u8; BLOCK_SZ]);
+    }
+
+    #[test]
+    fn usable_blocks() {
+        for (total_blocks, inode_bitmap_blocks) in [(4096, 1), (8192, 2), (20000, 1)] {
+            let efs = FileSystem::create(
+                ram_device(total_blocks as usize),
+                total_blocks,
+                inode_bitmap_blocks,
+            );
+            assert_eq!(
+                FileSystem::usable_blocks(total_blocks, inode_bitmap_blocks),
+                Some(efs.stat().free_data_blocks)
+            );
+        }
+        // 超级块、inode 位图与 inode 区共 1026 块，数据位图 1 块，配额表 4 块
+        assert_eq!(FileSystem::usable_blocks(1031, 1), Some(0));
+        assert_eq!(FileSystem::usable_blocks(1030, 1), None);
+        assert_eq!(FileSystem::usable_blocks(1000, 1), None);
+        FileSystem::create(ram_device(1031), 1031, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Error loading fs!")]
+    fn open_blank_device() {
+        FileSystem::open(ram_device(16));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported incompatible features")]
+    fn open_unknown_incompat_feature() {
+        let block_device = ram_device(4096);
+        let efs = FileSystem::create(Arc::clone(&block_device), 4096, 1);
+        efs.enable_features(0, FEATURE_INCOMPAT_COMPRESSION | 0x80);
+        FileSystem::open(block_device);
     }
 }
@@ -0,0 +1,401 @@
+//! 镜像的只读检查：各区域的位置、位图的占用和每个块的用途
+//!
+//! 只依据超级块和位图解释磁盘上的内容，不经过内存 inode 表，
+//! 供主机上的工具在内核出问题后查看镜像。
+
+use super::{get_block_cache, Bitmap, BlockDevice, DiskFormat, DiskInode, FileSystem, SuperBlock};
+use crate::verity::hash_tree_blocks;
+use crate::BLOCK_SZ;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// 每个位图块的位数
+const BLOCK_BITS: u32 = BLOCK_SZ as u32 * 8;
+
+/// Kind of a range of blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AreaKind {
+    /// The superblock
+    SuperBlock,
+    /// Inode bitmap
+    InodeBitmap,
+    /// Inode area
+    InodeArea,
+    /// Bitmap of the data area or of an extent
+    DataBitmap,
+    /// Data area or the data blocks of an extent
+    DataArea,
+    /// Hash tree appended after the filesystem
+    HashTree,
+}
+
+/// A range of blocks of the same kind
+#[derive(Clone, Copy, Debug)]
+pub struct Area {
+    /// What the blocks hold
+    pub kind: AreaKind,
+    /// Id of the first block
+    pub start_block: u32,
+    /// Number of blocks
+    pub blocks: u32,
+}
+
+/// Allocation state of one or more bitmaps
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BitmapUsage {
+    /// Number of bits that can be allocated
+    pub bits: u32,
+    /// Number of allocated bits
+    pub used: u32,
+    /// Number of runs of free bits, more runs for the same free space means more fragmentation
+    pub free_runs: u32,
+    /// Length of the longest run of free bits
+    pub largest_free_run: u32,
+}
+
+impl BitmapUsage {
+    fn add(&mut self, block_device: &Arc<dyn BlockDevice>, bitmap: &Bitmap) {
+        let (free_runs, largest_free_run) = bitmap.free_runs(block_device);
+        self.bits += bitmap.maximum() as u32;
+        self.used += bitmap.count_allocated(block_device) as u32;
+        self.free_runs += free_runs as u32;
+        self.largest_free_run = self.largest_free_run.max(largest_free_run as u32);
+    }
+}
+
+/// What a block of the image holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockRole {
+    /// The superblock
+    SuperBlock,
+    /// Inode bitmap, holding the bits from `first_bit` on
+    InodeBitmap {
+        /// First bit in the block
+        first_bit: u32,
+    },
+    /// Inode area, holding the inodes from `first_inode` on
+    InodeArea {
+        /// First inode in the block
+        first_inode: u32,
+    },
+    /// Bitmap of the data area or of an extent, holding the bits from `first_bit` on
+    DataBitmap {
+        /// First bit in the block
+        first_bit: u32,
+    },
+    /// 配额表
+    QuotaTable,
+    /// A data block of an inode
+    Data {
+        /// Owner of the block
+        inode: u32,
+        /// Position of the block in the file
+        inner_id: u32,
+    },
+    /// An index block of an inode
+    Index {
+        /// Owner of the block
+        inode: u32,
+        /// 2 for indirect2, 1 for indirect1 and the indirect1 blocks below indirect2
+        level: u8,
+    },
+    /// Allocated data block which no inode refers to
+    Unowned,
+    /// Free data block
+    Free,
+    /// Block of the hash tree
+    HashTree,
+    /// Block outside every area
+    Outside,
+}
+
+/// 数据区或扩容追加的数据区，连同它的位图
+struct DataBitmap {
+    bitmap: Bitmap,
+    bitmap_start_block: u32,
+    bitmap_blocks: u32,
+    area_start_block: u32,
+    area_blocks: u32,
+}
+
+impl DataBitmap {
+    fn new(bitmap_start_block: u32, bitmap_blocks: u32, area_blocks: u32) -> Self {
+        Self {
+            bitmap: Bitmap::with_maximum(
+                bitmap_start_block as usize,
+                bitmap_blocks as usize,
+                area_blocks as usize,
+            ),
+            bitmap_start_block,
+            bitmap_blocks,
+            area_start_block: bitmap_start_block + bitmap_blocks,
+            area_blocks,
+        }
+    }
+}
+
+fn data_bitmaps(super_block: &SuperBlock) -> Vec<DataBitmap> {
+    let mut v = alloc::vec![DataBitmap::new(
+        1 + super_block.inode_bitmap_blocks + super_block.inode_area_blocks,
+        super_block.data_bitmap_blocks,
+        super_block.data_area_blocks,
+    )];
+    v.extend(
+        super_block.data_extents[..super_block.data_extent_count as usize]
+            .iter()
+            .map(|extent| {
+                DataBitmap::new(
+                    extent.bitmap_start_block,
+                    extent.bitmap_blocks,
+                    extent.area_blocks,
+                )
+            }),
+    );
+    v
+}
+
+impl FileSystem {
+    /// Read the superblock of a device without opening it, so it may well be invalid
+    pub fn read_superblock(block_device: &Arc<dyn BlockDevice>) -> SuperBlock {
+        get_block_cache(0, Arc::clone(block_device)).lock().get(0)
+    }
+    /// Get a copy of the superblock
+    pub fn superblock(&self) -> SuperBlock {
+        Self::read_superblock(&self.block_device)
+    }
+    /// Return the areas of the image, extents after the data area they were added to
+    pub fn areas(&self) -> Vec<Area> {
+        let super_block = self.superblock();
+        let area = |kind, start_block, blocks| Area {
+            kind,
+            start_block,
+            blocks,
+        };
+        let inode_area_start_block = 1 + super_block.inode_bitmap_blocks;
+        let mut v = alloc::vec![
+            area(AreaKind::SuperBlock, 0, 1),
+            area(AreaKind::InodeBitmap, 1, super_block.inode_bitmap_blocks),
+            area(
+                AreaKind::InodeArea,
+                inode_area_start_block,
+                super_block.inode_area_blocks
+            ),
+        ];
+        for data_bitmap in data_bitmaps(&super_block) {
+            v.push(area(
+                AreaKind::DataBitmap,
+                data_bitmap.bitmap_start_block,
+                data_bitmap.bitmap_blocks,
+            ));
+            v.push(area(
+                AreaKind::DataArea,
+                data_bitmap.area_start_block,
+                data_bitmap.area_blocks,
+            ));
+        }
+        if super_block.verity_levels != 0 {
+            v.push(area(
+                AreaKind::HashTree,
+                super_block.total_blocks,
+                hash_tree_blocks(super_block.total_blocks),
+            ));
+        }
+        v
+    }
+    /// Get the allocation state of the inode bitmap
+    pub fn inode_usage(&self) -> BitmapUsage {
+        let super_block = self.superblock();
+        let mut usage = BitmapUsage::default();
+        usage.add(
+            &self.block_device,
+            &Bitmap::new(1, super_block.inode_bitmap_blocks as usize),
+        );
+        usage
+    }
+    /// Get the allocation state of the data bitmaps, extents included
+    pub fn data_usage(&self) -> BitmapUsage {
+        let mut usage = BitmapUsage::default();
+        for data_bitmap in data_bitmaps(&self.superblock()) {
+            usage.add(&self.block_device, &data_bitmap.bitmap);
+        }
+        usage
+    }
+    /// Return the allocated inodes in order
+    pub fn allocated_inodes(&self) -> Vec<u32> {
+        let super_block = self.superblock();
+        let inode_bitmap = Bitmap::new(1, super_block.inode_bitmap_blocks as usize);
+        (0..inode_bitmap.maximum())
+            .filter(|bit| inode_bitmap.is_allocated(&self.block_device, *bit))
+            .map(|bit| bit as u32)
+            .collect()
+    }
+    /// Read an inode as stored on disk, whether it is allocated or not
+    pub fn disk_inode(&self, inode_id: u32) -> DiskInode {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .get(block_offset)
+    }
+    /// Read the bytes of an inode as stored on disk, to look at an inode that does not decode
+    pub fn raw_disk_inode(&self, inode_id: u32) -> Vec<u8> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |block: &[u8; BLOCK_SZ]| {
+                block[block_offset..block_offset + DiskInode::DISK_SZ].to_vec()
+            })
+    }
+    /// Map the data and index blocks of every allocated inode to their role
+    pub fn block_owners(&self) -> BTreeMap<u32, BlockRole> {
+        let mut owners = BTreeMap::new();
+        for inode in self.allocated_inodes() {
+            let disk_inode = self.disk_inode(inode);
+            for (inner_id, block_id) in disk_inode
+                .block_map(&self.block_device)
+                .into_iter()
+                .enumerate()
+            {
+                if block_id != 0 {
+                    let inner_id = inner_id as u32;
+                    owners.insert(block_id, BlockRole::Data { inode, inner_id });
+                }
+            }
+            for block_id in disk_inode.index_blocks(&self.block_device) {
+                let level = if block_id == disk_inode.indirect2 {
+                    2
+                } else {
+                    1
+                };
+                owners.insert(block_id, BlockRole::Index { inode, level });
+            }
+        }
+        owners
+    }
+    /// Get the role of a block, `owners` is the map returned by [`FileSystem::block_owners`]
+    pub fn block_role(&self, block_id: u32, owners: &BTreeMap<u32, BlockRole>) -> BlockRole {
+        let super_block = self.superblock();
+        let inode_area_start_block = 1 + super_block.inode_bitmap_blocks;
+        let inodes_per_block = (BLOCK_SZ / DiskInode::DISK_SZ) as u32;
+        if block_id == 0 {
+            return BlockRole::SuperBlock;
+        }
+        if block_id < inode_area_start_block {
+            let first_bit = (block_id - 1) * BLOCK_BITS;
+            return BlockRole::InodeBitmap { first_bit };
+        }
+        if block_id < inode_area_start_block + super_block.inode_area_blocks {
+            let first_inode = (block_id - inode_area_start_block) * inodes_per_block;
+            return BlockRole::InodeArea { first_inode };
+        }
+        if let Some(role) = owners.get(&block_id) {
+            return *role;
+        }
+        let quota_blocks =
+            super_block.quota_start_block..super_block.quota_start_block + super_block.quota_blocks;
+        for data_bitmap in data_bitmaps(&super_block) {
+            let bitmap_end_block = data_bitmap.bitmap_start_block + data_bitmap.bitmap_blocks;
+            if (data_bitmap.bitmap_start_block..bitmap_end_block).contains(&block_id) {
+                let first_bit = (block_id - data_bitmap.bitmap_start_block) * BLOCK_BITS;
+                return BlockRole::DataBitmap { first_bit };
+            }
+            let bit = match block_id.checked_sub(data_bitmap.area_start_block) {
+                Some(bit) if bit < data_bitmap.area_blocks => bit as usize,
+                _ => continue,
+            };
+            return if quota_blocks.contains(&block_id) {
+                BlockRole::QuotaTable
+            } else if data_bitmap.bitmap.is_allocated(&self.block_device, bit) {
+                BlockRole::Unowned
+            } else {
+                BlockRole::Free
+            };
+        }
+        let hash_tree_end_block =
+            super_block.total_blocks + hash_tree_blocks(super_block.total_blocks);
+        if super_block.verity_levels != 0
+            && (super_block.total_blocks..hash_tree_end_block).contains(&block_id)
+        {
+            return BlockRole::HashTree;
+        }
+        BlockRole::Outside
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RamBlockDevice;
+
+    #[test]
+    fn areas_cover_the_image() {
+        let efs = FileSystem::create(Arc::new(RamBlockDevice::new(4096)), 4096, 1);
+        let areas = efs.areas();
+        let mut next_block = 0;
+        for area in areas.iter() {
+            assert_eq!(area.start_block, next_block);
+            next_block += area.blocks;
+        }
+        assert_eq!(next_block, 4096);
+        let stat = efs.stat();
+        let inode_usage = efs.inode_usage();
+        assert_eq!(inode_usage.bits - inode_usage.used, stat.free_inodes);
+        assert_eq!(
+            efs.data_usage().bits - efs.data_usage().used,
+            stat.free_data_blocks
+        );
+        assert_eq!(efs.allocated_inodes(), [0]);
+    }
+
+    #[test]
+    fn roles_of_blocks() {
+        let efs = FileSystem::create(Arc::new(RamBlockDevice::new(4096)), 4096, 1);
+        let root_inode = FileSystem::root_inode(&efs);
+        let a = root_inode.create("a").unwrap();
+        a.write_at(0, &[1u8; 40 * BLOCK_SZ]);
+        let b = root_inode.create("b").unwrap();
+        b.write_at(0, &[2u8; BLOCK_SZ]);
+        root_inode
+            .create("c")
+            .unwrap()
+            .write_at(0, &[3u8; BLOCK_SZ]);
+        assert_eq!(efs.data_usage().free_runs, 1);
+        drop(b);
+        root_inode.unlink("b");
+        // 删除 b 在已用的块之间留下一个空洞
+        assert_eq!(efs.data_usage().free_runs, 2);
+
+        let owners = efs.block_owners();
+        let disk_inode = efs.disk_inode(a.inode_id());
+        assert_eq!(
+            efs.block_role(disk_inode.direct[3], &owners),
+            BlockRole::Data {
+                inode: a.inode_id(),
+                inner_id: 3
+            }
+        );
+        assert_eq!(
+            efs.block_role(disk_inode.indirect1, &owners),
+            BlockRole::Index {
+                inode: a.inode_id(),
+                level: 1
+            }
+        );
+        let super_block = efs.superblock();
+        assert_eq!(efs.block_role(0, &owners), BlockRole::SuperBlock);
+        assert_eq!(
+            efs.block_role(1, &owners),
+            BlockRole::InodeBitmap { first_bit: 0 }
+        );
+        assert_eq!(
+            efs.block_role(3, &owners),
+            BlockRole::InodeArea { first_inode: 4 }
+        );
+        assert_eq!(
+            efs.block_role(super_block.quota_start_block, &owners),
+            BlockRole::QuotaTable
+        );
+        assert_eq!(efs.block_role(4095, &owners), BlockRole::Free);
+        assert_eq!(efs.block_role(4096, &owners), BlockRole::Outside);
+    }
+}
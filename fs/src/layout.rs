@@ -1,10 +1,35 @@
-use super::{get_block_cache, BlockDevice, BLOCK_SZ};
+use super::{
+    compress, get_block_cache, BlockDevice, Decoder, DiskFormat, Encoder, InodeCipher, BLOCK_SZ,
+};
+use crate::compress::CLUSTER_BLOCKS;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::fmt::{Debug, Formatter, Result};
+use core::fmt::Debug;
+use core::ops::Range;
 
 /// the magic number for the Easy File System (EFS)
 const EFS_MAGIC: u32 = 0x12345678;
+/// Version of the on-disk format
+pub const FORMAT_VERSION: u32 = 1;
+/// 超级块中有配额表，不认识该特性的实现不会更新用量
+pub const FEATURE_COMPAT_QUOTA: u32 = 1 << 0;
+/// 镜像末尾有哈希树
+pub const FEATURE_COMPAT_VERITY: u32 = 1 << 1;
+/// 有压缩存储的文件
+pub const FEATURE_INCOMPAT_COMPRESSION: u32 = 1 << 0;
+/// 有加密存储的文件或目录
+pub const FEATURE_INCOMPAT_ENCRYPTION: u32 = 1 << 1;
+/// 扩容追加过数据区
+pub const FEATURE_INCOMPAT_DATA_EXTENTS: u32 = 1 << 2;
+/// 孤儿列表非空，不认识该特性的实现可能复用其中的 inode
+pub const FEATURE_INCOMPAT_ORPHANS: u32 = 1 << 3;
+/// Incompatible features this implementation understands
+pub const SUPPORTED_INCOMPAT_FEATURES: u32 = FEATURE_INCOMPAT_COMPRESSION
+    | FEATURE_INCOMPAT_ENCRYPTION
+    | FEATURE_INCOMPAT_DATA_EXTENTS
+    | FEATURE_INCOMPAT_ORPHANS;
+/// Length of the volume label
+pub const LABEL_SZ: usize = 16;
 const INODE_DIRECT_COUNT: usize = 28;
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
@@ -12,14 +37,116 @@ const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COU
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 type IndirectBlock = [u32; BLOCK_SZ / 4];
 type DataBlock = [u8; BLOCK_SZ];
-#[repr(C)]
+/// Maximum number of data extents added by growing the filesystem
+pub const MAX_DATA_EXTENTS: usize = 8;
+/// Maximum number of inodes in the orphan list of the superblock
+pub const MAX_ORPHANS: usize = 32;
+
+/// 扩容时追加在镜像末尾的数据区，由自己的位图和紧随其后的数据块组成
+#[derive(Clone, Copy, Default)]
+pub struct DataExtent {
+    /// 位图的起始块号
+    pub bitmap_start_block: u32,
+    /// 位图占用的块数
+    pub bitmap_blocks: u32,
+    /// 数据块数
+    pub area_blocks: u32,
+}
+
+impl DataExtent {
+    /// Id of the first data block of the extent
+    pub fn area_start_block(&self) -> u32 {
+        self.bitmap_start_block + self.bitmap_blocks
+    }
+    /// Whether the block belongs to the data area of the extent
+    pub fn contains(&self, block_id: u32) -> bool {
+        let start = self.area_start_block();
+        block_id >= start && block_id < start + self.area_blocks
+    }
+}
+
+impl DiskFormat for DataExtent {
+    const DISK_SZ: usize = 12;
+    fn decode(bytes: &[u8]) -> Self {
+        let mut d = Decoder::new(bytes);
+        Self {
+            bitmap_start_block: d.get(),
+            bitmap_blocks: d.get(),
+            area_blocks: d.get(),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let mut e = Encoder::new(bytes);
+        e.put(&self.bitmap_start_block);
+        e.put(&self.bitmap_blocks);
+        e.put(&self.area_blocks);
+    }
+}
+
+/// 孤儿列表的表项：正在创建的 inode，或已删除但仍被打开的 inode
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Orphan {
+    /// 孤儿 inode 的编号
+    pub inode_id: u32,
+    /// 创建或删除它的目录，挂载时该目录仍引用它就不回收
+    pub parent_id: u32,
+}
+
+impl DiskFormat for Orphan {
+    const DISK_SZ: usize = 8;
+    fn decode(bytes: &[u8]) -> Self {
+        let mut d = Decoder::new(bytes);
+        Self {
+            inode_id: d.get(),
+            parent_id: d.get(),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let mut e = Encoder::new(bytes);
+        e.put(&self.inode_id);
+        e.put(&self.parent_id);
+    }
+}
+
+/// The superblock stored in block 0
 pub struct SuperBlock {
     magic: u32,
+    /// 格式版本
+    pub version: u32,
+    /// 不认识也可以读写的特性
+    pub compat_features: u32,
+    /// 不认识就不能挂载的特性
+    pub incompat_features: u32,
+    /// 文件系统占用的块数，不含哈希树
     pub total_blocks: u32,
+    /// inode 位图的块数
     pub inode_bitmap_blocks: u32,
+    /// inode 区的块数
     pub inode_area_blocks: u32,
+    /// 数据位图的块数
     pub data_bitmap_blocks: u32,
+    /// 数据区的块数
     pub data_area_blocks: u32,
+    /// 卷的唯一标识
+    pub uuid: [u8; 16],
+    /// 卷标，不足部分以 0 填充
+    pub label: [u8; LABEL_SZ],
+    /// 配额表的起始块号
+    pub quota_start_block: u32,
+    /// 配额表占用的块数，0 表示没有配额表
+    pub quota_blocks: u32,
+    /// 扩容追加的数据区个数
+    pub data_extent_count: u32,
+    /// 扩容追加的数据区
+    pub data_extents: [DataExtent; MAX_DATA_EXTENTS],
+    /// 哈希树层数，0 表示镜像没有哈希树
+    pub verity_levels: u32,
+    /// 哈希树根哈希
+    pub verity_root: [u8; 32],
+    orphan_count: u32,
+    orphans: [Orphan; MAX_ORPHANS],
+    /// 加密主密钥的校验值，全 0 表示还没有提供过密钥，见 [`crate::crypt::key_check`]
+    pub key_check: [u8; 32],
 }
 impl SuperBlock {
     /// Initialize a new super block with the given parameters
@@ -33,33 +160,210 @@ impl SuperBlock {
     ) {
         *self = Self {
             magic: EFS_MAGIC,
+            version: FORMAT_VERSION,
+            compat_features: 0,
+            incompat_features: 0,
             total_blocks,
             inode_bitmap_blocks,
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            uuid: [0; 16],
+            label: [0; LABEL_SZ],
+            quota_start_block: 0,
+            quota_blocks: 0,
+            data_extent_count: 0,
+            data_extents: [DataExtent::default(); MAX_DATA_EXTENTS],
+            verity_levels: 0,
+            verity_root: [0; 32],
+            orphan_count: 0,
+            orphans: [Orphan::default(); MAX_ORPHANS],
+            key_check: [0; 32],
         }
     }
+    /// Get the magic number, which is only checked by [`SuperBlock::is_valid`]
+    pub fn magic(&self) -> u32 {
+        self.magic
+    }
     /// check if the super block is valid
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+    /// Incompatible features in use which this implementation does not understand
+    pub fn unsupported_features(&self) -> u32 {
+        self.incompat_features & !SUPPORTED_INCOMPAT_FEATURES
+    }
+    /// Byte range of the hash tree fields, which the superblock hash leaves out
+    pub fn verity_range() -> Range<usize> {
+        // verity_levels 与 verity_root 之后是孤儿列表和密钥校验值
+        let end = Self::DISK_SZ - 4 - MAX_ORPHANS * Orphan::DISK_SZ - 32;
+        end - 4 - 32..end
+    }
+    /// Inodes to be reclaimed on the next mount unless their directory still refers to them
+    pub fn orphans(&self) -> &[Orphan] {
+        &self.orphans[..self.orphan_count as usize]
+    }
+    /// Add an entry to the orphan list, return false if it is full
+    pub fn add_orphan(&mut self, orphan: Orphan) -> bool {
+        if self.orphan_count as usize == MAX_ORPHANS {
+            return false;
+        }
+        self.orphans[self.orphan_count as usize] = orphan;
+        self.orphan_count += 1;
+        self.incompat_features |= FEATURE_INCOMPAT_ORPHANS;
+        true
+    }
+    /// Remove an entry from the orphan list, return false if it is not listed
+    pub fn remove_orphan(&mut self, orphan: Orphan) -> bool {
+        let count = self.orphan_count as usize;
+        let pos = match self.orphans[..count].iter().position(|other| *other == orphan) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        self.orphans.copy_within(pos + 1..count, pos);
+        self.orphan_count -= 1;
+        if self.orphan_count == 0 {
+            self.incompat_features &= !FEATURE_INCOMPAT_ORPHANS;
+        }
+        true
+    }
+}
+
+impl DiskFormat for SuperBlock {
+    const DISK_SZ: usize = 14 * 4
+        + 16
+        + LABEL_SZ
+        + MAX_DATA_EXTENTS * DataExtent::DISK_SZ
+        + 32
+        + MAX_ORPHANS * Orphan::DISK_SZ
+        + 32;
+    fn decode(bytes: &[u8]) -> Self {
+        let mut d = Decoder::new(bytes);
+        Self {
+            magic: d.get(),
+            version: d.get(),
+            compat_features: d.get(),
+            incompat_features: d.get(),
+            total_blocks: d.get(),
+            inode_bitmap_blocks: d.get(),
+            inode_area_blocks: d.get(),
+            data_bitmap_blocks: d.get(),
+            data_area_blocks: d.get(),
+            uuid: d.get(),
+            label: d.get(),
+            quota_start_block: d.get(),
+            quota_blocks: d.get(),
+            data_extent_count: d.get(),
+            data_extents: d.get(),
+            verity_levels: d.get(),
+            verity_root: d.get(),
+            orphan_count: d.get(),
+            orphans: d.get(),
+            key_check: d.get(),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let mut e = Encoder::new(bytes);
+        e.put(&self.magic);
+        e.put(&self.version);
+        e.put(&self.compat_features);
+        e.put(&self.incompat_features);
+        e.put(&self.total_blocks);
+        e.put(&self.inode_bitmap_blocks);
+        e.put(&self.inode_area_blocks);
+        e.put(&self.data_bitmap_blocks);
+        e.put(&self.data_area_blocks);
+        e.put(&self.uuid);
+        e.put(&self.label);
+        e.put(&self.quota_start_block);
+        e.put(&self.quota_blocks);
+        e.put(&self.data_extent_count);
+        e.put(&self.data_extents);
+        e.put(&self.verity_levels);
+        e.put(&self.verity_root);
+        e.put(&self.orphan_count);
+        e.put(&self.orphans);
+        e.put(&self.key_check);
+    }
 }
 
 /// Type of a disk inode
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DiskInodeType {
+    /// 普通文件
     File,
+    /// 目录
     Directory,
+    /// 损坏的镜像中无法识别的类型字节，这样的 inode 不可访问
+    Unknown(u8),
+}
+
+impl DiskFormat for DiskInodeType {
+    const DISK_SZ: usize = 1;
+    fn decode(bytes: &[u8]) -> Self {
+        match bytes[0] {
+            0 => Self::File,
+            1 => Self::Directory,
+            type_ => Self::Unknown(type_),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes[0] = match self {
+            Self::File => 0,
+            Self::Directory => 1,
+            Self::Unknown(type_) => *type_,
+        };
+    }
 }
 
-#[repr(C)]
+/// An inode as stored in the inode area
 pub struct DiskInode {
+    /// Size of the data in bytes
     pub size: u32,
+    /// 直接索引的数据块
     pub direct: [u32; INODE_DIRECT_COUNT],
+    /// 一级间接索引块
     pub indirect1: u32,
+    /// 二级间接索引块
     pub indirect2: u32,
     type_: DiskInodeType,
+    flags: u8,
+    uid: u16,
+}
+
+/// 文件数据按簇压缩存储，见 `compress` 模块
+pub const INODE_FLAG_COMPRESSED: u8 = 1 << 0;
+/// 文件数据加密存储；对目录表示在其中新建的 inode 都加密，见 `crypt` 模块
+pub const INODE_FLAG_ENCRYPTED: u8 = 1 << 1;
+/// 目录项（包括文件名）加密存储，只用于目录
+pub const INODE_FLAG_NAMES_ENCRYPTED: u8 = 1 << 2;
+/// 块映射越过文件大小，一直延伸到第一个为 0 的表项，见 [`DiskInode::preallocate`]
+pub const INODE_FLAG_PREALLOCATED: u8 = 1 << 3;
+
+impl DiskFormat for DiskInode {
+    const DISK_SZ: usize = 128;
+    fn decode(bytes: &[u8]) -> Self {
+        let mut d = Decoder::new(bytes);
+        Self {
+            size: d.get(),
+            direct: d.get(),
+            indirect1: d.get(),
+            indirect2: d.get(),
+            type_: d.get(),
+            flags: d.get(),
+            uid: d.get(),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let mut e = Encoder::new(bytes);
+        e.put(&self.size);
+        e.put(&self.direct);
+        e.put(&self.indirect1);
+        e.put(&self.indirect2);
+        e.put(&self.type_);
+        e.put(&self.flags);
+        e.put(&self.uid);
+    }
 }
 
 impl DiskInode {
@@ -70,6 +374,24 @@ impl DiskInode {
         self.indirect1 = 0;
         self.indirect2 = 0;
         self.type_ = type_;
+        self.flags = 0;
+        self.uid = 0;
+    }
+    /// Get the raw flags of this inode
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+    /// Get the owner of this inode
+    pub fn uid(&self) -> u16 {
+        self.uid
+    }
+    /// Set the owner of this inode
+    pub fn set_uid(&mut self, uid: u16) {
+        self.uid = uid;
+    }
+    /// Get the type of this inode
+    pub fn type_(&self) -> DiskInodeType {
+        self.type_
     }
     /// Whether this inode is a directory
     pub fn is_dir(&self) -> bool {
@@ -80,6 +402,171 @@ impl DiskInode {
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
+    /// Whether the data of this inode is stored compressed
+    pub fn is_compressed(&self) -> bool {
+        self.flags & INODE_FLAG_COMPRESSED != 0
+    }
+    /// Turn compression on or off, only allowed while the inode holds no data
+    pub fn set_compressed(&mut self, compressed: bool) -> bool {
+        if self.size != 0 || self.is_preallocated() || !self.is_file() {
+            return false;
+        }
+        if compressed {
+            self.flags |= INODE_FLAG_COMPRESSED;
+        } else {
+            self.flags &= !INODE_FLAG_COMPRESSED;
+        }
+        true
+    }
+    /// Whether the data of this inode is stored encrypted
+    pub fn is_encrypted(&self) -> bool {
+        if self.is_dir() {
+            self.flags & INODE_FLAG_NAMES_ENCRYPTED != 0
+        } else {
+            self.flags & INODE_FLAG_ENCRYPTED != 0
+        }
+    }
+    /// Whether inodes created under this directory are encrypted
+    pub fn encrypts_children(&self) -> bool {
+        self.is_dir() && self.flags & INODE_FLAG_ENCRYPTED != 0
+    }
+    /// Turn on encryption, only allowed while the inode holds no data.
+    /// For a directory, `names` also encrypts its own entries.
+    pub fn set_encrypted(&mut self, names: bool) -> bool {
+        if self.size != 0 || self.is_preallocated() {
+            return false;
+        }
+        self.flags |= INODE_FLAG_ENCRYPTED;
+        if names && self.is_dir() {
+            self.flags |= INODE_FLAG_NAMES_ENCRYPTED;
+        }
+        true
+    }
+    /// Whether blocks are mapped past the size of this inode
+    pub fn is_preallocated(&self) -> bool {
+        self.flags & INODE_FLAG_PREALLOCATED != 0
+    }
+    /// Return number of blocks allocated to this inode, including index blocks.
+    /// 压缩文件的块映射中有空洞，需要逐项统计
+    pub fn allocated_blocks(&self, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        let map_blocks = self.mapped_blocks(block_device);
+        let index_blocks = Self::total_blocks_of(map_blocks) - map_blocks;
+        if !self.is_compressed() {
+            return map_blocks + index_blocks;
+        }
+        let data_blocks = (0..map_blocks)
+            .filter(|inner_id| self.get_block_id(*inner_id, block_device) != 0)
+            .count() as u32;
+        data_blocks + index_blocks
+    }
+    /// Return all blocks of this inode, index blocks included and holes skipped
+    pub fn all_blocks(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let mut v: Vec<u32> = self
+            .block_map(block_device)
+            .into_iter()
+            .filter(|block_id| *block_id != 0)
+            .collect();
+        v.extend(self.index_blocks(block_device));
+        v
+    }
+    /// Return the data block of every inner id in use, 0 for holes
+    pub fn block_map(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        (0..self.mapped_blocks(block_device))
+            .map(|inner_id| self.get_block_id(inner_id, block_device))
+            .collect()
+    }
+    /// Return the index blocks in use: indirect1, indirect2 and the indirect1 blocks below it
+    pub fn index_blocks(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let map_blocks = self.mapped_blocks(block_device) as usize;
+        let mut v = Vec::new();
+        if map_blocks > INODE_DIRECT_COUNT {
+            v.push(self.indirect1);
+        }
+        if map_blocks > INDIRECT1_BOUND {
+            v.push(self.indirect2);
+            let indirect1_count =
+                (map_blocks - INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
+            get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    v.extend_from_slice(&indirect2[..indirect1_count]);
+                });
+        }
+        v
+    }
+    /// Return the data and index blocks in the order [`DiskInode::increase_size`] takes them,
+    /// holes skipped
+    pub fn blocks_in_order(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let mut v = Vec::new();
+        for inner_id in 0..self.mapped_blocks(block_device) as usize {
+            if inner_id == INODE_DIRECT_COUNT {
+                v.push(self.indirect1);
+            }
+            if inner_id == INDIRECT1_BOUND {
+                v.push(self.indirect2);
+            }
+            // indirect2 之下的每个一级索引块排在它索引的第一个数据块之前
+            if inner_id >= INDIRECT1_BOUND {
+                let last = inner_id - INDIRECT1_BOUND;
+                let (a, b) = (last / INODE_INDIRECT1_COUNT, last % INODE_INDIRECT1_COUNT);
+                if b == 0 {
+                    get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                        .lock()
+                        .read(0, |indirect2: &IndirectBlock| v.push(indirect2[a]));
+                }
+            }
+            let block_id = self.get_block_id(inner_id as u32, block_device);
+            if block_id != 0 {
+                v.push(block_id);
+            }
+        }
+        v
+    }
+    /// Replace every block of this inode by `new_block_id` of it, in this inode and in the
+    /// index blocks. The index blocks must already have been copied to their new place.
+    pub fn relocate(
+        &mut self,
+        new_block_id: impl Fn(u32) -> u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let map_blocks = self.mapped_blocks(block_device) as usize;
+        // 压缩文件的空洞保持为 0
+        let remap = |block_id: &mut u32| {
+            if *block_id != 0 {
+                *block_id = new_block_id(*block_id);
+            }
+        };
+        self.direct[..map_blocks.min(INODE_DIRECT_COUNT)]
+            .iter_mut()
+            .for_each(remap);
+        if map_blocks > INODE_DIRECT_COUNT {
+            self.indirect1 = new_block_id(self.indirect1);
+            let count = (map_blocks - INODE_DIRECT_COUNT).min(INODE_INDIRECT1_COUNT);
+            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect1: &mut IndirectBlock| {
+                    indirect1[..count].iter_mut().for_each(remap);
+                });
+        }
+        if map_blocks > INDIRECT1_BOUND {
+            self.indirect2 = new_block_id(self.indirect2);
+            let last = map_blocks - INDIRECT1_BOUND;
+            let indirect1_count = last.div_ceil(INODE_INDIRECT1_COUNT);
+            get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect2: &mut IndirectBlock| {
+                    for (i, entry) in indirect2[..indirect1_count].iter_mut().enumerate() {
+                        *entry = new_block_id(*entry);
+                        let count = (last - i * INODE_INDIRECT1_COUNT).min(INODE_INDIRECT1_COUNT);
+                        get_block_cache(*entry as usize, Arc::clone(block_device))
+                            .lock()
+                            .modify(0, |indirect1: &mut IndirectBlock| {
+                                indirect1[..count].iter_mut().for_each(remap);
+                            });
+                    }
+                });
+        }
+    }
     /// Get id of block given inner id
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
         let inner_id = inner_id as usize;
@@ -105,8 +592,76 @@ impl DiskInode {
                 })
         }
     }
+    /// Replace the block behind an inner id, the index blocks must already exist
+    pub fn set_block_id(&mut self, inner_id: u32, block_id: u32, block_device: &Arc<dyn BlockDevice>) {
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id] = block_id;
+        } else if inner_id < INDIRECT1_BOUND {
+            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect_block: &mut IndirectBlock| {
+                    indirect_block[inner_id - INODE_DIRECT_COUNT] = block_id;
+                });
+        } else {
+            let last = inner_id - INDIRECT1_BOUND;
+            let indirect1 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    indirect2[last / INODE_INDIRECT1_COUNT]
+                });
+            get_block_cache(indirect1 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect1: &mut IndirectBlock| {
+                    indirect1[last % INODE_INDIRECT1_COUNT] = block_id;
+                });
+        }
+    }
     fn _data_blocks(size: u32) -> u32 {
-        (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
+        size.div_ceil(BLOCK_SZ as u32)
+    }
+    /// Return number of block map entries covered by the size.
+    fn map_blocks(&self) -> u32 {
+        self.map_blocks_of(self.data_blocks())
+    }
+    /// Return number of block map entries in use, preallocated blocks past the size included
+    pub fn mapped_blocks(&self, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        let mut blocks = self.map_blocks();
+        if !self.is_preallocated() {
+            return blocks;
+        }
+        // 新分配的索引块全为 0，映射在第一个空表项处结束
+        while (blocks as usize) < INDIRECT1_BOUND + INODE_INDIRECT2_COUNT {
+            let inner_id = blocks as usize;
+            let index_missing = if inner_id == DIRECT_BOUND {
+                self.indirect1 == 0
+            } else if inner_id == INDIRECT1_BOUND {
+                self.indirect2 == 0
+            } else if inner_id > INDIRECT1_BOUND {
+                let last = inner_id - INDIRECT1_BOUND;
+                let (a, b) = (last / INODE_INDIRECT1_COUNT, last % INODE_INDIRECT1_COUNT);
+                b == 0
+                    && get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                        .lock()
+                        .read(0, |indirect2: &IndirectBlock| indirect2[a] == 0)
+            } else {
+                false
+            };
+            if index_missing || self.get_block_id(blocks, block_device) == 0 {
+                break;
+            }
+            blocks += 1;
+        }
+        blocks
+    }
+    /// 压缩文件的块映射总是覆盖整簇
+    fn map_blocks_of(&self, blocks: u32) -> u32 {
+        if self.is_compressed() {
+            let cluster_blocks = CLUSTER_BLOCKS as u32;
+            blocks.div_ceil(cluster_blocks) * cluster_blocks
+        } else {
+            blocks
+        }
     }
     /// Return block number correspond to size.
     pub fn data_blocks(&self) -> u32 {
@@ -114,8 +669,11 @@ impl DiskInode {
     }
     /// Return number of blocks needed include indirect1/2.
     pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
+        Self::total_blocks_of(Self::_data_blocks(size))
+    }
+    fn total_blocks_of(data_blocks: u32) -> u32 {
+        let data_blocks = data_blocks as usize;
+        let mut total = data_blocks;
         // indirect1
         if data_blocks > INODE_DIRECT_COUNT {
             total += 1;
@@ -124,15 +682,25 @@ impl DiskInode {
         if data_blocks > INDIRECT1_BOUND {
             total += 1;
             // sub indirect1
-            total +=
-                (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+            total += (data_blocks - INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
         }
         total as u32
     }
     /// Get the number of data blocks that have to be allocated given the new size of data
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
+    /// Data blocks of compressed inodes are allocated per cluster on write, so only index blocks are counted for them.
+    /// Preallocated blocks already cover part of the new size.
+    pub fn blocks_num_needed(&self, new_size: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
         assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+        let old_blocks = self.mapped_blocks(block_device);
+        let new_blocks = self
+            .map_blocks_of(Self::_data_blocks(new_size))
+            .max(old_blocks);
+        let needed = Self::total_blocks_of(new_blocks) - Self::total_blocks_of(old_blocks);
+        if self.is_compressed() {
+            needed - (new_blocks - old_blocks)
+        } else {
+            needed
+        }
     }
     /// Inncrease the size of current disk inode
     pub fn increase_size(
@@ -141,13 +709,56 @@ impl DiskInode {
         new_blocks: Vec<u32>,
         block_device: &Arc<dyn BlockDevice>,
     ) {
-        let mut current_blocks = self.data_blocks();
+        let current_blocks = self.mapped_blocks(block_device);
         self.size = new_size;
-        let mut total_blocks = self.data_blocks();
+        let total_blocks = self.map_blocks();
+        if total_blocks >= current_blocks {
+            self.flags &= !INODE_FLAG_PREALLOCATED;
+        }
+        self.extend_map(current_blocks, total_blocks, new_blocks, block_device);
+    }
+    /// Map blocks up to byte `end` without changing the size,
+    /// `new_blocks` is sized by [`DiskInode::blocks_num_needed`] for `end`.
+    /// Not for compressed inodes, whose block map has holes.
+    pub fn preallocate(
+        &mut self,
+        end: u32,
+        new_blocks: Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        assert!(!self.is_compressed());
+        let current_blocks = self.mapped_blocks(block_device);
+        let total_blocks = Self::_data_blocks(end);
+        if total_blocks > self.map_blocks() {
+            self.flags |= INODE_FLAG_PREALLOCATED;
+        }
+        self.extend_map(current_blocks, total_blocks, new_blocks, block_device);
+    }
+    /// Fill the block map from entry `current_blocks` to `total_blocks` with `new_blocks`,
+    /// taking index blocks from it where they are needed
+    fn extend_map(
+        &mut self,
+        mut current_blocks: u32,
+        mut total_blocks: u32,
+        new_blocks: Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        if current_blocks >= total_blocks {
+            return;
+        }
+        // 压缩文件的数据块留空，写入时按簇分配
+        let hole = self.is_compressed();
         let mut new_blocks = new_blocks.into_iter();
+        let next_data = |new_blocks: &mut alloc::vec::IntoIter<u32>| {
+            if hole {
+                0
+            } else {
+                new_blocks.next().unwrap()
+            }
+        };
         // fill direct
         while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
+            self.direct[current_blocks as usize] = next_data(&mut new_blocks);
             current_blocks += 1;
         }
         // alloc indirect1
@@ -165,7 +776,7 @@ impl DiskInode {
             .lock()
             .modify(0, |indirect1: &mut IndirectBlock| {
                 while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                    indirect1[current_blocks as usize] = new_blocks.next().unwrap();
+                    indirect1[current_blocks as usize] = next_data(&mut new_blocks);
                     current_blocks += 1;
                 }
             });
@@ -196,7 +807,7 @@ impl DiskInode {
                     get_block_cache(indirect2[a0] as usize, Arc::clone(block_device))
                         .lock()
                         .modify(0, |indirect1: &mut IndirectBlock| {
-                            indirect1[b0] = new_blocks.next().unwrap();
+                            indirect1[b0] = next_data(&mut new_blocks);
                         });
                     // move to next
                     b0 += 1;
@@ -207,15 +818,61 @@ impl DiskInode {
                 }
             });
     }
+    /// Shrink the size to `new_size` and return the blocks no longer mapped,
+    /// index blocks left empty included. Not for compressed or preallocated inodes.
+    pub fn decrease_size(&mut self, new_size: u32, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        assert!(new_size <= self.size);
+        assert!(!self.is_compressed() && !self.is_preallocated());
+        let old_blocks = self.data_blocks() as usize;
+        self.size = new_size;
+        let new_blocks = self.data_blocks() as usize;
+        let mut v: Vec<u32> = Vec::new();
+        for inner_id in new_blocks..old_blocks {
+            v.push(self.get_block_id(inner_id as u32, block_device));
+            self.set_block_id(inner_id as u32, 0, block_device);
+        }
+        // 二级索引下不再使用的一级索引块
+        if old_blocks > INDIRECT1_BOUND {
+            let used = |blocks: usize| {
+                blocks
+                    .saturating_sub(INDIRECT1_BOUND)
+                    .div_ceil(INODE_INDIRECT1_COUNT)
+            };
+            get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect2: &mut IndirectBlock| {
+                    for entry in indirect2[used(new_blocks)..used(old_blocks)].iter_mut() {
+                        v.push(*entry);
+                        *entry = 0;
+                    }
+                });
+            if new_blocks <= INDIRECT1_BOUND {
+                v.push(self.indirect2);
+                self.indirect2 = 0;
+            }
+        }
+        if old_blocks > DIRECT_BOUND && new_blocks <= DIRECT_BOUND {
+            v.push(self.indirect1);
+            self.indirect1 = 0;
+        }
+        v
+    }
     /// Clear size to zero and return blocks that should be deallocated.
     pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
         let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
+        let mut data_blocks = self.mapped_blocks(block_device) as usize;
         self.size = 0;
+        self.flags &= !INODE_FLAG_PREALLOCATED;
+        // 压缩文件中值为 0 的表项是空洞，不对应任何块
+        let push_data = |v: &mut Vec<u32>, block_id: u32| {
+            if block_id != 0 {
+                v.push(block_id);
+            }
+        };
         let mut current_blocks = 0usize;
         // direct
         while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]);
+            push_data(&mut v, self.direct[current_blocks]);
             self.direct[current_blocks] = 0;
             current_blocks += 1;
         }
@@ -232,7 +889,7 @@ impl DiskInode {
             .lock()
             .modify(0, |indirect1: &mut IndirectBlock| {
                 while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                    v.push(indirect1[current_blocks]);
+                    push_data(&mut v, indirect1[current_blocks]);
                     //indirect1[current_blocks] = 0;
                     current_blocks += 1;
                 }
@@ -259,7 +916,7 @@ impl DiskInode {
                         .lock()
                         .modify(0, |indirect1: &mut IndirectBlock| {
                             for entry in indirect1.iter() {
-                                v.push(*entry);
+                                push_data(&mut v, *entry);
                             }
                         });
                 }
@@ -270,7 +927,7 @@ impl DiskInode {
                         .lock()
                         .modify(0, |indirect1: &mut IndirectBlock| {
                             for entry in indirect1.iter().take(b1) {
-                                v.push(*entry);
+                                push_data(&mut v, *entry);
                             }
                         });
                     //indirect2[a1] = 0;
@@ -280,14 +937,19 @@ impl DiskInode {
         v
     }
 
-    /// Read data from the disk inode at a specific offset into a buffer.
+    /// Read data from the disk inode at a specific offset into a buffer,
+    /// decrypting it with `cipher` if the inode is encrypted.
     /// Return the number of bytes read.
     pub fn read_at(
         &self,
         offset: usize,
         buf: &mut [u8],
         block_device: &Arc<dyn BlockDevice>,
+        cipher: Option<&InodeCipher>,
     ) -> usize {
+        if self.is_compressed() {
+            return compress::read_at(self, offset, buf, block_device, cipher);
+        }
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
         if start >= end {
@@ -308,8 +970,15 @@ impl DiskInode {
             )
                 .lock()
                 .read(0, |data_block: &DataBlock| {
-                    let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                    dst.copy_from_slice(src);
+                    let range = start % BLOCK_SZ..start % BLOCK_SZ + block_read_size;
+                    match cipher {
+                        Some(cipher) => {
+                            let mut plain = *data_block;
+                            cipher.decrypt_block(start_block as u32, &mut plain);
+                            dst.copy_from_slice(&plain[range]);
+                        }
+                        None => dst.copy_from_slice(&data_block[range]),
+                    }
                 });
             read_size += block_read_size;
             // move to next block
@@ -322,13 +991,15 @@ impl DiskInode {
         read_size
     }
 
-    /// Write data into current disk inode
-    /// size must be adjusted properly beforehand
+    /// Write data into current disk inode, encrypting it with `cipher` if the inode is encrypted.
+    /// size must be adjusted properly beforehand,
+    /// compressed inodes are written through [`compress::write_at`] instead
     pub fn write_at(
         &mut self,
         offset: usize,
         buf: &[u8],
         block_device: &Arc<dyn BlockDevice>,
+        cipher: Option<&InodeCipher>,
     ) -> usize {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
@@ -348,8 +1019,15 @@ impl DiskInode {
                 .lock()
                 .modify(0, |data_block: &mut DataBlock| {
                     let src = &buf[write_size..write_size + block_write_size];
-                    let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
-                    dst.copy_from_slice(src);
+                    let range = start % BLOCK_SZ..start % BLOCK_SZ + block_write_size;
+                    match cipher {
+                        Some(cipher) => {
+                            cipher.decrypt_block(start_block as u32, data_block);
+                            data_block[range].copy_from_slice(src);
+                            cipher.encrypt_block(start_block as u32, data_block);
+                        }
+                        None => data_block[range].copy_from_slice(src),
+                    }
                 });
             write_size += block_write_size;
             // move to next block
@@ -363,23 +1041,17 @@ impl DiskInode {
     }
 }
 
-const NAME_LENGTH_LIMIT: usize = 27;
+/// Max length of a file name in bytes
+pub const NAME_LENGTH_LIMIT: usize = 27;
+/// Size of a directory entry on disk
 pub const DIRENT_SZ: usize = 32;
 /// A directory entry
-#[repr(C)]
 pub struct DirEntry {
     name: [u8; NAME_LENGTH_LIMIT + 1],
     inode_number: u32,
 }
 
 impl DirEntry {
-    /// Create an empty directory entry
-    pub fn empty() -> Self {
-        Self {
-            name: [0u8; NAME_LENGTH_LIMIT + 1],
-            inode_number: 0,
-        }
-    }
     /// Crate a directory entry from name and inode number
     pub fn new(name: &str, inode_number: u32) -> Self {
         let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
@@ -390,21 +1062,218 @@ impl DirEntry {
         }
     }
     /// Serialize into bytes
-    pub fn as_bytes(&self) -> &[u8] {
-        unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, DIRENT_SZ) }
+    pub fn to_bytes(&self) -> [u8; DIRENT_SZ] {
+        let mut bytes = [0u8; DIRENT_SZ];
+        self.encode(&mut bytes);
+        bytes
     }
-    /// Serialize into mutable bytes
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, DIRENT_SZ) }
+    /// Deserialize from bytes
+    pub fn from_bytes(bytes: &[u8; DIRENT_SZ]) -> Self {
+        Self::decode(bytes)
     }
-    /// Get name of the entry
-    pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+    /// Get name of the entry, `None` if it is not valid UTF-8 as on a corrupted image
+    pub fn name(&self) -> Option<&str> {
+        let len = self.name.iter().position(|byte| *byte == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).ok()
     }
     /// Get inode number of the entry
     pub fn inode_number(&self) -> u32 {
         self.inode_number
     }
 
-}
\ No newline at end of file
+}
+
+impl DiskFormat for DirEntry {
+    const DISK_SZ: usize = DIRENT_SZ;
+    fn decode(bytes: &[u8]) -> Self {
+        let mut d = Decoder::new(bytes);
+        Self {
+            name: d.get(),
+            inode_number: d.get(),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let mut e = Encoder::new(bytes);
+        e.put(&self.name);
+        e.put(&self.inode_number);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_dev::ram_device;
+    use alloc::collections::BTreeSet;
+    use alloc::vec;
+
+    fn new_file() -> DiskInode {
+        let mut disk_inode = DiskInode::decode(&[0u8; DiskInode::DISK_SZ]);
+        disk_inode.initialize(DiskInodeType::File);
+        disk_inode
+    }
+
+    /// 从 `next_block` 开始顺序分配块，返回新分配的块
+    fn grow(
+        disk_inode: &mut DiskInode,
+        new_size: u32,
+        next_block: &mut u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        let needed = disk_inode.blocks_num_needed(new_size, block_device);
+        let blocks: Vec<u32> = (*next_block..*next_block + needed).collect();
+        *next_block += needed;
+        disk_inode.increase_size(new_size, blocks.clone(), block_device);
+        blocks
+    }
+
+    /// 数据块互不相同，且和索引块一起恰好用完分配的块
+    fn check_block_map(
+        disk_inode: &DiskInode,
+        allocated: &BTreeSet<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let data: BTreeSet<u32> = (0..disk_inode.data_blocks())
+            .map(|inner_id| disk_inode.get_block_id(inner_id, block_device))
+            .collect();
+        assert_eq!(data.len(), disk_inode.data_blocks() as usize);
+        assert!(data.is_subset(allocated));
+        assert_eq!(allocated.len() as u32, DiskInode::total_blocks(disk_inode.size));
+    }
+
+    /// 直接索引、一级索引、二级索引各自的边界两侧
+    const BOUNDARY_BLOCKS: [usize; 9] = [
+        1,
+        DIRECT_BOUND,
+        DIRECT_BOUND + 1,
+        INDIRECT1_BOUND - 1,
+        INDIRECT1_BOUND,
+        INDIRECT1_BOUND + 1,
+        INDIRECT1_BOUND + INODE_INDIRECT1_COUNT,
+        INDIRECT1_BOUND + INODE_INDIRECT1_COUNT + 1,
+        INDIRECT1_BOUND + 2 * INODE_INDIRECT1_COUNT + 40,
+    ];
+
+    #[test]
+    fn increase_size_step_by_step() {
+        let block_device = ram_device(1024);
+        let mut disk_inode = new_file();
+        let mut next_block = 1;
+        let mut allocated = BTreeSet::new();
+        for blocks in BOUNDARY_BLOCKS {
+            // 先增长到块中间，再增长到块末尾
+            for size in [blocks * BLOCK_SZ - BLOCK_SZ / 2, blocks * BLOCK_SZ] {
+                let new_blocks = grow(&mut disk_inode, size as u32, &mut next_block, &block_device);
+                allocated.extend(new_blocks);
+                check_block_map(&disk_inode, &allocated, &block_device);
+            }
+        }
+        let freed: BTreeSet<u32> = disk_inode.clear_size(&block_device).into_iter().collect();
+        assert_eq!(freed, allocated);
+        assert_eq!(disk_inode.size, 0);
+    }
+
+    #[test]
+    fn decrease_size_step_by_step() {
+        let block_device = ram_device(1024);
+        let mut disk_inode = new_file();
+        let mut next_block = 1;
+        let largest = BOUNDARY_BLOCKS[BOUNDARY_BLOCKS.len() - 1];
+        let mut allocated: BTreeSet<u32> =
+            grow(&mut disk_inode, (largest * BLOCK_SZ) as u32, &mut next_block, &block_device)
+                .into_iter()
+                .collect();
+        for blocks in BOUNDARY_BLOCKS.iter().rev().skip(1).chain([0].iter()) {
+            let freed = disk_inode.decrease_size((blocks * BLOCK_SZ) as u32, &block_device);
+            for block_id in freed {
+                assert!(allocated.remove(&block_id), "block {} freed twice", block_id);
+            }
+            check_block_map(&disk_inode, &allocated, &block_device);
+        }
+        assert!(allocated.is_empty());
+        // 释放后的索引表项已清零，重新增长得到新的块映射
+        let allocated: BTreeSet<u32> =
+            grow(&mut disk_inode, (largest * BLOCK_SZ) as u32, &mut next_block, &block_device)
+                .into_iter()
+                .collect();
+        check_block_map(&disk_inode, &allocated, &block_device);
+    }
+
+    #[test]
+    fn increase_size_at_once() {
+        for blocks in BOUNDARY_BLOCKS {
+            let block_device = ram_device(1024);
+            let mut disk_inode = new_file();
+            let mut next_block = 1;
+            let allocated: BTreeSet<u32> =
+                grow(&mut disk_inode, (blocks * BLOCK_SZ) as u32, &mut next_block, &block_device)
+                    .into_iter()
+                    .collect();
+            check_block_map(&disk_inode, &allocated, &block_device);
+            let freed: BTreeSet<u32> = disk_inode.clear_size(&block_device).into_iter().collect();
+            assert_eq!(freed, allocated);
+        }
+    }
+
+    #[test]
+    fn read_write_across_index_levels() {
+        let block_device = ram_device(1024);
+        let mut disk_inode = new_file();
+        let mut next_block = 1;
+        let size = BOUNDARY_BLOCKS[BOUNDARY_BLOCKS.len() - 1] * BLOCK_SZ - 100;
+        grow(&mut disk_inode, size as u32, &mut next_block, &block_device);
+        let data: Vec<u8> = (0..size).map(|i| (i * 7 + i / BLOCK_SZ) as u8).collect();
+        // 分段写入，段边界不与块边界对齐
+        for chunk_start in (0..size).step_by(3000) {
+            let chunk_end = (chunk_start + 3000).min(size);
+            let written =
+                disk_inode.write_at(chunk_start, &data[chunk_start..chunk_end], &block_device, None);
+            assert_eq!(written, chunk_end - chunk_start);
+        }
+        let mut buf = vec![0u8; size + 10];
+        assert_eq!(disk_inode.read_at(0, &mut buf, &block_device, None), size);
+        assert!(buf[..size] == data[..]);
+        let offset = (INDIRECT1_BOUND * BLOCK_SZ) - 10;
+        let mut buf = [0u8; 20];
+        assert_eq!(disk_inode.read_at(offset, &mut buf, &block_device, None), 20);
+        assert!(buf[..] == data[offset..offset + 20]);
+        assert_eq!(disk_inode.read_at(size, &mut buf, &block_device, None), 0);
+    }
+
+    #[test]
+    fn disk_format_round_trip() {
+        let mut super_block = SuperBlock::decode(&[0u8; SuperBlock::DISK_SZ]);
+        super_block.initialize(4096, 1, 1024, 1, 3069);
+        super_block.label[..4].copy_from_slice(b"root");
+        let mut bytes = [0u8; SuperBlock::DISK_SZ];
+        super_block.encode(&mut bytes);
+        let decoded = SuperBlock::decode(&bytes);
+        assert!(decoded.is_valid());
+        assert_eq!(decoded.total_blocks, 4096);
+        assert_eq!(decoded.data_area_blocks, 3069);
+        assert_eq!(&decoded.label[..4], b"root");
+        let dirent = DirEntry::from_bytes(&DirEntry::new("initproc", 42).to_bytes());
+        assert_eq!(dirent.name(), Some("initproc"));
+        assert_eq!(dirent.inode_number(), 42);
+    }
+
+    #[test]
+    fn corrupted_inode_type() {
+        let mut bytes = [0u8; DiskInode::DISK_SZ];
+        new_file().encode(&mut bytes);
+        // 类型字节紧跟在块映射之后
+        bytes[4 + 4 * INODE_DIRECT_COUNT + 8] = 0xff;
+        let disk_inode = DiskInode::decode(&bytes);
+        assert_eq!(disk_inode.type_(), DiskInodeType::Unknown(0xff));
+        assert!(!disk_inode.is_dir() && !disk_inode.is_file());
+        let mut encoded = [0u8; DiskInode::DISK_SZ];
+        disk_inode.encode(&mut encoded);
+        assert_eq!(encoded, bytes);
+    }
+
+    #[test]
+    fn corrupted_dirent_name() {
+        let mut bytes = DirEntry::new("initproc", 42).to_bytes();
+        bytes[0] = 0xff;
+        assert_eq!(DirEntry::from_bytes(&bytes).name(), None);
+    }
+}
@@ -1,19 +1,64 @@
+//! easy-fs：基于块设备的简单文件系统
+//!
+//! 在 `no_std` 下运行，既被内核用作根文件系统，也被宿主机上的 fs-fuse 用来打包和检查镜像。
+//! 打开 `std` 特性后还提供宿主机文件块设备和 `std::io` 适配。
 #![no_std]
 #![deny(missing_docs)]
 extern crate alloc;
+#[cfg(any(feature = "std", test))]
+extern crate std;
 
 mod block_cache;
+mod compress;
+mod defrag;
+mod fat;
+mod crypt;
 mod block_dev;
+mod disk_format;
+mod fault;
 mod layout;
+mod quota;
 mod bitmap;
 mod fs;
+mod inspect;
+mod verity;
+mod ops;
 mod vfs;
+#[cfg(feature = "std")]
+mod std_io;
 
 ///the block size
 pub const BLOCK_SZ: usize = 512;
-pub use block_dev::BlockDevice;
-use block_cache::{block_cache_sync_all, get_block_cache};
+pub use block_dev::{BlockDevice, RamBlockDevice};
+pub use fault::{CrashFailure, CrashPolicy, FaultyBlockDevice, WriteRecord};
+#[cfg(any(feature = "std", test))]
+pub use fault::replay_crashes;
+use block_cache::{block_cache_sync_all, get_block_cache, zero_block};
+pub use block_cache::{
+    block_cache_flush_policy, block_cache_reset_stats, block_cache_set_capacity,
+    block_cache_set_flush_policy, block_cache_stats, BlockCacheStats, FlushPolicy,
+};
 use layout::*;
+use disk_format::{Decoder, DiskFormat, Encoder};
 use bitmap::Bitmap;
-pub use fs::FileSystem;
-use vfs::Inode;
\ No newline at end of file
+pub use defrag::DefragReport;
+pub use fat::{FatFileSystem, FatInode};
+pub use fs::{FileSystem, FsStat};
+pub use inspect::{Area, AreaKind, BitmapUsage, BlockRole};
+pub use verity::hash_tree_blocks;
+pub use crypt::KEY_SZ;
+pub use quota::Quota;
+pub use layout::{
+    DataExtent, DiskInode, DiskInodeType, Orphan, SuperBlock, DIRENT_SZ, FEATURE_COMPAT_QUOTA,
+    FEATURE_COMPAT_VERITY, FEATURE_INCOMPAT_COMPRESSION, FEATURE_INCOMPAT_DATA_EXTENTS,
+    FEATURE_INCOMPAT_ENCRYPTION, FEATURE_INCOMPAT_ORPHANS, FORMAT_VERSION, INODE_FLAG_COMPRESSED,
+    INODE_FLAG_ENCRYPTED, INODE_FLAG_NAMES_ENCRYPTED, INODE_FLAG_PREALLOCATED, LABEL_SZ,
+    MAX_ORPHANS, NAME_LENGTH_LIMIT, SUPPORTED_INCOMPAT_FEATURES,
+};
+use crypt::InodeCipher;
+pub use ops::{
+    DirEntryInfo, DirIter, DirOps, FileOps, FileType, InodeOps, SuperBlockOps, VfsInode, VfsStat,
+};
+pub use vfs::Inode;
+#[cfg(feature = "std")]
+pub use std_io::{FileBlockDevice, FileHandle};
\ No newline at end of file
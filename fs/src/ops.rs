@@ -0,0 +1,189 @@
+//! 与具体文件系统无关的接口：超级块、inode、目录和文件操作
+//!
+//! 内核和宿主机工具只通过这些 trait 访问文件系统，easy-fs 是其中一种实现。
+//! 某种文件系统不支持的属性（属主、压缩、加密）由默认实现报告为不支持。
+
+use alloc::string::String;
+use alloc::sync::Arc;
+
+/// Operations on a mounted filesystem as a whole
+pub trait SuperBlockOps: Send + Sync {
+    /// Short name of the filesystem type, such as `easy-fs`
+    fn fs_type(&self) -> &'static str;
+    /// Size in bytes of the blocks counted by [`SuperBlockOps::stat`]
+    fn block_size(&self) -> usize;
+    /// Get the root directory
+    fn root_inode(self: Arc<Self>) -> Arc<dyn VfsInode>;
+    /// Get the number of files and data blocks, and how many of them are free
+    fn stat(&self) -> VfsStat;
+    /// Write every cached change back to the device
+    fn sync(&self);
+}
+
+/// Operations common to files and directories
+pub trait InodeOps: Send + Sync {
+    /// Get the inode number, unique within the filesystem
+    fn inode_id(&self) -> u32;
+    /// Whether the inode is a directory
+    fn is_dir(&self) -> bool;
+    /// Get the size in bytes
+    fn size(&self) -> usize;
+    /// Get the owner, 0 if the filesystem keeps none
+    fn owner(&self) -> u16 {
+        0
+    }
+    /// Hand the inode over to `uid`, return whether it succeeded
+    fn set_owner(&self, _uid: u16) -> bool {
+        false
+    }
+    /// Whether the data is stored compressed
+    fn is_compressed(&self) -> bool {
+        false
+    }
+    /// Enable or disable transparent compression, return whether it succeeded
+    fn set_compressed(&self, _compressed: bool) -> bool {
+        false
+    }
+    /// Whether the data is stored encrypted
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+    /// Encrypt the inode, for a directory `names` also encrypts its entries.
+    /// Return whether it succeeded.
+    fn set_encrypted(&self, _names: bool) -> bool {
+        false
+    }
+}
+
+/// Operations on a directory, they fail on a file
+pub trait DirOps {
+    /// Longest name in bytes an entry can have
+    fn name_length_limit(&self) -> usize;
+    /// Find the entry `name`
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>>;
+    /// Create a file named `name`, `None` if it exists or cannot be created
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>>;
+    /// Create a directory named `name`, `None` if it exists or cannot be created
+    fn create_dir(&self, name: &str) -> Option<Arc<dyn VfsInode>>;
+    /// Remove the entry `name`, a directory only when it is empty.
+    /// Return false if there is no such entry or it cannot be removed.
+    fn unlink(&self, name: &str) -> bool;
+    /// Read the entry at `cookie`, `None` past the last one
+    fn dir_entry(&self, cookie: usize) -> Option<DirEntryInfo>;
+    /// Iterate over the entries, starting from `cookie`. The cookie of the first entry is 0.
+    fn read_dir(&self, cookie: usize) -> DirIter<'_>;
+    /// Get the inode of an entry read from this directory without looking its name up again,
+    /// `None` if the entry has been removed since
+    fn open_entry(&self, entry: &DirEntryInfo) -> Option<Arc<dyn VfsInode>>;
+}
+
+/// Operations on the data of a file
+pub trait FileOps {
+    /// Read data at `offset`, return the number of bytes read
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize;
+    /// Write data at `offset`, growing the file as needed.
+    /// Return the number of bytes written, 0 if there is no space left or writing is refused.
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize;
+    /// Truncate the file to size 0
+    fn clear(&self);
+}
+
+/// An inode of any filesystem, with the operations of both files and directories
+pub trait VfsInode: InodeOps + DirOps + FileOps {}
+
+impl<T: InodeOps + DirOps + FileOps> VfsInode for T {}
+
+/// Capacity and free space of a mounted filesystem
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VfsStat {
+    /// Number of files that can exist, 0 if the filesystem has no fixed number of inodes
+    pub files: u64,
+    /// Number of files that can still be created, 0 if there is no fixed number
+    pub free_files: u64,
+    /// Number of blocks of [`SuperBlockOps::block_size`] bytes for data
+    pub blocks: u64,
+    /// Number of free data blocks
+    pub free_blocks: u64,
+}
+
+/// Type of a file, the same for every filesystem
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    /// 普通文件
+    File,
+    /// 目录
+    Directory,
+}
+
+/// An entry yielded by [`DirIter`]
+pub struct DirEntryInfo {
+    /// Name of the entry
+    pub name: String,
+    /// Inode number of the entry
+    pub inode_number: u32,
+    /// Type of the inode
+    pub type_: FileType,
+    /// Cookie from which [`DirOps::read_dir`] resumes right after this entry
+    pub next_cookie: usize,
+}
+
+/// Streaming iterator over the entries of a directory.
+/// 每次只读取一个目录项，迭代之间不持有目录的锁
+pub struct DirIter<'a> {
+    dir: &'a dyn DirOps,
+    cookie: usize,
+}
+
+impl<'a> DirIter<'a> {
+    /// Iterate over the entries of `dir` from `cookie`
+    pub fn new(dir: &'a dyn DirOps, cookie: usize) -> Self {
+        Self { dir, cookie }
+    }
+    /// Cookie of the next entry, pass it to [`DirOps::read_dir`] to resume later
+    pub fn cookie(&self) -> usize {
+        self.cookie
+    }
+}
+
+impl Iterator for DirIter<'_> {
+    type Item = DirEntryInfo;
+    fn next(&mut self) -> Option<DirEntryInfo> {
+        let entry = self.dir.dir_entry(self.cookie)?;
+        self.cookie = entry.next_cookie;
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileSystem, RamBlockDevice, NAME_LENGTH_LIMIT};
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn easy_fs_through_the_traits() {
+        let efs: Arc<dyn SuperBlockOps> =
+            FileSystem::create(Arc::new(RamBlockDevice::new(4096)), 4096, 1);
+        assert_eq!(efs.fs_type(), "easy-fs");
+        let free_before = efs.stat().free_blocks;
+        let root_inode = Arc::clone(&efs).root_inode();
+        assert!(root_inode.is_dir());
+        assert_eq!(root_inode.name_length_limit(), NAME_LENGTH_LIMIT);
+        let dir = root_inode.create_dir("etc").unwrap();
+        let file = dir.create("passwd").unwrap();
+        assert!(dir.create("passwd").is_none());
+        assert_eq!(file.write_at(0, &[7u8; 3000]), 3000);
+        let mut buf = vec![0u8; 3000];
+        assert_eq!(dir.find("passwd").unwrap().read_at(0, &mut buf), 3000);
+        assert!(buf.iter().all(|byte| *byte == 7));
+        let names: Vec<_> = root_inode.read_dir(0).map(|entry| entry.name).collect();
+        assert_eq!(names, ["etc"]);
+        assert!(efs.stat().free_blocks < free_before);
+        assert!(!root_inode.unlink("etc"));
+        drop(file);
+        assert!(dir.unlink("passwd"));
+        assert!(root_inode.unlink("etc"));
+        assert_eq!(efs.stat().free_blocks, free_before);
+    }
+}
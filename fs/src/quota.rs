@@ -0,0 +1,291 @@
+//! 按属主统计和限制磁盘用量
+//!
+//! 配额表占用 `FileSystem::create` 时在数据区预留的 [`QUOTA_BLOCKS`] 个块，
+//! 每个属主一项，记录软/硬限制和已用的块数、inode 数。
+//! 超过硬限制的分配会被拒绝，超过软限制只在用量报告中体现。
+//! 用量在第一次为某个 uid 分配时开始记录，表满之后为新的 uid 分配会被拒绝。
+use super::{get_block_cache, BlockDevice, Decoder, DiskFormat, Encoder, BLOCK_SZ};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Number of blocks reserved for the quota table
+pub const QUOTA_BLOCKS: u32 = 4;
+const QUOTAS_PER_BLOCK: usize = BLOCK_SZ / Quota::DISK_SZ;
+const QUOTA_IN_USE: u16 = 1;
+
+type QuotaBlock = [Quota; QUOTAS_PER_BLOCK];
+
+/// Limits and usage of one owner, a limit of 0 means unlimited
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Quota {
+    flags: u16,
+    /// Owner of the inodes
+    pub uid: u16,
+    /// Soft limit on blocks
+    pub block_soft: u32,
+    /// Hard limit on blocks
+    pub block_hard: u32,
+    /// Soft limit on inodes
+    pub inode_soft: u32,
+    /// Hard limit on inodes
+    pub inode_hard: u32,
+    /// Blocks in use, including index blocks
+    pub blocks_used: u32,
+    /// Inodes in use
+    pub inodes_used: u32,
+}
+
+impl Quota {
+    /// Quota of `uid` without any limit
+    pub fn new(uid: u16) -> Self {
+        Self {
+            flags: QUOTA_IN_USE,
+            uid,
+            ..Self::default()
+        }
+    }
+    /// Whether the block or inode usage exceeds its soft limit
+    pub fn over_soft_limit(&self) -> bool {
+        exceeds(self.blocks_used, self.block_soft) || exceeds(self.inodes_used, self.inode_soft)
+    }
+    fn is_used(&self) -> bool {
+        self.flags & QUOTA_IN_USE != 0
+    }
+}
+
+impl DiskFormat for Quota {
+    const DISK_SZ: usize = 32;
+    fn decode(bytes: &[u8]) -> Self {
+        let mut d = Decoder::new(bytes);
+        Self {
+            flags: d.get(),
+            uid: d.get(),
+            block_soft: d.get(),
+            block_hard: d.get(),
+            inode_soft: d.get(),
+            inode_hard: d.get(),
+            blocks_used: d.get(),
+            inodes_used: d.get(),
+        }
+    }
+    fn encode(&self, bytes: &mut [u8]) {
+        let mut e = Encoder::new(bytes);
+        e.put(&self.flags);
+        e.put(&self.uid);
+        e.put(&self.block_soft);
+        e.put(&self.block_hard);
+        e.put(&self.inode_soft);
+        e.put(&self.inode_hard);
+        e.put(&self.blocks_used);
+        e.put(&self.inodes_used);
+    }
+}
+
+fn exceeds(used: u32, limit: u32) -> bool {
+    limit != 0 && used > limit
+}
+
+/// 位于磁盘上的配额表
+pub struct QuotaTable {
+    start_block_id: usize,
+    blocks: usize,
+}
+
+impl QuotaTable {
+    /// Quota table in `blocks` blocks from `start_block_id`, no table if `blocks` is 0
+    pub fn new(start_block_id: usize, blocks: usize) -> Self {
+        Self {
+            start_block_id,
+            blocks,
+        }
+    }
+    /// Whether the filesystem has a quota table
+    pub fn is_enabled(&self) -> bool {
+        self.blocks > 0
+    }
+    /// Call a function over the entry of `uid`, creating it if `create` is set.
+    /// Return `None` if there is no such entry.
+    fn modify_entry<V>(
+        &self,
+        uid: u16,
+        create: bool,
+        block_device: &Arc<dyn BlockDevice>,
+        f: impl FnOnce(&mut Quota) -> V,
+    ) -> Option<V> {
+        let mut free_slot = None;
+        for i in 0..self.blocks {
+            let found = get_block_cache(self.start_block_id + i, Arc::clone(block_device))
+                .lock()
+                .read(0, |quota_block: &QuotaBlock| {
+                    let mut found = None;
+                    for (j, entry) in quota_block.iter().enumerate() {
+                        if entry.is_used() && entry.uid == uid {
+                            found = Some(j);
+                            break;
+                        }
+                        if !entry.is_used() && free_slot.is_none() {
+                            free_slot = Some((i, j));
+                        }
+                    }
+                    found
+                });
+            if let Some(j) = found {
+                return Some(self.modify_slot(i, j, block_device, f));
+            }
+        }
+        let (i, j) = free_slot.filter(|_| create)?;
+        Some(self.modify_slot(i, j, block_device, |entry| {
+            *entry = Quota::new(uid);
+            f(entry)
+        }))
+    }
+    fn modify_slot<V>(
+        &self,
+        block: usize,
+        slot: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        f: impl FnOnce(&mut Quota) -> V,
+    ) -> V {
+        get_block_cache(self.start_block_id + block, Arc::clone(block_device))
+            .lock()
+            .modify(0, |quota_block: &mut QuotaBlock| f(&mut quota_block[slot]))
+    }
+    /// Charge blocks and inodes to `uid`, refused if a hard limit would be exceeded
+    /// or `uid` has no entry and the table is full
+    pub fn charge(
+        &self,
+        uid: u16,
+        blocks: u32,
+        inodes: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> bool {
+        if !self.is_enabled() || (blocks == 0 && inodes == 0) {
+            return true;
+        }
+        self.modify_entry(uid, true, block_device, |entry| {
+            let blocks_used = entry.blocks_used + blocks;
+            let inodes_used = entry.inodes_used + inodes;
+            if exceeds(blocks_used, entry.block_hard) || exceeds(inodes_used, entry.inode_hard) {
+                return false;
+            }
+            entry.blocks_used = blocks_used;
+            entry.inodes_used = inodes_used;
+            true
+        })
+        // 表满时新的 uid 无法统计，也就无从限制
+        .unwrap_or(false)
+    }
+    /// Give back blocks and inodes charged to `uid`
+    pub fn release(&self, uid: u16, blocks: u32, inodes: u32, block_device: &Arc<dyn BlockDevice>) {
+        if !self.is_enabled() || (blocks == 0 && inodes == 0) {
+            return;
+        }
+        self.modify_entry(uid, false, block_device, |entry| {
+            entry.blocks_used = entry.blocks_used.saturating_sub(blocks);
+            entry.inodes_used = entry.inodes_used.saturating_sub(inodes);
+        });
+    }
+    /// Set the limits of `uid`, return false if the table is missing or full
+    pub fn set_limits(&self, limits: &Quota, block_device: &Arc<dyn BlockDevice>) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        self.modify_entry(limits.uid, true, block_device, |entry| {
+            entry.block_soft = limits.block_soft;
+            entry.block_hard = limits.block_hard;
+            entry.inode_soft = limits.inode_soft;
+            entry.inode_hard = limits.inode_hard;
+        })
+        .is_some()
+    }
+    /// Get the limits and usage of `uid`
+    pub fn get(&self, uid: u16, block_device: &Arc<dyn BlockDevice>) -> Option<Quota> {
+        self.list(block_device)
+            .into_iter()
+            .find(|entry| entry.uid == uid)
+    }
+    /// Get the limits and usage of every owner in the table
+    pub fn list(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<Quota> {
+        let mut v = Vec::new();
+        for i in 0..self.blocks {
+            get_block_cache(self.start_block_id + i, Arc::clone(block_device))
+                .lock()
+                .read(0, |quota_block: &QuotaBlock| {
+                    v.extend(quota_block.iter().filter(|entry| entry.is_used()));
+                });
+        }
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_dev::ram_device;
+    use crate::FileSystem;
+
+    fn limits(uid: u16, block_soft: u32, block_hard: u32, inode_hard: u32) -> Quota {
+        Quota {
+            block_soft,
+            block_hard,
+            inode_hard,
+            ..Quota::new(uid)
+        }
+    }
+
+    #[test]
+    fn block_hard_limit() {
+        let efs = FileSystem::create(ram_device(4096), 4096, 1);
+        assert!(efs.set_quota(&limits(1, 0, 4, 0)));
+        let file = FileSystem::root_inode(&efs).create("a").unwrap();
+        assert!(file.set_owner(1));
+        assert_eq!(file.write_at(0, &[1; 4 * BLOCK_SZ]), 4 * BLOCK_SZ);
+        assert_eq!(file.write_at(4 * BLOCK_SZ, &[1; BLOCK_SZ]), 0);
+        assert_eq!(file.size(), 4 * BLOCK_SZ);
+        let quota = efs.quota(1).unwrap();
+        assert_eq!((quota.blocks_used, quota.inodes_used), (4, 1));
+        assert!(!quota.over_soft_limit());
+    }
+
+    #[test]
+    fn block_soft_limit() {
+        let efs = FileSystem::create(ram_device(4096), 4096, 1);
+        assert!(efs.set_quota(&limits(1, 2, 0, 0)));
+        let file = FileSystem::root_inode(&efs).create("a").unwrap();
+        assert!(file.set_owner(1));
+        assert_eq!(file.write_at(0, &[1; 2 * BLOCK_SZ]), 2 * BLOCK_SZ);
+        assert!(!efs.quota(1).unwrap().over_soft_limit());
+        // 超过软限制的分配照常进行，只在用量中体现
+        assert_eq!(file.write_at(2 * BLOCK_SZ, &[1; BLOCK_SZ]), BLOCK_SZ);
+        assert!(efs.quota(1).unwrap().over_soft_limit());
+    }
+
+    #[test]
+    fn inode_hard_limit() {
+        let efs = FileSystem::create(ram_device(4096), 4096, 1);
+        assert!(efs.set_quota(&limits(1, 0, 0, 1)));
+        let root_inode = FileSystem::root_inode(&efs);
+        assert!(root_inode.create("a").unwrap().set_owner(1));
+        let b = root_inode.create("b").unwrap();
+        assert!(!b.set_owner(1));
+        assert_eq!(b.owner(), 0);
+        assert_eq!(efs.quota(1).unwrap().inodes_used, 1);
+    }
+
+    #[test]
+    fn full_table_refuses_new_owners() {
+        let efs = FileSystem::create(ram_device(4096), 4096, 1);
+        // uid 0 已经占用一项
+        let entries = QUOTA_BLOCKS as usize * QUOTAS_PER_BLOCK;
+        for uid in 1..entries as u16 {
+            assert!(efs.set_quota(&Quota::new(uid)));
+        }
+        let new_uid = entries as u16;
+        assert!(!efs.set_quota(&Quota::new(new_uid)));
+        let file = FileSystem::root_inode(&efs).create("a").unwrap();
+        assert!(!file.set_owner(new_uid));
+        assert_eq!(file.owner(), 0);
+        assert!(efs.quota(new_uid).is_none());
+        assert!(file.set_owner(1));
+    }
+}
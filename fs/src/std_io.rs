@@ -0,0 +1,283 @@
+//! 宿主机上使用的适配层，需要启用 `std` feature
+//!
+//! [`FileBlockDevice`] 把普通文件当作块设备，[`FileHandle`] 把 [`VfsInode`] 包装成
+//! 实现了 `std::io::{Read, Write, Seek}` 的打开文件，宿主机工具可以直接用
+//! `io::copy` 在镜像内外复制数据。
+
+use super::{block_cache_sync_all, BlockDevice, VfsInode, BLOCK_SZ};
+use alloc::sync::Arc;
+use core::ops::Range;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A block device backed by a host file
+pub struct FileBlockDevice(Mutex<File>);
+
+impl FileBlockDevice {
+    /// Use an opened file as a block device
+    pub fn new(file: File) -> Self {
+        Self(Mutex::new(file))
+    }
+    /// Open an existing image for reading and writing
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self::new(file))
+    }
+    /// Create an image of `total_blocks` blocks, an existing file is truncated
+    pub fn create<P: AsRef<Path>>(path: P, total_blocks: u32) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(total_blocks as u64 * BLOCK_SZ as u64)?;
+        Ok(Self::new(file))
+    }
+    /// Number of whole blocks in the file
+    pub fn total_blocks(&self) -> io::Result<u32> {
+        let len = self.0.lock().unwrap().metadata()?.len();
+        Ok((len / BLOCK_SZ as u64) as u32)
+    }
+    /// Change the size of the file to `total_blocks` blocks
+    pub fn set_total_blocks(&self, total_blocks: u32) -> io::Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .set_len(total_blocks as u64 * BLOCK_SZ as u64)
+    }
+}
+
+impl BlockDevice for FileBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        // 读到文件末尾之后的部分视为全零，短读则继续读完
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => panic!("Error when reading block {}: {}", block_id, e),
+            }
+        }
+        buf[read..].fill(0);
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        file.write_all(buf)
+            .unwrap_or_else(|e| panic!("Error when writing block {}: {}", block_id, e));
+    }
+
+    fn flush(&self) {
+        self.0
+            .lock()
+            .unwrap()
+            .sync_data()
+            .unwrap_or_else(|e| panic!("Error when syncing: {}", e));
+    }
+
+    /// 在宿主机文件中打洞，文件大小不变，稀疏的镜像因此保持小巧。
+    /// discard 只是提示，宿主机文件系统不支持打洞时忽略
+    #[cfg(target_os = "linux")]
+    fn discard(&self, blocks: Range<usize>) {
+        let file = self.0.lock().unwrap();
+        unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                (blocks.start * BLOCK_SZ) as libc::off_t,
+                (blocks.len() * BLOCK_SZ) as libc::off_t,
+            );
+        }
+    }
+}
+
+/// An opened file with its own position
+pub struct FileHandle {
+    inode: Arc<dyn VfsInode>,
+    offset: usize,
+}
+
+impl FileHandle {
+    /// Open `inode` at offset 0
+    pub fn new(inode: Arc<dyn VfsInode>) -> Self {
+        Self { inode, offset: 0 }
+    }
+    /// Get the opened inode
+    pub fn inode(&self) -> &Arc<dyn VfsInode> {
+        &self.inode
+    }
+}
+
+impl Read for FileHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inode.read_at(self.offset, buf);
+        self.offset += size;
+        Ok(size)
+    }
+}
+
+impl Write for FileHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let size = self.inode.write_at(self.offset, buf);
+        if size == 0 {
+            // 超出配额，或者加密文件没有提供密钥
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "quota exceeded or file locked",
+            ));
+        }
+        self.offset += size;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        block_cache_sync_all();
+        Ok(())
+    }
+}
+
+impl Seek for FileHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(delta) => (self.inode.size() as i64, delta),
+            SeekFrom::Current(delta) => (self.offset as i64, delta),
+        };
+        match base.checked_add(delta) {
+            Some(offset) if offset >= 0 => {
+                self.offset = offset as usize;
+                Ok(self.offset as u64)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileSystem;
+    use alloc::{format, vec, vec::Vec};
+    use std::path::PathBuf;
+
+    /// A file in the temporary directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("easy-fs-{}-{}", std::process::id(), name));
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn read_past_end_of_file() {
+        let temp = TempFile::new("read_past_end");
+        std::fs::write(&temp.0, [7u8; BLOCK_SZ + 100]).unwrap();
+        let block_device = FileBlockDevice::open(&temp.0).unwrap();
+        assert_eq!(block_device.total_blocks().unwrap(), 1);
+        // 最后一块只有一部分在文件中，其余部分读出全零
+        let mut buf = [0xffu8; BLOCK_SZ];
+        block_device.read_block(1, &mut buf);
+        assert!(buf[..100].iter().all(|byte| *byte == 7));
+        assert!(buf[100..].iter().all(|byte| *byte == 0));
+        block_device.read_block(5, &mut buf);
+        assert_eq!(buf, [0u8; BLOCK_SZ]);
+    }
+
+    #[test]
+    fn write_extends_the_file() {
+        let temp = TempFile::new("write_extends");
+        let block_device = FileBlockDevice::create(&temp.0, 2).unwrap();
+        block_device.write_block(4, &[3u8; BLOCK_SZ]);
+        block_device.flush();
+        assert_eq!(block_device.total_blocks().unwrap(), 5);
+        let data = std::fs::read(&temp.0).unwrap();
+        assert!(data[..4 * BLOCK_SZ].iter().all(|byte| *byte == 0));
+        assert!(data[4 * BLOCK_SZ..].iter().all(|byte| *byte == 3));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn discard_punches_a_hole() {
+        use std::os::unix::fs::MetadataExt;
+        let temp = TempFile::new("discard");
+        let block_device = FileBlockDevice::create(&temp.0, 64).unwrap();
+        for block_id in 0..64 {
+            block_device.write_block(block_id, &[1u8; BLOCK_SZ]);
+        }
+        block_device.flush();
+        let allocated = std::fs::metadata(&temp.0).unwrap().blocks();
+        block_device.discard(16..48);
+        let metadata = std::fs::metadata(&temp.0).unwrap();
+        assert_eq!(metadata.len(), 64 * BLOCK_SZ as u64);
+        assert!(metadata.blocks() < allocated);
+        let mut buf = [0xffu8; BLOCK_SZ];
+        block_device.read_block(20, &mut buf);
+        assert_eq!(buf, [0u8; BLOCK_SZ]);
+        block_device.read_block(48, &mut buf);
+        assert_eq!(buf, [1u8; BLOCK_SZ]);
+    }
+
+    fn new_file() -> FileHandle {
+        let efs = FileSystem::create(Arc::new(crate::RamBlockDevice::new(4096)), 4096, 1);
+        FileHandle::new(FileSystem::root_inode(&efs).create("file").unwrap())
+    }
+
+    #[test]
+    fn read_write_seek() {
+        let mut file = new_file();
+        file.write_all(b"hello world").unwrap();
+        assert_eq!(file.seek(SeekFrom::End(-5)).unwrap(), 6);
+        let mut buf = vec![0u8; 5];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, b"world");
+        assert_eq!(file.seek(SeekFrom::Current(-11)).unwrap(), 0);
+        file.write_all(b"HELLO").unwrap();
+        // 越过文件末尾写入，中间的空洞读出全零
+        assert_eq!(file.seek(SeekFrom::Current(10)).unwrap(), 15);
+        file.write_all(b"!").unwrap();
+        file.flush().unwrap();
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"HELLO world\0\0\0\0!");
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn negative_seek_is_refused() {
+        let mut file = new_file();
+        file.write_all(b"abc").unwrap();
+        let error = file.seek(SeekFrom::End(-4)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = file.seek(SeekFrom::Current(-4)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        // 失败的 seek 不改变位置
+        assert_eq!(file.stream_position().unwrap(), 3);
+    }
+}
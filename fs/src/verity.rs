@@ -0,0 +1,250 @@
+//! 只读校验镜像：覆盖整个 easy-fs 镜像的 Merkle 哈希树
+//!
+//! 哈希树紧跟在文件系统的 `total_blocks` 个块之后，自底向上逐层存放。
+//! 第 0 层的每个表项是一个块的 SHA-256，第 `l + 1` 层的表项是第 `l` 层一个块的 SHA-256，
+//! 最高层只有一个块，它的哈希就是根哈希，记录在超级块中。
+//! 计算超级块自身的哈希时，哈希树相关的字段按 0 处理。
+use super::{block_cache_sync_all, get_block_cache, BlockDevice, DiskFormat, SuperBlock, BLOCK_SZ};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use sha2::{Digest, Sha256};
+
+const HASH_SZ: usize = 32;
+const HASHES_PER_BLOCK: usize = BLOCK_SZ / HASH_SZ;
+type Hash = [u8; HASH_SZ];
+
+/// (start block, block count) of every tree level, from the leaves up
+fn tree_levels(total_blocks: usize) -> Vec<(usize, usize)> {
+    let mut levels = Vec::new();
+    let mut start = total_blocks;
+    let mut entries = total_blocks;
+    loop {
+        let blocks = entries.div_ceil(HASHES_PER_BLOCK);
+        levels.push((start, blocks));
+        if blocks == 1 {
+            return levels;
+        }
+        start += blocks;
+        entries = blocks;
+    }
+}
+
+/// Number of blocks taken by the hash tree of an image with `total_blocks` blocks
+pub fn hash_tree_blocks(total_blocks: u32) -> u32 {
+    tree_levels(total_blocks as usize)
+        .iter()
+        .map(|(_, blocks)| *blocks as u32)
+        .sum()
+}
+
+/// Hash the content of a filesystem block
+fn hash_block(block_id: usize, data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    if block_id == 0 {
+        let range = SuperBlock::verity_range();
+        hasher.update(&data[..range.start]);
+        hasher.update(&[0u8; BLOCK_SZ][range.clone()]);
+        hasher.update(&data[range.end..]);
+    } else {
+        hasher.update(data);
+    }
+    hasher.finalize().into()
+}
+
+/// Write the hash tree of the filesystem on `block_device` and record its root in the superblock.
+/// Return the root hash.
+pub fn build(block_device: &Arc<dyn BlockDevice>) -> Hash {
+    block_cache_sync_all();
+    let total_blocks = get_block_cache(0, Arc::clone(block_device))
+        .lock()
+        .read(0, |super_block: &SuperBlock| super_block.total_blocks) as usize;
+    let levels = tree_levels(total_blocks);
+    let mut buf = [0u8; BLOCK_SZ];
+    let mut hashes: Vec<Hash> = (0..total_blocks)
+        .map(|block_id| {
+            block_device.read_block(block_id, &mut buf);
+            hash_block(block_id, &buf)
+        })
+        .collect();
+    for (start, blocks) in levels.iter() {
+        let mut upper: Vec<Hash> = Vec::with_capacity(*blocks);
+        for (i, chunk) in hashes.chunks(HASHES_PER_BLOCK).enumerate() {
+            let mut tree_block = [0u8; BLOCK_SZ];
+            for (slot, hash) in tree_block.chunks_mut(HASH_SZ).zip(chunk.iter()) {
+                slot.copy_from_slice(hash);
+            }
+            block_device.write_block(start + i, &tree_block);
+            upper.push(Sha256::digest(tree_block).into());
+        }
+        hashes = upper;
+    }
+    let root = hashes[0];
+    get_block_cache(0, Arc::clone(block_device))
+        .lock()
+        .modify(0, |super_block: &mut SuperBlock| {
+            super_block.verity_levels = levels.len() as u32;
+            super_block.verity_root = root;
+        });
+    block_cache_sync_all();
+    root
+}
+
+/// 读出的每个块都沿哈希树校验到根哈希的块设备，校验失败或写入时 panic
+pub struct VerifiedBlockDevice {
+    inner: Arc<dyn BlockDevice>,
+    total_blocks: usize,
+    levels: Vec<(usize, usize)>,
+    root: Hash,
+}
+
+impl VerifiedBlockDevice {
+    /// Wrap a device holding a verified image.
+    /// `root` is the trusted root hash, obtained out of band: the one in the superblock
+    /// could have been rewritten along with the data and the tree.
+    pub fn new(inner: Arc<dyn BlockDevice>, root: Hash) -> Self {
+        // 超级块还未经校验，这里只取出几何信息，之后经由本设备读超级块时会被校验
+        let mut raw = [0u8; BLOCK_SZ];
+        inner.read_block(0, &mut raw);
+        let super_block = SuperBlock::decode(&raw);
+        assert!(super_block.is_valid(), "Error loading fs!");
+        assert!(super_block.verity_levels > 0, "verity: image has no hash tree");
+        let total_blocks = super_block.total_blocks as usize;
+        let levels = tree_levels(total_blocks);
+        assert_eq!(levels.len(), super_block.verity_levels as usize, "verity: bad tree geometry");
+        Self {
+            inner,
+            total_blocks,
+            levels,
+            root,
+        }
+    }
+}
+
+impl BlockDevice for VerifiedBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        assert!(block_id < self.total_blocks, "verity: block {} out of image", block_id);
+        self.inner.read_block(block_id, buf);
+        let mut hash = hash_block(block_id, buf);
+        let mut index = block_id;
+        let mut tree_block = [0u8; BLOCK_SZ];
+        for (start, _) in self.levels.iter() {
+            self.inner.read_block(start + index / HASHES_PER_BLOCK, &mut tree_block);
+            let slot = index % HASHES_PER_BLOCK * HASH_SZ;
+            assert!(
+                tree_block[slot..slot + HASH_SZ] == hash,
+                "verity: block {} has been tampered with",
+                block_id
+            );
+            hash = Sha256::digest(tree_block).into();
+            index /= HASHES_PER_BLOCK;
+        }
+        assert!(hash == self.root, "verity: root hash mismatch");
+    }
+
+    fn write_block(&self, block_id: usize, _buf: &[u8]) {
+        panic!("verity: write to block {} of a read-only image", block_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_dev::ram_device;
+    use crate::{FileSystem, Orphan};
+
+    const TOTAL_BLOCKS: u32 = 4096;
+    const PATTERN: u8 = 0x5a;
+
+    /// A verity image whose file `a` fills one block with `PATTERN`, and its root hash
+    fn image() -> (Arc<dyn BlockDevice>, Hash) {
+        let block_device = ram_device((TOTAL_BLOCKS + hash_tree_blocks(TOTAL_BLOCKS)) as usize);
+        let efs = FileSystem::create(Arc::clone(&block_device), TOTAL_BLOCKS, 1);
+        let file = FileSystem::root_inode(&efs).create("a").unwrap();
+        file.write_at(0, &[PATTERN; BLOCK_SZ]);
+        let root = efs.build_hash_tree();
+        efs.sync();
+        (block_device, root)
+    }
+
+    /// The block holding the data of `a`
+    fn data_block(block_device: &Arc<dyn BlockDevice>) -> usize {
+        let mut buf = [0u8; BLOCK_SZ];
+        (0..TOTAL_BLOCKS as usize)
+            .find(|block_id| {
+                block_device.read_block(*block_id, &mut buf);
+                buf.iter().all(|byte| *byte == PATTERN)
+            })
+            .unwrap()
+    }
+
+    fn flip_byte(block_device: &Arc<dyn BlockDevice>, block_id: usize, offset: usize) {
+        let mut buf = [0u8; BLOCK_SZ];
+        block_device.read_block(block_id, &mut buf);
+        buf[offset] ^= 1;
+        block_device.write_block(block_id, &buf);
+    }
+
+    fn read_a(block_device: Arc<dyn BlockDevice>, root: Hash) -> [u8; BLOCK_SZ] {
+        let efs = FileSystem::open_verified(block_device, root);
+        let file = FileSystem::root_inode(&efs).find("a").unwrap();
+        let mut buf = [0u8; BLOCK_SZ];
+        assert_eq!(file.read_at(0, &mut buf), BLOCK_SZ);
+        buf
+    }
+
+    #[test]
+    fn read_verified_image() {
+        let (block_device, root) = image();
+        assert_eq!(read_a(block_device, root), [PATTERN; BLOCK_SZ]);
+    }
+
+    #[test]
+    fn orphans_are_left_alone() {
+        let block_device = ram_device((TOTAL_BLOCKS + hash_tree_blocks(TOTAL_BLOCKS)) as usize);
+        let efs = FileSystem::create(Arc::clone(&block_device), TOTAL_BLOCKS, 1);
+        let file = FileSystem::root_inode(&efs).create("a").unwrap();
+        file.write_at(0, &[PATTERN; BLOCK_SZ]);
+        // 像删除时仍被打开的文件那样留下一条孤儿记录，只读挂载不能回收它
+        assert!(efs.add_orphan(Orphan {
+            inode_id: file.inode_id(),
+            parent_id: 0,
+        }));
+        let root = efs.build_hash_tree();
+        efs.sync();
+        let efs = FileSystem::open_verified(Arc::clone(&block_device), root);
+        assert_eq!(efs.orphans().len(), 1);
+        assert_eq!(read_a(block_device, root), [PATTERN; BLOCK_SZ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "has been tampered with")]
+    fn tampered_data_block() {
+        let (block_device, root) = image();
+        let block_id = data_block(&block_device);
+        flip_byte(&block_device, block_id, 0);
+        read_a(block_device, root);
+    }
+
+    #[test]
+    #[should_panic(expected = "has been tampered with")]
+    fn tampered_tree_block() {
+        let (block_device, root) = image();
+        let block_id = data_block(&block_device);
+        // 改动同一叶子块中另一个块的哈希，叶子层仍然匹配，在上一层被发现
+        let slot = (block_id + 1) % HASHES_PER_BLOCK;
+        let (start, _) = tree_levels(TOTAL_BLOCKS as usize)[0];
+        flip_byte(&block_device, start + block_id / HASHES_PER_BLOCK, slot * HASH_SZ);
+        read_a(block_device, root);
+    }
+
+    #[test]
+    #[should_panic(expected = "root hash mismatch")]
+    fn rebuilt_tree_does_not_match_trusted_root() {
+        let (block_device, root) = image();
+        let block_id = data_block(&block_device);
+        flip_byte(&block_device, block_id, 0);
+        // 连同哈希树和超级块中的根哈希一起改写
+        build(&block_device);
+        read_a(block_device, root);
+    }
+}
@@ -1,186 +1,980 @@
 use super::{
-    block_cache_sync_all, get_block_cache, BlockDevice, DirEntry, DiskInode, DiskInodeType,
-    FileSystem, DIRENT_SZ,
+    block_cache_sync_all, compress, get_block_cache, BlockDevice, DirEntry, DirEntryInfo, DirIter,
+    DirOps, DiskInode, DiskInodeType, FileOps, FileSystem, FileType, InodeCipher, InodeOps, Orphan, VfsInode,
+    BLOCK_SZ, DIRENT_SZ, FEATURE_INCOMPAT_COMPRESSION, FEATURE_INCOMPAT_ENCRYPTION, NAME_LENGTH_LIMIT,
 };
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use spin::{Mutex, MutexGuard};
-/// Virtual filesystem layer over easy-fs
+use spin::{Mutex, RwLock};
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// Virtual filesystem layer over easy-fs, the easy-fs implementation of [`VfsInode`]
 pub struct Inode {
+    inode_id: u32,
     block_id: usize,
     block_offset: usize,
-    fs: Arc<Mutex<FileSystem>>,
+    fs: Arc<FileSystem>,
     block_device: Arc<dyn BlockDevice>,
+    /// 保护磁盘 inode 的大小和块映射：读者共享，改动大小、块映射或标志时独占
+    lock: RwLock<()>,
+    /// 已被删除时的孤儿记录，最后一个句柄释放时回收 inode
+    unlinked: Mutex<Option<Orphan>>,
 }
 
 impl Inode {
     /// Create a vfs inode
     pub fn new(
+        inode_id: u32,
         block_id: u32,
         block_offset: usize,
-        fs: Arc<Mutex<FileSystem>>,
+        fs: Arc<FileSystem>,
         block_device: Arc<dyn BlockDevice>,
     ) -> Self {
         Self {
+            inode_id,
             block_id: block_id as usize,
             block_offset,
             fs,
             block_device,
+            lock: RwLock::new(()),
+            unlinked: Mutex::new(None),
         }
     }
-    /// Call a function over a disk inode to read it
-    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
+    /// Get a copy of the disk inode, the caller must hold the inode lock
+    fn load_disk_inode(&self) -> DiskInode {
         get_block_cache(self.block_id, Arc::clone(&self.block_device))
             .lock()
-            .read(self.block_offset, f)
+            .get(self.block_offset)
     }
-    /// Call a function over a disk inode to modify it
-    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+    /// Write back the disk inode, the caller must hold the inode lock for writing
+    fn store_disk_inode(&self, disk_inode: &DiskInode) {
         get_block_cache(self.block_id, Arc::clone(&self.block_device))
             .lock()
-            .modify(self.block_offset, f)
+            .set(self.block_offset, disk_inode);
+    }
+    /// Call a function over a disk inode to read it
+    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
+        let _guard = self.lock.read();
+        f(&self.load_disk_inode())
+    }
+    /// Call a function over a disk inode to modify it
+    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+        let _guard = self.lock.write();
+        let mut disk_inode = self.load_disk_inode();
+        let ret = f(&mut disk_inode);
+        self.store_disk_inode(&disk_inode);
+        ret
+    }
+    /// Whether the disk inode is encrypted while no key has been supplied,
+    /// such an inode can be neither read nor written
+    fn is_locked(&self, disk_inode: &DiskInode) -> bool {
+        disk_inode.is_encrypted() && self.cipher(disk_inode).is_none()
+    }
+    /// Cipher for the data of an encrypted disk inode
+    fn cipher(&self, disk_inode: &DiskInode) -> Option<InodeCipher> {
+        if disk_inode.is_encrypted() {
+            self.fs.inode_cipher(self.inode_id)
+        } else {
+            None
+        }
     }
     /// Find inode under a disk inode by name
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
+    fn find_inode_id(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+        cipher: Option<&InodeCipher>,
+    ) -> Option<u32> {
+        self.find_dirent(name, disk_inode, cipher)
+            .map(|(_, dirent)| dirent.inode_number())
+    }
+    /// Find the entry `name` under a disk inode, with its position
+    fn find_dirent(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+        cipher: Option<&InodeCipher>,
+    ) -> Option<(usize, DirEntry)> {
         // assert it is a directory
         assert!(disk_inode.is_dir());
         let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
-            }
-        }
-        None
+        (0..file_count)
+            .map(|i| (i, self.dirent_at(disk_inode, i, cipher)))
+            .find(|(_, dirent)| dirent.name() == Some(name))
+    }
+    /// Read the `index`th entry of a directory disk inode
+    fn dirent_at(
+        &self,
+        disk_inode: &DiskInode,
+        index: usize,
+        cipher: Option<&InodeCipher>,
+    ) -> DirEntry {
+        let mut bytes = [0u8; DIRENT_SZ];
+        assert_eq!(
+            disk_inode.read_at(DIRENT_SZ * index, &mut bytes, &self.block_device, cipher),
+            DIRENT_SZ,
+        );
+        DirEntry::from_bytes(&bytes)
     }
-    /// Find inode under current inode by name
+    /// Find inode under current inode by name, `None` if the entry is corrupted
     pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
-        let fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
-        })
+        let inode_id = self.read_disk_inode(|disk_inode| {
+            if self.is_locked(disk_inode) {
+                return None;
+            }
+            let cipher = self.cipher(disk_inode);
+            self.find_inode_id(name, disk_inode, cipher.as_ref())
+        })?;
+        self.entry_type(inode_id)?;
+        Some(self.fs.get_inode(inode_id))
     }
-    /// Increase the size of a disk inode
-    fn increase_size(
-        &self,
-        new_size: u32,
-        disk_inode: &mut DiskInode,
-        fs: &mut MutexGuard<FileSystem>,
-    ) {
+    /// Type of inode `inode_id` an entry refers to, `None` if the entry is corrupted: the inode
+    /// is out of range, free or of unknown type. The type never changes after creation,
+    /// so no lock is needed.
+    fn entry_type(&self, inode_id: u32) -> Option<DiskInodeType> {
+        if !self.fs.is_inode_allocated(inode_id) {
+            return None;
+        }
+        let (block_id, block_offset) = self.fs.get_disk_inode_pos(inode_id);
+        match get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| disk_inode.type_())
+        {
+            DiskInodeType::Unknown(_) => None,
+            type_ => Some(type_),
+        }
+    }
+    /// Increase the size of a disk inode, the new blocks are charged to its owner.
+    /// Return false if the owner's quota is exceeded.
+    fn increase_size(&self, new_size: u32, disk_inode: &mut DiskInode) -> bool {
         if new_size < disk_inode.size {
-            return;
+            return true;
+        }
+        let blocks_needed = disk_inode.blocks_num_needed(new_size, &self.block_device);
+        if !self.fs.charge(disk_inode.uid(), blocks_needed, 0) {
+            return false;
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
         let mut v: Vec<u32> = Vec::new();
         for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+            v.push(self.fs.alloc_data());
+        }
+        // 新分配的块在位图中落盘之后才被块映射引用，崩溃时至多泄漏
+        if blocks_needed > 0 {
+            block_cache_sync_all();
         }
         disk_inode.increase_size(new_size, v, &self.block_device);
+        true
     }
-    /// Create inode under current inode by name
+    /// Create a file under current inode by name
     pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
-        let op = |root_inode: &DiskInode| {
-            // assert it is a directory
-            assert!(root_inode.is_dir());
-            // has the file been created?
-            self.find_inode_id(name, root_inode)
-        };
-        if self.read_disk_inode(op).is_some() {
+        self.create_inode(name, DiskInodeType::File)
+    }
+    /// Create a directory under current inode by name
+    pub fn create_dir(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+    /// Create an inode of `type_` under current inode by name
+    fn create_inode(&self, name: &str, type_: DiskInodeType) -> Option<Arc<Inode>> {
+        if name.len() > NAME_LENGTH_LIMIT {
+            return None;
+        }
+        // 持有目录的写锁直到目录项写入，避免同名文件被并发创建两次
+        let _guard = self.lock.write();
+        let mut root_inode = self.load_disk_inode();
+        // assert it is a directory
+        assert!(root_inode.is_dir());
+        if self.is_locked(&root_inode) {
+            return None;
+        }
+        // has the file been created?
+        let cipher = self.cipher(&root_inode);
+        if self
+            .find_inode_id(name, &root_inode, cipher.as_ref())
+            .is_some()
+        {
+            return None;
+        }
+        // 新文件继承目录的加密策略和属主
+        let encrypted = root_inode.encrypts_children();
+        let names_encrypted = root_inode.is_encrypted();
+        let uid = root_inode.uid();
+        if !self.fs.charge(uid, 0, 1) {
             return None;
         }
         // create a new file
         // alloc a inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
+        let orphan = self.fs.alloc_orphan_inode(self.inode_id);
+        let new_inode_id = orphan.inode_id;
         // initialize inode
-        let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
+        let (new_inode_block_id, new_inode_block_offset) = self.fs.get_disk_inode_pos(new_inode_id);
         get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                new_inode.initialize(DiskInodeType::File);
+                new_inode.initialize(type_);
+                new_inode.set_uid(uid);
+                if encrypted {
+                    new_inode.set_encrypted(names_encrypted);
+                }
             });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
+        // append file in the dirent
+        let file_count = (root_inode.size as usize) / DIRENT_SZ;
+        let new_size = (file_count + 1) * DIRENT_SZ;
+        // increase size
+        if !self.increase_size(new_size as u32, &mut root_inode) {
+            self.fs.release(uid, 0, 1);
+            self.fs.dealloc_orphan_inode(orphan);
+            self.fs.sync();
+            return None;
+        }
+        // write dirent
+        let dirent = DirEntry::new(name, new_inode_id);
+        root_inode.write_at(
+            file_count * DIRENT_SZ,
+            &dirent.to_bytes(),
+            &self.block_device,
+            cipher.as_ref(),
+        );
+        // 新 inode、新分配的块和目录项先落盘，最后才更新目录的大小，
+        // 崩溃后目录中不会出现尚未写入的目录项或指向空闲 inode 的目录项
+        block_cache_sync_all();
+        self.store_disk_inode(&root_inode);
+        block_cache_sync_all();
+        // 目录项落盘之后才能撤销孤儿记录
+        self.fs.remove_orphan(orphan);
+        block_cache_sync_all();
+        // return inode
+        Some(self.fs.get_inode(new_inode_id))
+    }
+    /// Remove the entry `name` from current directory and free its inode,
+    /// or once the last handle to it is dropped if the inode is still in use.
+    /// A directory can only be removed when it is empty.
+    /// Return false if there is no such entry or it cannot be removed.
+    pub fn unlink(&self, name: &str) -> bool {
+        let guard = self.lock.write();
+        let mut dir_inode = self.load_disk_inode();
+        assert!(dir_inode.is_dir());
+        if self.is_locked(&dir_inode) {
+            return false;
+        }
+        let cipher = self.cipher(&dir_inode);
+        let (pos, dirent) = match self.find_dirent(name, &dir_inode, cipher.as_ref()) {
+            Some(found) => found,
+            None => return false,
+        };
+        let inode_id = dirent.inode_number();
+        if inode_id == self.inode_id {
+            return false;
+        }
+        let inode = self.fs.get_inode(inode_id);
+        if inode.is_dir() && inode.size() > 0 {
+            return false;
+        }
+        // 目录项删除之前记入孤儿列表，崩溃后挂载时回收。列表满时不受保护
+        let orphan = Orphan {
+            inode_id,
+            parent_id: self.inode_id,
+        };
+        self.fs.add_orphan(orphan);
+        // 目录项紧密排列：最后一项移入空位，目录缩小一项，只释放因此变空的末尾块。
+        // 移动之后、缩小之前崩溃，最后一项会出现两次，挂载时处理孤儿列表一并去掉
+        let last = dir_inode.size as usize / DIRENT_SZ - 1;
+        if pos != last {
+            let last_dirent = self.dirent_at(&dir_inode, last, cipher.as_ref());
+            dir_inode.write_at(
+                pos * DIRENT_SZ,
+                &last_dirent.to_bytes(),
                 &self.block_device,
+                cipher.as_ref(),
             );
-        });
-
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
+            block_cache_sync_all();
+        }
+        let data_blocks_dealloc =
+            dir_inode.decrease_size((last * DIRENT_SZ) as u32, &self.block_device);
+        self.store_disk_inode(&dir_inode);
+        // 缩小后的目录落盘之后才释放末尾的块
         block_cache_sync_all();
-        // return inode
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
-        // release efs lock automatically by compiler
+        self.fs
+            .release(dir_inode.uid(), data_blocks_dealloc.len() as u32, 0);
+        for data_block in data_blocks_dealloc.into_iter() {
+            self.fs.dealloc_data(data_block);
+        }
+        drop(guard);
+        // 仍被打开的文件照常读写，最后一个句柄释放时才回收 inode 及其数据
+        *inode.unlinked.lock() = Some(orphan);
+        drop(inode);
+        self.fs.sync();
+        true
     }
     /// List inodes under current inode
     pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
             let file_count = (disk_inode.size as usize) / DIRENT_SZ;
             let mut v: Vec<String> = Vec::new();
+            if self.is_locked(disk_inode) {
+                return v;
+            }
+            let cipher = self.cipher(disk_inode);
             for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
+                let mut bytes = [0u8; DIRENT_SZ];
                 assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
+                    disk_inode.read_at(
+                        i * DIRENT_SZ,
+                        &mut bytes,
+                        &self.block_device,
+                        cipher.as_ref(),
+                    ),
                     DIRENT_SZ,
                 );
-                v.push(String::from(dirent.name()));
+                // 名字损坏的目录项不列出
+                if let Some(name) = DirEntry::from_bytes(&bytes).name() {
+                    v.push(String::from(name));
+                }
             }
             v
         })
     }
+    /// Iterate over the entries of current directory, starting from `cookie`.
+    /// The cookie of the first entry is 0.
+    pub fn read_dir(&self, cookie: usize) -> DirIter<'_> {
+        DirIter::new(self, cookie)
+    }
+    /// Read the directory entry at `cookie`, `None` past the last one.
+    /// Corrupted entries are skipped, see [`Inode::entry_type`].
+    fn read_dir_entry(&self, mut cookie: usize) -> Option<DirEntryInfo> {
+        loop {
+            let dirent = self.dirent_at_cookie(cookie)?;
+            let type_ = self.entry_type(dirent.inode_number());
+            cookie += 1;
+            match (dirent.name(), type_) {
+                (Some(name), Some(type_)) => {
+                    return Some(DirEntryInfo {
+                        name: String::from(name),
+                        inode_number: dirent.inode_number(),
+                        type_: match type_ {
+                            DiskInodeType::Directory => FileType::Directory,
+                            _ => FileType::File,
+                        },
+                        next_cookie: cookie,
+                    })
+                }
+                _ => continue,
+            }
+        }
+    }
+    /// Read the directory entry at `cookie`, `None` past the last one
+    fn dirent_at_cookie(&self, cookie: usize) -> Option<DirEntry> {
+        self.read_disk_inode(|disk_inode| {
+            assert!(disk_inode.is_dir());
+            if cookie >= disk_inode.size as usize / DIRENT_SZ || self.is_locked(disk_inode) {
+                return None;
+            }
+            let mut bytes = [0u8; DIRENT_SZ];
+            assert_eq!(
+                disk_inode.read_at(
+                    cookie * DIRENT_SZ,
+                    &mut bytes,
+                    &self.block_device,
+                    self.cipher(disk_inode).as_ref(),
+                ),
+                DIRENT_SZ,
+            );
+            Some(DirEntry::from_bytes(&bytes))
+        })
+    }
+    /// Get the inode number of current inode
+    pub fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+    /// Whether current inode is a directory
+    pub fn is_dir(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+    /// Get the size of current inode in bytes
+    pub fn size(&self) -> usize {
+        self.read_disk_inode(|disk_inode| disk_inode.size as usize)
+    }
     /// Read data from current inode
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
+        self.read_disk_inode(|disk_inode| {
+            if self.is_locked(disk_inode) {
+                return 0;
+            }
+            let cipher = self.cipher(disk_inode);
+            disk_inode.read_at(offset, buf, &self.block_device, cipher.as_ref())
+        })
     }
     /// Write data to current inode
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
         let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
+            if self.is_locked(disk_inode) {
+                return 0;
+            }
+            let cipher = self.cipher(disk_inode);
+            if !self.increase_size((offset + buf.len()) as u32, disk_inode) {
+                return 0;
+            }
+            if disk_inode.is_compressed() {
+                compress::write_at(disk_inode, offset, buf, &self.fs, cipher.as_ref())
+            } else {
+                disk_inode.write_at(offset, buf, &self.block_device, cipher.as_ref())
+            }
         });
-        block_cache_sync_all();
+        self.fs.sync();
         size
     }
+    /// Reserve the blocks holding bytes `offset..offset + len` ahead of time and zero them,
+    /// taking them in contiguous runs where possible. The size grows to cover them unless
+    /// `keep_size`, writes up to there then need no more blocks.
+    /// Return false if there is not enough space, the owner's quota is exceeded
+    /// or the inode is a directory, compressed or locked.
+    pub fn allocate(&self, offset: usize, len: usize, keep_size: bool) -> bool {
+        let done = self.modify_disk_inode(|disk_inode| {
+            if disk_inode.is_dir() || disk_inode.is_compressed() || self.is_locked(disk_inode) {
+                return false;
+            }
+            let old_size = disk_inode.size as usize;
+            let end = (offset + len).max(old_size);
+            let blocks_needed = disk_inode.blocks_num_needed(end as u32, &self.block_device);
+            if !self.fs.charge(disk_inode.uid(), blocks_needed, 0) {
+                return false;
+            }
+            let new_blocks = match self.fs.alloc_data_runs(blocks_needed) {
+                Some(new_blocks) => new_blocks,
+                None => {
+                    self.fs.release(disk_inode.uid(), blocks_needed, 0);
+                    return false;
+                }
+            };
+            let first_new = disk_inode.mapped_blocks(&self.block_device);
+            if keep_size {
+                disk_inode.preallocate(end as u32, new_blocks, &self.block_device);
+            } else {
+                disk_inode.increase_size(end as u32, new_blocks, &self.block_device);
+            }
+            let cipher = self.cipher(disk_inode);
+            for inner_id in first_new..disk_inode.mapped_blocks(&self.block_device) {
+                let block_id = disk_inode.get_block_id(inner_id, &self.block_device);
+                get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| {
+                        data_block.fill(0);
+                        if let Some(cipher) = cipher.as_ref() {
+                            cipher.encrypt_block(inner_id, data_block);
+                        }
+                    });
+            }
+            // 加密文件原来最后一块中超出大小的部分解密后不是 0
+            if !keep_size && cipher.is_some() {
+                let tail = old_size.next_multiple_of(BLOCK_SZ).min(end) - old_size;
+                disk_inode.write_at(
+                    old_size,
+                    &[0u8; BLOCK_SZ][..tail],
+                    &self.block_device,
+                    cipher.as_ref(),
+                );
+            }
+            true
+        });
+        block_cache_sync_all();
+        done
+    }
+    /// Enable or disable transparent compression of the file.
+    /// Only an empty file can be switched, return whether it succeeded.
+    pub fn set_compressed(&self, compressed: bool) -> bool {
+        let done = self.modify_disk_inode(|disk_inode| disk_inode.set_compressed(compressed));
+        if done && compressed {
+            self.fs.enable_features(0, FEATURE_INCOMPAT_COMPRESSION);
+        }
+        done
+    }
+    /// Whether the file is stored compressed
+    pub fn is_compressed(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_compressed())
+    }
+    /// Encrypt the file with the key supplied at mount time.
+    /// For a directory, the inodes created in it are encrypted and `names` also encrypts its entries.
+    /// Only an empty inode can be switched, return whether it succeeded.
+    pub fn set_encrypted(&self, names: bool) -> bool {
+        if self.fs.inode_cipher(self.inode_id).is_none() {
+            return false;
+        }
+        let done = self.modify_disk_inode(|disk_inode| disk_inode.set_encrypted(names));
+        if done {
+            self.fs.enable_features(0, FEATURE_INCOMPAT_ENCRYPTION);
+        }
+        done
+    }
+    /// Whether the data of the inode is stored encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_encrypted())
+    }
     /// Clear the data in current inode
     pub fn clear(&self) {
-        let mut fs = self.fs.lock();
-        self.modify_disk_inode(|disk_inode| {
+        let (uid, data_blocks_dealloc) = self.modify_disk_inode(|disk_inode| {
             let size = disk_inode.size;
+            let compressed = disk_inode.is_compressed();
+            let preallocated = disk_inode.is_preallocated();
             let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
+            // 压缩文件的块映射中有空洞，释放的块数少于按大小计算的块数；预分配的文件则多于
+            assert!(
+                compressed
+                    || preallocated
+                    || data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize
+            );
+            (disk_inode.uid(), data_blocks_dealloc)
+        });
+        // 清空的块映射落盘之后才释放其中的块，崩溃时至多泄漏，不会有 inode 引用空闲块
+        block_cache_sync_all();
+        self.fs.release(uid, data_blocks_dealloc.len() as u32, 0);
+        for data_block in data_blocks_dealloc.into_iter() {
+            self.fs.dealloc_data(data_block);
+        }
+        self.fs.sync();
+    }
+    /// Get the owner of current inode
+    pub fn owner(&self) -> u16 {
+        self.read_disk_inode(|disk_inode| disk_inode.uid())
+    }
+    /// Hand current inode over to `uid`, moving its usage between the quotas.
+    /// Return false if the new owner's quota is exceeded.
+    pub fn set_owner(&self, uid: u16) -> bool {
+        let changed = self.modify_disk_inode(|disk_inode| {
+            let old_uid = disk_inode.uid();
+            if old_uid == uid {
+                return true;
+            }
+            let blocks = disk_inode.allocated_blocks(&self.block_device);
+            if !self.fs.charge(uid, blocks, 1) {
+                return false;
             }
+            self.fs.release(old_uid, blocks, 1);
+            disk_inode.set_uid(uid);
+            true
         });
         block_cache_sync_all();
+        changed
+    }
+}
+
+impl Drop for Inode {
+    fn drop(&mut self) {
+        if let Some(orphan) = self.unlinked.get_mut().take() {
+            self.clear();
+            self.fs.release(self.owner(), 0, 1);
+            self.fs.dealloc_orphan_inode(orphan);
+            self.fs.sync();
+        }
+    }
+}
+
+impl InodeOps for Inode {
+    fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+    fn is_dir(&self) -> bool {
+        Inode::is_dir(self)
+    }
+    fn size(&self) -> usize {
+        Inode::size(self)
+    }
+    fn owner(&self) -> u16 {
+        Inode::owner(self)
+    }
+    fn set_owner(&self, uid: u16) -> bool {
+        Inode::set_owner(self, uid)
+    }
+    fn is_compressed(&self) -> bool {
+        Inode::is_compressed(self)
+    }
+    fn set_compressed(&self, compressed: bool) -> bool {
+        Inode::set_compressed(self, compressed)
+    }
+    fn is_encrypted(&self) -> bool {
+        Inode::is_encrypted(self)
+    }
+    fn set_encrypted(&self, names: bool) -> bool {
+        Inode::set_encrypted(self, names)
+    }
+}
+
+impl DirOps for Inode {
+    fn name_length_limit(&self) -> usize {
+        NAME_LENGTH_LIMIT
+    }
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        Inode::find(self, name).map(|inode| inode as Arc<dyn VfsInode>)
+    }
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        Inode::create(self, name).map(|inode| inode as Arc<dyn VfsInode>)
+    }
+    fn create_dir(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        Inode::create_dir(self, name).map(|inode| inode as Arc<dyn VfsInode>)
+    }
+    fn unlink(&self, name: &str) -> bool {
+        Inode::unlink(self, name)
+    }
+    fn dir_entry(&self, cookie: usize) -> Option<DirEntryInfo> {
+        self.read_dir_entry(cookie)
+    }
+    fn read_dir(&self, cookie: usize) -> DirIter<'_> {
+        DirIter::new(self, cookie)
+    }
+    fn open_entry(&self, entry: &DirEntryInfo) -> Option<Arc<dyn VfsInode>> {
+        self.entry_type(entry.inode_number)?;
+        Some(self.fs.get_inode(entry.inode_number))
+    }
+}
+
+impl FileOps for Inode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        Inode::read_at(self, offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        Inode::write_at(self, offset, buf)
+    }
+    fn clear(&self) {
+        Inode::clear(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Inode;
+    use crate::compress::{CLUSTER_BLOCKS, CLUSTER_SZ};
+    use crate::{DiskInodeType, FileSystem, FileType, Orphan, RamBlockDevice, DIRENT_SZ};
+    use alloc::format;
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn new_fs(total_blocks: usize) -> Arc<FileSystem> {
+        FileSystem::create(Arc::new(RamBlockDevice::new(total_blocks)), total_blocks as u32, 1)
+    }
+
+    #[test]
+    fn create_find_ls() {
+        let efs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&efs);
+        let a = root_inode.create("a").unwrap();
+        root_inode.create("b").unwrap();
+        assert!(root_inode.create("a").is_none());
+        assert!(Arc::ptr_eq(&root_inode.find("a").unwrap(), &a));
+        assert!(root_inode.find("c").is_none());
+        assert_eq!(root_inode.ls(), ["a", "b"]);
+    }
+
+    #[test]
+    fn mkdir_and_unlink() {
+        let efs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&efs);
+        let used = efs.quota(0).unwrap();
+        let dir = root_inode.create_dir("bin").unwrap();
+        assert!(dir.is_dir());
+        let file = dir.create("sh").unwrap();
+        file.write_at(0, &[5u8; 40_000]);
+        for i in 0..30 {
+            root_inode.create(&format!("tmp{}", i)).unwrap();
+        }
+        let entries: Vec<_> = root_inode.read_dir(0).take(1).collect();
+        assert_eq!(entries[0].type_, FileType::Directory);
+        // 非空目录不能删除
+        assert!(!root_inode.unlink("bin"));
+        drop(file);
+        assert!(dir.unlink("sh"));
+        assert!(!dir.unlink("sh"));
+        assert!(dir.find("sh").is_none());
+        drop(dir);
+        assert!(root_inode.unlink("bin"));
+        for i in 0..30 {
+            assert!(root_inode.unlink(&format!("tmp{}", i)));
+        }
+        assert!(root_inode.ls().is_empty());
+        assert!(efs.check().is_empty());
+        let now = efs.quota(0).unwrap();
+        assert_eq!(now.blocks_used, used.blocks_used);
+        assert_eq!(now.inodes_used, used.inodes_used);
+    }
+
+    #[test]
+    fn unlink_open_file() {
+        let efs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&efs);
+        let stat = efs.stat();
+        let file = root_inode.create("tmp").unwrap();
+        file.write_at(0, &[6u8; 5000]);
+        assert!(root_inode.unlink("tmp"));
+        assert!(root_inode.find("tmp").is_none());
+        // 仍打开的文件照常读写，inode 留在孤儿列表中
+        assert_eq!(file.write_at(5000, &[7u8; 1000]), 1000);
+        let mut buf = [0u8; 6000];
+        assert_eq!(file.read_at(0, &mut buf), 6000);
+        assert!(buf[..5000].iter().all(|byte| *byte == 6));
+        let orphan = Orphan {
+            inode_id: file.inode_id(),
+            parent_id: 0,
+        };
+        assert_eq!(efs.orphans(), [orphan]);
+        assert!(efs.check().is_empty());
+        drop(file);
+        assert!(efs.orphans().is_empty());
+        assert_eq!(efs.features().1, 0);
+        assert_eq!(efs.stat().free_inodes, stat.free_inodes);
+        assert_eq!(efs.stat().free_data_blocks, stat.free_data_blocks);
+        assert!(efs.check().is_empty());
+    }
+
+    #[test]
+    fn read_dir_resumes_from_cookie() {
+        let efs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&efs);
+        for i in 0..40 {
+            root_inode.create(&format!("f{}", i)).unwrap();
+        }
+        let mut iter = root_inode.read_dir(0);
+        let mut entries: Vec<_> = iter.by_ref().take(15).collect();
+        entries.extend(root_inode.read_dir(iter.cookie()));
+        assert_eq!(entries.len(), 40);
+        for (i, entry) in entries.iter().enumerate() {
+            assert_eq!(entry.name, format!("f{}", i));
+            assert_eq!(entry.inode_number, i as u32 + 1);
+            assert_eq!(entry.type_, FileType::File);
+            assert_eq!(entry.next_cookie, i + 1);
+        }
+        assert!(root_inode.read_dir(40).next().is_none());
+    }
+
+    #[test]
+    fn write_read_clear() {
+        let efs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&efs);
+        let file = root_inode.create("data").unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(file.write_at(0, &data), data.len());
+        assert_eq!(file.size(), data.len());
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(file.read_at(0, &mut buf), data.len());
+        assert!(buf == data);
+        assert_eq!(file.write_at(99_990, b"tail of the file"), 16);
+        assert_eq!(file.size(), 100_006);
+        let before = efs.quota(0).unwrap().blocks_used;
+        file.clear();
+        assert_eq!(file.size(), 0);
+        assert_eq!(file.read_at(0, &mut buf), 0);
+        assert!(efs.quota(0).unwrap().blocks_used < before);
+    }
+
+    #[test]
+    fn allocate_ahead() {
+        let efs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&efs);
+        // 打乱空闲块，预分配仍应取连续的一段
+        for i in 0..20 {
+            root_inode.create(&format!("f{}", i)).unwrap().write_at(0, &[1u8; 600]);
+        }
+        for i in (0..20).step_by(2) {
+            assert!(root_inode.unlink(&format!("f{}", i)));
+        }
+        let log = root_inode.create("log").unwrap();
+        let used = efs.quota(0).unwrap().blocks_used;
+        // 跨过一级和二级索引
+        assert!(log.allocate(0, 100_000, true));
+        assert_eq!(log.size(), 0);
+        assert_eq!(efs.inode_fragments(log.inode_id()), 1);
+        let reserved = efs.quota(0).unwrap().blocks_used;
+        assert!(reserved > used + 100_000 / 512);
+        assert!(efs.check().is_empty());
+        let data: Vec<u8> = (0..90_000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(log.write_at(0, &data), data.len());
+        assert_eq!(log.size(), data.len());
+        assert_eq!(efs.quota(0).unwrap().blocks_used, reserved);
+        // 超出预分配的部分照常分配
+        assert_eq!(log.write_at(99_000, &[2u8; 5000]), 5000);
+        assert!(efs.quota(0).unwrap().blocks_used > reserved);
+        let mut buf = vec![0u8; 104_000];
+        assert_eq!(log.read_at(0, &mut buf), 104_000);
+        assert!(buf[..90_000] == data[..]);
+        assert!(buf[90_000..99_000].iter().all(|byte| *byte == 0));
+        assert!(buf[99_000..].iter().all(|byte| *byte == 2));
+        log.clear();
+        assert_eq!(efs.quota(0).unwrap().blocks_used, used);
+        // 不保持大小时文件变长，新的部分读出 0
+        assert!(log.allocate(1000, 3000, false));
+        assert_eq!(log.size(), 4000);
+        assert_eq!(log.read_at(0, &mut buf), 4000);
+        assert!(buf[..4000].iter().all(|byte| *byte == 0));
+        assert!(!log.allocate(0, 4096 * 512, true));
+        assert_eq!(log.size(), 4000);
+        assert!(!root_inode.allocate(0, 512, false));
+        log.clear();
+        assert!(log.allocate(0, 512, true));
+        assert!(!log.set_compressed(true));
+        assert!(efs.check().is_empty());
+    }
+
+    #[test]
+    fn compressed_file() {
+        let efs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&efs);
+        let file = root_inode.create("elf").unwrap();
+        assert!(file.set_compressed(true));
+        let data: Vec<u8> = (0..50_000).map(|i| (i / 1000) as u8).collect();
+        assert_eq!(file.write_at(0, &data), data.len());
+        assert!(!file.set_compressed(false));
+        let mut buf = vec![0u8; 3000];
+        assert_eq!(file.read_at(20_000, &mut buf), 3000);
+        assert!(buf[..] == data[20_000..23_000]);
+    }
+
+    /// Bytes from a xorshift generator, which LZ4 cannot compress
+    fn noise(len: usize, mut seed: u32) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect()
+    }
+
+    /// Number of blocks stored for a cluster of a compressed file
+    fn cluster_blocks(file: &Inode, cluster: usize) -> usize {
+        file.read_disk_inode(|disk_inode| {
+            (0..CLUSTER_BLOCKS)
+                .filter(|i| {
+                    let inner_id = (cluster * CLUSTER_BLOCKS + i) as u32;
+                    disk_inode.get_block_id(inner_id, &file.block_device) != 0
+                })
+                .count()
+        })
+    }
+
+    fn blocks_used(efs: &FileSystem) -> u32 {
+        efs.quota(0).unwrap().blocks_used
+    }
+
+    #[test]
+    fn compressed_cluster_storage() {
+        let efs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&efs);
+        let file = root_inode.create("elf").unwrap();
+        assert!(file.set_compressed(true));
+        let mut data = noise(CLUSTER_SZ, 1);
+        data.extend_from_slice(&[0u8; CLUSTER_SZ]);
+        data.extend((0..CLUSTER_SZ).map(|i| (i / 100) as u8));
+        assert_eq!(file.write_at(0, &data), data.len());
+        // 压缩后放不下的簇按原样存储，全 0 的簇是空洞
+        assert_eq!(cluster_blocks(&file, 0), CLUSTER_BLOCKS);
+        assert_eq!(cluster_blocks(&file, 1), 0);
+        assert!((1..CLUSTER_BLOCKS).contains(&cluster_blocks(&file, 2)));
+        let mut buf = vec![0xffu8; data.len()];
+        assert_eq!(file.read_at(0, &mut buf), data.len());
+        assert!(buf == data);
+        assert!(efs.check().is_empty());
+    }
+
+    #[test]
+    fn compressed_partial_writes_and_reads() {
+        let efs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&efs);
+        let file = root_inode.create("elf").unwrap();
+        assert!(file.set_compressed(true));
+        let mut data: Vec<u8> = (0..3 * CLUSTER_SZ).map(|i| (i / 300) as u8).collect();
+        assert_eq!(file.write_at(0, &data), data.len());
+        // 覆盖一个簇的中间，以及跨越簇边界的一段
+        for (offset, len, seed) in [(1000, 200, 2), (CLUSTER_SZ - 100, 300, 3)] {
+            let patch = noise(len, seed);
+            assert_eq!(file.write_at(offset, &patch), len);
+            data[offset..offset + len].copy_from_slice(&patch);
+        }
+        // 读取不从簇边界开始，也不在簇边界结束
+        let reads = [
+            (1, 10),
+            (999, 300),
+            (CLUSTER_SZ - 7, CLUSTER_SZ + 14),
+            (5000, 6000),
+        ];
+        for (offset, len) in reads {
+            let mut buf = vec![0u8; len];
+            assert_eq!(file.read_at(offset, &mut buf), len);
+            assert!(buf[..] == data[offset..offset + len]);
+        }
+        let mut buf = vec![0u8; 100];
+        assert_eq!(file.read_at(data.len() - 30, &mut buf), 30);
+        assert!(buf[..30] == data[data.len() - 30..]);
+        assert!(efs.check().is_empty());
+    }
+
+    #[test]
+    fn compressed_blocks_are_released() {
+        let efs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&efs);
+        let file = root_inode.create("elf").unwrap();
+        assert!(file.set_compressed(true));
+        let before = blocks_used(&efs);
+        let free_blocks = efs.stat().free_data_blocks;
+        assert_eq!(file.write_at(0, &noise(2 * CLUSTER_SZ, 4)), 2 * CLUSTER_SZ);
+        let written = blocks_used(&efs);
+        assert!(written >= before + 2 * CLUSTER_BLOCKS as u32);
+        // 整簇写成 0 变为空洞，释放它的块
+        assert_eq!(file.write_at(0, &[0u8; CLUSTER_SZ]), CLUSTER_SZ);
+        assert_eq!(cluster_blocks(&file, 0), 0);
+        assert_eq!(blocks_used(&efs), written - CLUSTER_BLOCKS as u32);
+        assert_eq!(file.size(), 2 * CLUSTER_SZ);
+        file.clear();
+        assert_eq!(file.size(), 0);
+        assert_eq!(blocks_used(&efs), before);
+        assert_eq!(efs.stat().free_data_blocks, free_blocks);
+        assert!(efs.check().is_empty());
+    }
+
+    #[test]
+    fn concurrent_files() {
+        let efs = new_fs(8192);
+        let root_inode = FileSystem::root_inode(&efs);
+        let threads: Vec<_> = (0..4u8)
+            .map(|t| {
+                let root_inode = Arc::clone(&root_inode);
+                std::thread::spawn(move || {
+                    for i in 0..10 {
+                        let file = root_inode.create(&format!("t{}-{}", t, i)).unwrap();
+                        let data = vec![t ^ i; 2000 + i as usize * 300];
+                        assert_eq!(file.write_at(0, &data), data.len());
+                        let mut buf = vec![0u8; data.len()];
+                        assert_eq!(file.read_at(0, &mut buf), data.len());
+                        assert!(buf == data);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(root_inode.ls().len(), 40);
+        assert!(efs.check().is_empty());
+    }
+
+    #[test]
+    fn corrupted_entries_are_skipped() {
+        let fs = new_fs(4096);
+        let root_inode = FileSystem::root_inode(&fs);
+        for name in ["bad_type", "bad_name", "good"] {
+            root_inode.create(name).unwrap();
+        }
+        // 类型字节无法识别的 inode
+        let bad_type = root_inode.find("bad_type").unwrap();
+        bad_type.modify_disk_inode(|disk_inode| disk_inode.initialize(DiskInodeType::Unknown(7)));
+        // 名字不是 UTF-8 的目录项
+        root_inode.modify_disk_inode(|disk_inode| {
+            disk_inode.write_at(DIRENT_SZ, &[0xff], &root_inode.block_device, None);
+        });
+        assert!(root_inode.find("bad_type").is_none());
+        assert!(root_inode.find("bad_name").is_none());
+        let names: Vec<_> = root_inode.read_dir(0).map(|entry| entry.name).collect();
+        assert_eq!(names, ["good"]);
+        assert_eq!(root_inode.ls(), ["bad_type", "good"]);
+        fs.check();
     }
 }
@@ -3,4 +3,7 @@
 pub mod uart;
 
 /// CLINT 驱动模块
-pub mod clint;
\ No newline at end of file
+pub mod clint;
+
+/// virtio-blk 块设备驱动，承载根文件系统
+pub mod virtio_blk;
\ No newline at end of file
@@ -0,0 +1,85 @@
+use crate::mm::{
+    FrameTracker, PageTable, PhysicalAddr, PhysicalPageNum, VirtualAddr, frame_alloc_contiguous,
+    kernel_token,
+};
+use crate::sync::UPSafeCell;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use easy_fs::BlockDevice;
+use lazy_static::lazy_static;
+use virtio_drivers::{Hal, VirtIOBlk, VirtIOHeader};
+
+/// qemu virt 机器上第一个 virtio-mmio 设备的地址，内核地址空间中恒等映射
+const VIRTIO0: usize = 0x1000_1000;
+
+lazy_static! {
+    /// 根文件系统所在的块设备
+    pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = Arc::new(VirtIOBlock::new());
+}
+
+lazy_static! {
+    /// 分配给 virtqueue 的物理页帧，随设备一直存在
+    static ref QUEUE_FRAMES: UPSafeCell<Vec<FrameTracker>> = unsafe { UPSafeCell::new(Vec::new()) };
+}
+
+/// virtio-blk 设备
+pub struct VirtIOBlock(UPSafeCell<VirtIOBlk<'static, VirtioHal>>);
+
+impl VirtIOBlock {
+    /// 初始化 VIRTIO0 处的设备
+    pub fn new() -> Self {
+        unsafe {
+            Self(UPSafeCell::new(
+                VirtIOBlk::<VirtioHal>::new(&mut *(VIRTIO0 as *mut VirtIOHeader)).unwrap(),
+            ))
+        }
+    }
+}
+
+impl BlockDevice for VirtIOBlock {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.0
+            .exclusive_access()
+            .read_block(block_id, buf)
+            .expect("Error when reading VirtIOBlk");
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.0
+            .exclusive_access()
+            .write_block(block_id, buf)
+            .expect("Error when writing VirtIOBlk");
+    }
+}
+
+/// virtio 驱动所需的 DMA 内存与地址转换
+pub struct VirtioHal;
+
+impl Hal for VirtioHal {
+    fn dma_alloc(pages: usize) -> usize {
+        // virtqueue 要求物理连续
+        let frames = frame_alloc_contiguous(pages).unwrap();
+        let pa: PhysicalAddr = frames[0].ppn.into();
+        QUEUE_FRAMES.exclusive_access().extend(frames);
+        pa.0
+    }
+
+    fn dma_dealloc(pa: usize, pages: usize) -> i32 {
+        let ppn_base: PhysicalPageNum = PhysicalAddr::from(pa).into();
+        // 丢弃 FrameTracker 即归还页帧
+        QUEUE_FRAMES
+            .exclusive_access()
+            .retain(|frame| !(ppn_base.0..ppn_base.0 + pages).contains(&frame.ppn.0));
+        0
+    }
+
+    fn phys_to_virt(addr: usize) -> usize {
+        addr
+    }
+
+    fn virt_to_phys(vaddr: usize) -> usize {
+        PageTable::from_token(kernel_token())
+            .translate_va(VirtualAddr::from(vaddr))
+            .unwrap()
+            .0
+    }
+}
@@ -0,0 +1,56 @@
+//! 根文件系统：挂载在 virtio-blk 上的 easy-fs，用户程序从这里加载
+
+use crate::drivers::virtio_blk::BLOCK_DEVICE;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use easy_fs::{FileSystem, SuperBlockOps, VfsInode};
+use lazy_static::*;
+
+lazy_static! {
+    /// 根目录，内核只通过 VfsInode 访问文件系统
+    pub static ref ROOT_INODE: Arc<dyn VfsInode> = {
+        let efs: Arc<dyn SuperBlockOps> = FileSystem::open(BLOCK_DEVICE.clone());
+        efs.root_inode()
+    };
+}
+
+/// 挂载根文件系统，挂载时回收上次遗留的孤儿 inode
+pub fn init() {
+    lazy_static::initialize(&ROOT_INODE);
+}
+
+/// 按 `/` 分隔的路径从根目录查找
+pub fn lookup(path: &str) -> Option<Arc<dyn VfsInode>> {
+    let mut inode = ROOT_INODE.clone();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if !inode.is_dir() {
+            return None;
+        }
+        inode = inode.find(name)?;
+    }
+    Some(inode)
+}
+
+/// 读出 `path` 处文件的全部内容，用于加载 ELF
+pub fn read_file(path: &str) -> Option<Vec<u8>> {
+    let inode = lookup(path)?;
+    if inode.is_dir() {
+        return None;
+    }
+    let mut data = vec![0u8; inode.size()];
+    // 加密而未解锁的文件读不出内容
+    if inode.read_at(0, &mut data) != data.len() {
+        return None;
+    }
+    Some(data)
+}
+
+/// 列出根目录下的应用
+pub fn list_apps() {
+    println!("/**** APPS ****");
+    for entry in ROOT_INODE.read_dir(0) {
+        println!("{}", entry.name);
+    }
+    println!("**************/")
+}
@@ -35,8 +35,8 @@ mod board;
 #[macro_use]
 mod console;
 mod config;
+mod fs;
 mod lang_items;
-mod loader;
 mod mm;
 mod sbi;
 mod sync;
@@ -48,7 +48,6 @@ pub mod trap;
 pub mod drivers;
 
 core::arch::global_asm!(include_str!("entry.asm"));
-core::arch::global_asm!(include_str!("link_app.S"));
 
 /// clear BSS segment
 fn clear_bss() {
@@ -70,13 +69,14 @@ pub fn rust_main() -> ! {
     drivers::uart::init();
     mm::init();
     mm::remap_test();
+    fs::init();
     task::add_initproc();
     println!("after initproc!");
     trap::init();
     //trap::enable_interrupt();
     trap::enable_timer_interrupt();
     timer::set_next_trigger();
-    loader::list_apps();
+    fs::list_apps();
     task::run_tasks();
     panic!("Unreachable in rust_main!");
 }
\ No newline at end of file
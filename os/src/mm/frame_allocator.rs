@@ -11,6 +11,7 @@ use lazy_static::*;
 trait FrameAllocator {
     fn new() -> Self;
     fn alloc(&mut self) -> Option<PhysicalPageNum>;
+    fn alloc_contiguous(&mut self, pages: usize) -> Option<PhysicalPageNum>;
     fn dealloc(&mut self, ppn: PhysicalPageNum);
 }
 
@@ -67,6 +68,14 @@ impl FrameAllocator for StackFrameAllocator {
             }
         }
     }
+    fn alloc_contiguous(&mut self, pages: usize) -> Option<PhysicalPageNum> {
+        // 回收的页帧不保证相邻，只从未分配过的区间切出一段
+        if self.end - self.current < pages {
+            return None;
+        }
+        self.current += pages;
+        Some((self.current - pages).into())
+    }
     fn dealloc(&mut self, ppn: PhysicalPageNum) {
         let ppn = ppn.0;
         // validity check
@@ -114,6 +123,14 @@ pub fn frame_alloc() -> Option<FrameTracker> {
         .map(|ppn| FrameTracker::new(ppn))
 }
 
+///分配物理地址连续的 `pages` 个页帧，供设备 DMA 使用
+pub fn frame_alloc_contiguous(pages: usize) -> Option<Vec<FrameTracker>> {
+    let base = FRAME_ALLOCATOR
+        .exclusive_access()
+        .alloc_contiguous(pages)?;
+    Some((0..pages).map(|i| FrameTracker::new((base.0 + i).into())).collect())
+}
+
 fn frame_dealloc(ppn: PhysicalPageNum) {
     FRAME_ALLOCATOR
         .exclusive_access()
@@ -478,6 +478,11 @@ lazy_static! {
     )});
 }
 
+/// 内核地址空间的 satp，驱动据此把内核虚拟地址翻译为物理地址
+pub fn kernel_token() -> usize {
+    KERNEL_SPACE.exclusive_access().token()
+}
+
 #[allow(unused)]
 pub fn remap_test() {
     let mut kernel_space = KERNEL_SPACE.exclusive_access();
@@ -9,10 +9,11 @@ mod linked_list;
 
 pub use address::{PhysicalAddr, PhysicalPageNum, VirtualAddr, VirtualPageNum};
 use address::{StepByOne, VPNRange};
-pub use frame_allocator::{FrameTracker, frame_alloc};
+pub use frame_allocator::{FrameTracker, frame_alloc, frame_alloc_contiguous};
 pub use memory_set::remap_test;
-pub use memory_set::{KERNEL_SPACE, MapPermission, MemorySet};
-use page_table::{PTEFlags, PageTable};
+pub use memory_set::{KERNEL_SPACE, MapPermission, MemorySet, kernel_token};
+use page_table::PTEFlags;
+pub use page_table::PageTable;
 pub use page_table::{PageTableEntry, translated_byte_buffer, translated_refmut, translated_str};
 pub use linked_list::{LinkedList};    
 pub use buddy::LockedHeap;
@@ -1,7 +1,7 @@
 //! App management syscalls
 // use crate::batch::run_next_app;
 
-use crate::loader::get_app_data_by_name;
+use crate::fs::read_file;
 use crate::mm::{translated_refmut, translated_str};
 use crate::task::{
     add_task, current_task, current_user_token, exit_current_and_run_next,
@@ -52,9 +52,9 @@ pub fn sys_fork() -> isize {
 pub fn sys_exec(path: *const u8) -> isize {
     let token = current_user_token();
     let path = translated_str(token, path);
-    if let Some(data) = get_app_data_by_name(path.as_str()) {
+    if let Some(data) = read_file(path.as_str()) {
         let task = current_task().unwrap();
-        task.exec(data);
+        task.exec(data.as_slice());
         0
     } else {
         -1
@@ -18,7 +18,7 @@ mod pid;
 mod manager;
 mod processor;
 
-use crate::loader::get_app_data_by_name;
+use crate::fs::read_file;
 use crate::sbi::shutdown;
 use alloc::sync::Arc;
 use lazy_static::*;
@@ -301,7 +301,7 @@ pub fn exit_current_and_run_next(exit_code: i32) {
 lazy_static! {
     ///Globle process that init user shell
     pub static ref INITPROC: Arc<TaskControlBlock> = Arc::new(TaskControlBlock::new(
-        get_app_data_by_name("initproc").unwrap()
+        read_file("initproc").unwrap().as_slice()
     ));
 }
 ///Add init process to the manager